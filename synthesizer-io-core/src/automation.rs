@@ -0,0 +1,180 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Automation lanes: parameter curves given as breakpoints, evaluated on the
+//! audio thread at the exact time of each sample.
+//!
+//! Times are in ns on the same clock as message timestamps, so lanes line up
+//! with notes from the sequencer and with offline rendering.
+
+use transport::Transport;
+
+/// The shape of the segment from a breakpoint to the next one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    /// Hold the value until the next breakpoint.
+    Step,
+    Linear,
+    /// Ease in and out, following half a cosine cycle.
+    Smooth,
+    /// An exponential bend: positive values start slowly and finish fast,
+    /// negative values the reverse. 0 is linear.
+    Bend(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub time: u64,
+    pub value: f32,
+    pub curve: Curve,
+}
+
+/// A lane of breakpoints for one parameter. Before the first breakpoint the
+/// lane has the first value, and after the last it holds the last value.
+#[derive(Clone, Debug, Default)]
+pub struct Lane {
+    points: Vec<Breakpoint>,
+    // index of the breakpoint starting the current segment
+    cursor: usize,
+}
+
+impl Curve {
+    // Map a fraction of the segment to a fraction of the change in value.
+    fn shape(self, x: f32) -> f32 {
+        match self {
+            Curve::Step => 0.0,
+            Curve::Linear => x,
+            Curve::Smooth => 0.5 - 0.5 * (x * ::std::f32::consts::PI).cos(),
+            Curve::Bend(k) if k.abs() < 1e-3 => x,
+            Curve::Bend(k) => (k * x).exp_m1() / k.exp_m1(),
+        }
+    }
+}
+
+impl Lane {
+    /// Create a lane; the breakpoints are sorted by time.
+    pub fn new(mut points: Vec<Breakpoint>) -> Lane {
+        points.sort_by_key(|p| p.time);
+        Lane { points, cursor: 0 }
+    }
+
+    /// Create a lane from breakpoints given in beats, as (beat, value, curve),
+    /// placed in time by the transport's current tempo and start time.
+    pub fn from_beats(transport: &Transport, points: &[(f64, f32, Curve)]) -> Lane {
+        Lane::new(points.iter().map(|&(beat, value, curve)| Breakpoint {
+            time: transport.time_at(beat),
+            value,
+            curve,
+        }).collect())
+    }
+
+    pub fn points(&self) -> &[Breakpoint] {
+        &self.points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The time of the last breakpoint, after which the value is constant.
+    pub fn end_time(&self) -> Option<u64> {
+        self.points.last().map(|p| p.time)
+    }
+
+    /// The value at time `t`, or `None` for an empty lane. This is fastest
+    /// when times don't decrease from one call to the next, as on the audio
+    /// thread.
+    pub fn value_at(&mut self, t: u64) -> Option<f32> {
+        if self.points.is_empty() {
+            return None;
+        }
+        if t < self.points[self.cursor].time {
+            self.cursor = 0;
+        }
+        while self.cursor + 1 < self.points.len() && self.points[self.cursor + 1].time <= t {
+            self.cursor += 1;
+        }
+        let p0 = self.points[self.cursor];
+        match self.points.get(self.cursor + 1) {
+            Some(p1) if t >= p0.time => {
+                let x = (t - p0.time) as f32 / (p1.time - p0.time) as f32;
+                Some(p0.value + (p1.value - p0.value) * p0.curve.shape(x))
+            }
+            _ => Some(p0.value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(time: u64, value: f32, curve: Curve) -> Breakpoint {
+        Breakpoint { time, value, curve }
+    }
+
+    #[test]
+    fn segments() {
+        let mut lane = Lane::new(vec![
+            point(3000, 0.0, Curve::Linear),
+            point(1000, 1.0, Curve::Step),
+            point(2000, 2.0, Curve::Linear),
+            point(4000, 8.0, Curve::Smooth),
+        ]);
+        assert_eq!(lane.value_at(0), Some(1.0));
+        assert_eq!(lane.value_at(1500), Some(1.0));
+        assert_eq!(lane.value_at(2000), Some(2.0));
+        assert_eq!(lane.value_at(2500), Some(1.0));
+        assert_eq!(lane.value_at(3500), Some(4.0));
+        assert_eq!(lane.value_at(9000), Some(8.0));
+        // going back in time
+        assert_eq!(lane.value_at(2250), Some(1.5));
+        assert_eq!(Lane::default().value_at(0), None);
+    }
+
+    #[test]
+    fn render() {
+        use graph::{Message, Node, SetLane};
+        use modules::Automation;
+        use worker::Worker;
+
+        let (mut worker, _tx, _rx) = Worker::create(16);
+        let mut module = Automation::new(48_000.0, 0.0);
+        module.set_lane(Lane::new(vec![point(0, 0.0, Curve::Linear), point(1_000_000, 1.0,
+            Curve::Step)]));
+        worker.handle_node(Node::create(Box::new(module), 0, [], []));
+        let out = worker.render(0, 4, 48_000.0);
+        // one value per sample, reaching 1 after 1ms (48 samples)
+        assert!((out[24] - 0.5).abs() < 1e-6);
+        assert_eq!(out[60], 1.0);
+
+        let lane = Lane::new(vec![point(0, -1.0, Curve::Step)]);
+        worker.handle_message(Message::SetLane(SetLane { ix: 0, lane }));
+        assert_eq!(worker.render(0, 1, 48_000.0)[0], -1.0);
+
+        // lanes for missing nodes are dropped
+        for &ix in &[3, 9999] {
+            worker.handle_message(Message::SetLane(SetLane { ix, lane: Lane::default() }));
+        }
+        assert_eq!(worker.render(0, 1, 48_000.0)[0], -1.0);
+    }
+
+    #[test]
+    fn bend() {
+        let mut lane = Lane::new(vec![point(0, 0.0, Curve::Bend(4.0)), point(100, 1.0, Curve::Step)]);
+        let mid = lane.value_at(50).unwrap();
+        assert!(mid > 0.0 && mid < 0.5);
+        assert!((lane.value_at(100).unwrap() - 1.0).abs() < 1e-6);
+    }
+}
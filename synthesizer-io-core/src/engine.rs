@@ -14,13 +14,28 @@
 
 //! Interface for the audio engine.
 
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
 use time;
 
+use automation::Lane;
+use fm::FmAlgorithm;
 use id_allocator::IdAllocator;
-use graph::{IntoBoxedSlice, Message, Node, Note, SetParam};
+use midi_out::MidiOut;
+use graph::{IntoBoxedSlice, Message, Node, Note, SetLane, SetParam, SetTuning};
 use module::Module;
 use modules;
+use mpe::MpeZone;
+use osc::{OscCommand, OscMessage};
+use patch::{self, Patch, PresetBank};
 use queue::{Receiver, Sender};
+use sfz::Sfz;
+use transport::{Arpeggiator, ClockEvent, ClockIn, ClockOut, ScheduledNote, StepSequencer,
+    Transport};
+use tuning::Tuning;
 
 /// The interface from the application to the audio engine.
 ///
@@ -32,8 +47,22 @@ pub struct Engine {
 
     // We have a midi state in the engine, but this may get factored out.
     midi: Option<Midi>,
+
+    transport: Transport,
+    sequencer: Option<StepSequencer>,
+    // When set, incoming MIDI clock drives the transport.
+    clock_in: Option<ClockIn>,
+    clock_out: Option<ClockOut>,
+    midi_out: Option<Box<dyn MidiOut>>,
+    // When set, incoming MIDI is forwarded to `midi_out`.
+    midi_thru: bool,
+    presets: Option<PresetBank>,
 }
 
+// How far ahead `poll_transport` schedules notes, in ns. Polling should happen
+// more often than this.
+const SCHEDULE_AHEAD: u64 = 100_000_000;
+
 /// Type used to identify nodes in the external interface (not to be confused
 /// with nodes in the low-level graph).
 pub type NodeId = usize;
@@ -45,6 +74,48 @@ pub enum ModuleType {
     Saw,
 }
 
+/// A modulation source for the mono synth's matrix (see
+/// `Engine::set_mod_slot`). All range from 0 to 1, except the LFO, which is
+/// bipolar.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ModSource {
+    Lfo,
+    /// The amplitude envelope, as linear gain including velocity.
+    Envelope,
+    Velocity,
+    ModWheel,
+    /// Channel pressure.
+    Aftertouch,
+}
+
+/// A destination in the mono synth's modulation matrix, with the unit of
+/// depth for a source at full scale.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ModDest {
+    /// Oscillator pitch, in octaves.
+    Pitch,
+    /// Filter cutoff, in octaves.
+    Cutoff,
+    /// Filter resonance.
+    Resonance,
+    /// Output level, as log2 gain.
+    Level,
+}
+
+// Inputs per Sum node, within the graph's limit on buffer inputs.
+const MAX_SUM_INPUTS: usize = 16;
+
+const N_MOD_SOURCES: usize = 5;
+const N_MOD_DESTS: usize = 4;
+
+// Mixer for the voice and the external input. Both channels are centered, so
+// the left output carries the full mix.
+fn voice_mixer() -> modules::Mixer {
+    let mut mixer = modules::Mixer::new(2, 0);
+    mixer.set_level(1, -2.0);
+    mixer
+}
+
 /// The core owns the connection to the real-time worker.
 struct Core {
     sample_rate: f32,
@@ -56,6 +127,15 @@ struct Core {
     monitor_queues: Option<MonitorQueues>,
 }
 
+/// The nodes of an FM voice made by `Engine::create_fm_voice`.
+pub struct FmVoice {
+    /// The voice's output, which can be passed to `set_outputs`.
+    pub out: usize,
+    /// A control node for each operator's output level (operator 1 first),
+    /// in log2 gain.
+    pub levels: Vec<usize>,
+}
+
 #[derive(Clone)]
 pub struct NoteEvent {
     pub down: bool,
@@ -66,21 +146,75 @@ pub struct NoteEvent {
 struct Midi {
     control_map: ControlMap,
     cur_note: Option<u8>,
+    // When set, incoming notes are held by the arpeggiator rather than played.
+    arpeggiator: Option<Arpeggiator>,
+    mpe: Option<Mpe>,
+    // bank select, from controllers 0 (MSB) and 32 (LSB)
+    bank: u16,
+    // set by a program change, as (bank, program), until the engine loads it
+    program_change: Option<(u16, u8)>,
+}
+
+// The voices of an MPE instrument, one per member channel.
+struct Mpe {
+    zone: MpeZone,
+    voices: Vec<MpeVoice>,
+    // The voice to take next when a note arrives off the member channels
+    // and all voices are busy.
+    next_steal: usize,
+}
+
+struct MpeVoice {
+    channel: u8,
+    // node number of the voice's NoteExpression
+    expression: usize,
+    note_receivers: Vec<usize>,
+    note: Option<u8>,
 }
 
 struct ControlMap {
-    cutoff: usize,
-    reso: usize,
+    // Synth parameters; `None` when the instrument doesn't have them.
+    cutoff: Option<usize>,
+    reso: Option<usize>,
+    mod_matrix: Option<ModMatrixIds>,
 
-    attack: usize,
-    decay: usize,
-    sustain: usize,
-    release: usize,
+    note_pitch: usize,
+    glide: usize,
+    bend: usize,
+
+    attack: Option<usize>,
+    decay: Option<usize>,
+    sustain: Option<usize>,
+    release: Option<usize>,
+
+    reverb_mix: usize,
 
     // node number of node that can be replaced to inject more audio
     ext: usize,
+    // mixes the voice with `ext`; replaced when a patch is loaded
+    voice_mixer: usize,
 
     note_receivers: Vec<usize>,
+    // nodes of the built-in voice, removed when a patch replaces it
+    voice_nodes: Vec<usize>,
+    // nodes of the loaded patch, if any
+    patch_nodes: Vec<PatchNodeId>,
+}
+
+// Node numbers of the modulation matrix and the sources that only it uses.
+struct ModMatrixIds {
+    matrix: usize,
+    lfo: usize,
+    lfo_rate: usize,
+    mod_wheel: usize,
+    aftertouch: usize,
+}
+
+struct PatchNodeId {
+    name: String,
+    module: String,
+    id: usize,
+    receives_notes: bool,
 }
 
 struct MonitorQueues {
@@ -94,7 +228,17 @@ impl Engine {
     /// This call takes ownership of channels to and from the worker.
     pub fn new(sample_rate: f32, rx: Receiver<Message>, tx: Sender<Message>) -> Engine {
         let core = Core::new(sample_rate, rx, tx);
-        Engine { core, midi: None }
+        Engine {
+            core,
+            midi: None,
+            transport: Transport::new(120.0),
+            sequencer: None,
+            clock_in: None,
+            clock_out: None,
+            midi_out: None,
+            midi_thru: false,
+            presets: None,
+        }
     }
 
     /// Initialize the engine with a simple mono synth.
@@ -103,17 +247,386 @@ impl Engine {
         self.midi = Some(Midi::new(control_map));
     }
 
+    /// Initialize the engine with a sampled instrument, loaded from an SFZ
+    /// file. It plays through the same note handling and output chain as the
+    /// mono synth.
+    ///
+    /// All samples are decoded here, on the calling thread, before any nodes
+    /// are created.
+    pub fn init_sfz<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let sfz = Sfz::load(path)?;
+        let mut loaded: HashMap<&Path, Arc<modules::Sample>> = HashMap::new();
+        let mut samples = Vec::with_capacity(sfz.regions.len());
+        for region in &sfz.regions {
+            let sample = match loaded.get(region.sample.as_path()) {
+                Some(sample) => sample.clone(),
+                None => Arc::new(modules::Sample::load(&region.sample)?),
+            };
+            loaded.insert(&region.sample, sample.clone());
+            samples.push(sample);
+        }
+        let control_map = self.core.init_sfz(&sfz, &samples);
+        self.midi = Some(Midi::new(control_map));
+        Ok(())
+    }
+
+    /// Initialize the engine with a polyphonic synth played by an MPE
+    /// controller, with one voice for each member channel of `zone`.
+    ///
+    /// Pitch bend, channel pressure and CC 74 on a member channel shape the
+    /// note on that channel: bend adds to its pitch, pressure to its level,
+    /// and timbre opens its filter. Messages on the master channel apply to
+    /// all voices, as on the mono synth. Notes from elsewhere, such as the
+    /// sequencer, take a free voice.
+    pub fn init_mpe(&mut self, zone: MpeZone) {
+        let (control_map, voices) = self.core.init_mpe(&zone);
+        let mut midi = Midi::new(control_map);
+        midi.mpe = Some(Mpe { zone, voices, next_steal: 0 });
+        self.midi = Some(midi);
+    }
+
     /// Handle a MIDI event.
     pub fn dispatch_midi(&mut self, data: &[u8], ts: u64) {
+        if self.midi_thru {
+            self.send_midi(ts, data);
+        }
+        let mut clock_events = Vec::new();
+        let mut program_change = None;
         if let Some(ref mut midi) = self.midi {
-            midi.dispatch_midi(&mut self.core, data, ts);
+            midi.dispatch_midi(&mut self.core, data, ts, &mut clock_events);
+            program_change = midi.program_change.take();
+        }
+        for event in clock_events {
+            self.handle_clock(event, ts);
+        }
+        if let Some((bank, program)) = program_change {
+            self.program_change(bank, program);
+        }
+    }
+
+    /// Set the patches selected by program change and bank select.
+    pub fn set_preset_bank(&mut self, presets: PresetBank) {
+        self.presets = Some(presets);
+    }
+
+    fn program_change(&mut self, bank: u16, program: u8) {
+        let patch = match self.presets.as_ref().and_then(|p| p.get(bank, program)) {
+            Some(patch) => patch.clone(),
+            None => {
+                println!("no patch for bank {} program {}", bank, program);
+                return;
+            }
+        };
+        if let Err(e) = self.load_patch(&patch) {
+            println!("error loading patch: {}", e);
+        }
+    }
+
+    /// Replace the instrument's voice with a patch.
+    ///
+    /// The new graph reaches the worker in one piece, so it never runs a
+    /// partly built patch. Nodes with the same name and module type as in the
+    /// previous patch keep their id, so their state carries over through
+    /// `migrate` rather than clicking. The nodes of the instrument the patch
+    /// replaces are removed, but its output chain stays.
+    pub fn load_patch(&mut self, patch: &Patch) -> io::Result<()> {
+        if self.midi.is_none() {
+            let control_map = self.core.init_patch_base();
+            self.midi = Some(Midi::new(control_map));
+        }
+        let midi = self.midi.as_mut().unwrap();
+        if midi.mpe.take().is_some() {
+            // Each MPE voice has its own pitch; the patch needs a shared one.
+            let (note_pitch, glide, bend) = self.core.init_note_pitch();
+            let control_map = &mut midi.control_map;
+            control_map.note_pitch = note_pitch;
+            control_map.glide = glide;
+            control_map.bend = bend;
+            control_map.note_receivers.push(note_pitch);
+        }
+        self.core.load_patch(patch, &mut midi.control_map)
+    }
+
+    fn handle_clock(&mut self, event: ClockEvent, ts: u64) {
+        let started = match self.clock_in {
+            Some(ref mut clock_in) => clock_in.handle(event, ts, &mut self.transport),
+            None => false,
+        };
+        if started {
+            self.reset_sequencers();
         }
     }
 
     /// Handle a note event.
     pub fn dispatch_note_event(&mut self, note_event: &NoteEvent) {
+        let status = if note_event.down { 0x90 } else { 0x80 };
+        let data = [status, note_event.note, note_event.velocity];
+        self.dispatch_midi(&data, time::precise_time_ns());
+    }
+
+    /// Handle an OSC message: `/node/<id>/param/<ix> value` sets a parameter
+    /// on a node, and `/note note velocity` plays a note as if from MIDI
+    /// (velocity 0 for note off).
+    pub fn dispatch_osc(&mut self, msg: &OscMessage, ts: u64) {
+        match OscCommand::from_message(msg) {
+            Some(OscCommand::SetParam { node, .. }) if !self.core.id_alloc.is_allocated(node) => {
+                println!("OSC message {} for unknown node", msg.addr);
+            }
+            Some(OscCommand::SetParam { node, param_ix, val }) => {
+                let param = SetParam {
+                    ix: node,
+                    param_ix,
+                    val,
+                    timestamp: ts,
+                };
+                self.core.send(Message::SetParam(param));
+            }
+            Some(OscCommand::Note { note, velocity }) => {
+                if let Some(ref mut midi) = self.midi {
+                    midi.dispatch_midi(&mut self.core, &[0x90, note, velocity], ts, &mut Vec::new());
+                }
+            }
+            None => println!("don't have handler for OSC message {}", msg.addr),
+        }
+    }
+
+    /// Change a synth parameter from the UI, by its MIDI controller number.
+    /// The change is also sent to the MIDI output as a control change, so
+    /// that controllers following the engine stay in sync.
+    pub fn set_control(&mut self, controller: u8, value: u8) {
+        let data = [0xb0, controller, value];
+        let ts = time::precise_time_ns();
         if let Some(ref mut midi) = self.midi {
-            midi.dispatch_note_event(&mut self.core, note_event);
+            midi.dispatch_midi(&mut self.core, &data, ts, &mut Vec::new());
+        }
+        self.send_midi(ts, &data);
+    }
+
+    /// Set or remove the MIDI output, which receives sequencer and
+    /// arpeggiator notes, clock (see `set_clock_out`), control changes from
+    /// `set_control`, and incoming MIDI when thru is enabled.
+    pub fn set_midi_out(&mut self, midi_out: Option<Box<dyn MidiOut>>) {
+        self.midi_out = midi_out;
+    }
+
+    /// Forward incoming MIDI, including note events from the UI, to the MIDI
+    /// output.
+    pub fn set_midi_thru(&mut self, enabled: bool) {
+        self.midi_thru = enabled;
+    }
+
+    fn send_midi(&mut self, ts: u64, data: &[u8]) {
+        if let Some(ref mut midi_out) = self.midi_out {
+            midi_out.send(ts, data);
+        }
+    }
+
+    /// Replace the tuning used by the note receivers. The table is copied into
+    /// the modules by the worker, so this doesn't allocate on the audio thread.
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        if let Some(ref midi) = self.midi {
+            let ixs = midi.control_map.note_receivers.clone().into_boxed_slice();
+            let tuning = Box::new(tuning);
+            self.core.send(Message::SetTuning(SetTuning { ixs, tuning }));
+        }
+    }
+
+    /// Send an automation lane to a node, replacing any lane it has. Lanes
+    /// are followed by `SmoothCtrl` and `Automation` modules; values are in
+    /// the units of the node's output. A lane for a node that doesn't exist
+    /// is dropped.
+    pub fn set_lane(&mut self, node: usize, lane: Lane) {
+        if !self.core.id_alloc.is_allocated(node) {
+            return;
+        }
+        self.core.send(Message::SetLane(SetLane { ix: node, lane }));
+    }
+
+    /// Route a modulation source to a destination in the mono synth's matrix,
+    /// replacing whatever the slot (up to `ModMatrix::MAX_SLOTS`) held. Depth
+    /// is bipolar, in the units of the destination; a depth of 0 clears the
+    /// slot. The mod wheel follows controller 1 (which only drives the
+    /// matrix; filter cutoff is on controller 74) and aftertouch follows
+    /// channel pressure.
+    pub fn set_mod_slot(&mut self, slot: usize, source: ModSource, dest: ModDest, depth: f32) {
+        let matrix = match self.mod_matrix_ids() {
+            Some(ids) => ids.matrix,
+            None => return,
+        };
+        let timestamp = time::precise_time_ns();
+        let settings = [
+            (modules::ModMatrix::PARAM_SOURCE, source as usize as f32),
+            (modules::ModMatrix::PARAM_DEST, dest as usize as f32),
+            (modules::ModMatrix::PARAM_DEPTH, depth),
+        ];
+        for &(offset, val) in &settings {
+            let param_ix = modules::ModMatrix::param(slot, offset);
+            self.core.send(Message::SetParam(SetParam { ix: matrix, param_ix, val, timestamp }));
+        }
+    }
+
+    /// Set the rate (in Hz) and shape of the mono synth's modulation LFO.
+    pub fn set_mod_lfo(&mut self, rate: f32, shape: modules::LfoShape) {
+        let (lfo, lfo_rate) = match self.mod_matrix_ids() {
+            Some(ids) => (ids.lfo, ids.lfo_rate),
+            None => return,
+        };
+        let timestamp = time::precise_time_ns();
+        self.core.send(Message::SetParam(SetParam {
+            ix: lfo_rate,
+            param_ix: 0,
+            val: rate.log2(),
+            timestamp,
+        }));
+        self.core.send(Message::SetParam(SetParam {
+            ix: lfo,
+            param_ix: modules::Lfo::PARAM_SHAPE,
+            val: shape as usize as f32,
+            timestamp,
+        }));
+    }
+
+    fn mod_matrix_ids(&self) -> Option<&ModMatrixIds> {
+        self.midi.as_ref()?.control_map.mod_matrix.as_ref()
+    }
+
+    /// The node driven by a MIDI controller number, if the instrument has
+    /// one, for automating it with `set_lane` or addressing it over OSC.
+    pub fn control_node(&self, controller: u8) -> Option<usize> {
+        let map = &self.midi.as_ref()?.control_map;
+        match controller {
+            1 => map.mod_matrix.as_ref().map(|ids| ids.mod_wheel),
+            2 => map.reso,
+            3 => Some(map.glide),
+            5 => map.attack,
+            6 => map.decay,
+            7 => map.sustain,
+            8 => map.release,
+            74 => map.cutoff,
+            91 => Some(map.reverb_mix),
+            _ => None,
+        }
+    }
+
+    /// The tempo clock that drives the sequencer and arpeggiator.
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Start the transport from the beginning.
+    pub fn start_transport(&mut self) {
+        let now = time::precise_time_ns();
+        self.transport.start(now);
+        self.reset_sequencers();
+        if self.clock_out.is_some() {
+            self.send_midi(now, &[0xfa]);
+        }
+        // schedule now, so that the first step isn't missed
+        self.schedule(now);
+    }
+
+    pub fn stop_transport(&mut self) {
+        let now = time::precise_time_ns();
+        self.transport.stop(now);
+        if self.clock_out.is_some() {
+            self.send_midi(now, &[0xfc]);
+        }
+    }
+
+    fn reset_sequencers(&mut self) {
+        if let Some(ref mut sequencer) = self.sequencer {
+            sequencer.reset();
+        }
+        if let Some(Midi { arpeggiator: Some(ref mut arp), .. }) = self.midi {
+            arp.reset();
+        }
+        if let Some(ref mut clock_out) = self.clock_out {
+            clock_out.reset();
+        }
+    }
+
+    /// Follow incoming MIDI clock, start, stop, continue and song position
+    /// messages, or stop following them.
+    pub fn set_clock_sync(&mut self, enabled: bool) {
+        self.clock_in = if enabled { Some(ClockIn::new()) } else { None };
+    }
+
+    /// The tempo of the incoming MIDI clock, if following it and it has been
+    /// measured.
+    pub fn clock_in_bpm(&self) -> Option<f64> {
+        self.clock_in.as_ref().and_then(|clock_in| clock_in.bpm())
+    }
+
+    /// Send MIDI clock, start and stop messages from the transport to the
+    /// MIDI output.
+    pub fn set_clock_out(&mut self, enabled: bool) {
+        self.clock_out = if enabled { Some(ClockOut::new()) } else { None };
+    }
+
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.transport.set_bpm(bpm, time::precise_time_ns());
+    }
+
+    /// Set the swing (see `Transport::set_swing`).
+    pub fn set_swing(&mut self, swing: f64) {
+        self.transport.set_swing(swing);
+    }
+
+    /// Set or remove the step sequencer. It plays through the same note
+    /// receivers as incoming MIDI.
+    pub fn set_sequencer(&mut self, sequencer: Option<StepSequencer>) {
+        self.sequencer = sequencer;
+    }
+
+    pub fn sequencer_mut(&mut self) -> Option<&mut StepSequencer> {
+        self.sequencer.as_mut()
+    }
+
+    /// Set or remove the arpeggiator. While it is set, incoming notes are
+    /// held by the arpeggiator, which plays them as the transport runs.
+    pub fn set_arpeggiator(&mut self, arpeggiator: Option<Arpeggiator>) {
+        if let Some(ref mut midi) = self.midi {
+            midi.arpeggiator = arpeggiator;
+        }
+    }
+
+    pub fn arpeggiator_mut(&mut self) -> Option<&mut Arpeggiator> {
+        self.midi.as_mut().and_then(|midi| midi.arpeggiator.as_mut())
+    }
+
+    /// Schedule notes from the sequencer and arpeggiator for the near future.
+    /// This should be called regularly, at least every 50ms or so; the notes
+    /// are sent ahead of time with precise timestamps.
+    pub fn poll_transport(&mut self) {
+        self.schedule(time::precise_time_ns());
+    }
+
+    fn schedule(&mut self, now: u64) {
+        let until = now + SCHEDULE_AHEAD;
+        let mut notes = Vec::new();
+        if let Some(ref mut sequencer) = self.sequencer {
+            sequencer.schedule(&self.transport, now, until, &mut notes);
+        }
+        let mut ticks = Vec::new();
+        if let Some(ref mut clock_out) = self.clock_out {
+            clock_out.schedule(&self.transport, now, until, &mut ticks);
+        }
+        if let Some(ref mut midi) = self.midi {
+            if let Some(ref mut arp) = midi.arpeggiator {
+                arp.schedule(&self.transport, now, until, &mut notes);
+            }
+            // stable, so an off stays before an on at the same time
+            notes.sort_by_key(|note| note.timestamp);
+            for note in &notes {
+                midi.scheduled_note(&mut self.core, note);
+            }
+        }
+        for note in &notes {
+            let status = if note.on { 0x90 } else { 0x80 };
+            self.send_midi(note.timestamp, &[status, note.note, note.velocity]);
+        }
+        for tick in ticks {
+            self.send_midi(tick, &[0xf8]);
         }
     }
 
@@ -138,6 +651,69 @@ impl Engine {
         self.core.instantiate_module(node_id, ty)
     }
 
+    /// Create an FM voice, with `ops` (operator 1 first) wired according to
+    /// `algorithm`, and `feedback` applied to its feedback operator. Each
+    /// operator's output level starts at unity; set it with `set_fm_level`.
+    /// A modulator's level is its modulation index, in log2.
+    ///
+    /// When the mono synth is running, the voice follows its pitch and
+    /// responds to its notes. Fails if the number of operators doesn't match
+    /// the algorithm.
+    pub fn create_fm_voice(&mut self, algorithm: &FmAlgorithm,
+        ops: Vec<modules::FmOperator>, feedback: f32) -> io::Result<FmVoice>
+    {
+        if ops.len() != algorithm.n_ops {
+            return Err(invalid("wrong number of operators for algorithm"));
+        }
+        let pitch = self.voice_pitch();
+        let (voice, op_ids) = self.core.create_fm_voice(algorithm, ops, feedback, pitch);
+        self.add_note_receivers(&op_ids);
+        Ok(voice)
+    }
+
+    /// Set the output level of an operator (numbered from 1) in an FM voice,
+    /// as log2 gain.
+    pub fn set_fm_level(&mut self, voice: &FmVoice, op: usize, level: f32) {
+        let param = SetParam {
+            ix: voice.levels[op - 1],
+            param_ix: 0,
+            val: level,
+            timestamp: time::precise_time_ns(),
+        };
+        self.core.send(Message::SetParam(param));
+    }
+
+    /// Load a WAV file into a sampler, which plays it at its original speed
+    /// for `root_key` (a MIDI note number, in equal temperament).
+    ///
+    /// Decoding happens here, on the calling thread; the worker receives the
+    /// decoded sample inside the new node. As with `create_fm_voice`, the
+    /// sampler follows the mono synth's notes. Returns an id for the sampler's
+    /// output, which can be passed to `set_outputs`.
+    pub fn load_sample<P: AsRef<Path>>(&mut self, path: P, root_key: f32) -> io::Result<usize> {
+        let sample = Arc::new(modules::Sample::load(path)?);
+        let root = Tuning::equal_temperament().pitch(root_key);
+        let sampler = modules::Sampler::new(self.core.sample_rate, sample, root);
+        let pitch = self.voice_pitch();
+        let id = self.core.create_node(sampler, [], [(pitch, 0)]);
+        self.add_note_receivers(&[id]);
+        Ok(id)
+    }
+
+    // The pitch source for new voices: the mono synth's, or a fixed pitch.
+    fn voice_pitch(&mut self) -> usize {
+        match self.midi {
+            Some(ref midi) => midi.control_map.note_pitch,
+            None => self.core.create_node(modules::SmoothCtrl::new(440.0f32.log2()), [], []),
+        }
+    }
+
+    fn add_note_receivers(&mut self, ids: &[usize]) {
+        if let Some(ref mut midi) = self.midi {
+            midi.control_map.note_receivers.extend_from_slice(ids);
+        }
+    }
+
     /// Set the output bus.
     pub fn set_outputs(&mut self, outputs: &[usize]) {
         let sum_node = match self.midi {
@@ -148,6 +724,10 @@ impl Engine {
     }
 }
 
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
 impl Core {
     fn new(sample_rate: f32, rx: Receiver<Message>, tx: Sender<Message>) -> Core {
         let mut id_alloc = IdAllocator::new();
@@ -168,12 +748,9 @@ impl Core {
 
     fn init_monosynth(&mut self) -> ControlMap {
         let sample_rate = self.sample_rate;
-        let note_pitch = self.create_node(modules::NotePitch::new(), [], []);
-        let saw = self.create_node(modules::Saw::new(sample_rate), [], [(note_pitch, 0)]);
+        let (note_pitch, glide, bend) = self.init_note_pitch();
         let cutoff = self.create_node(modules::SmoothCtrl::new(880.0f32.log2()), [], []);
         let reso = self.create_node(modules::SmoothCtrl::new(0.5), [], []);
-        let filter_out = self.create_node(modules::Biquad::new(sample_rate),
-            [(saw, 0)], [(cutoff, 0), (reso, 0)]);
 
         let attack = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
         let decay = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
@@ -181,32 +758,363 @@ impl Core {
         let release = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
         let adsr = self.create_node(modules::Adsr::new(), [],
             vec![(attack, 0), (decay, 0), (sustain, 0), (release, 0)]);
-        let env_out = self.create_node(modules::Gain::new(), [(filter_out, 0)], [(adsr, 0)]);
 
+        // Pitch, cutoff, resonance and level pass through the matrix, which
+        // adds the modulation to them. Its sources and destinations are in
+        // the order of `ModSource` and `ModDest`.
+        let lfo_rate = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let lfo = self.create_node(modules::Lfo::new(sample_rate, modules::LfoShape::Sine),
+            [], [(lfo_rate, 0)]);
+        let mod_wheel = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let aftertouch = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let mut matrix = modules::ModMatrix::new(N_MOD_SOURCES, N_MOD_DESTS);
+        matrix.set_range(ModDest::Pitch as usize, 0.0, (sample_rate * 0.5).log2());
+        matrix.set_range(ModDest::Cutoff as usize, 4.0, (sample_rate * 0.45).log2());
+        matrix.set_range(ModDest::Resonance as usize, 0.0, 0.995);
+        matrix.set_range(ModDest::Level as usize, -f32::INFINITY, 1.0);
+        let matrix = self.create_node(matrix, [], vec![
+            (lfo, 0), (adsr, 1), (note_pitch, 1), (mod_wheel, 0), (aftertouch, 0),
+            (note_pitch, 0), (cutoff, 0), (reso, 0), (adsr, 0),
+        ]);
+
+        let saw = self.create_node(modules::Saw::new(sample_rate), [],
+            [(matrix, ModDest::Pitch as usize)]);
+        let filter_out = self.create_node(modules::Biquad::new(sample_rate), [(saw, 0)],
+            [(matrix, ModDest::Cutoff as usize), (matrix, ModDest::Resonance as usize)]);
+        let env_out = self.create_node(modules::Gain::new(), [(filter_out, 0)],
+            [(matrix, ModDest::Level as usize)]);
+
+        let (ext, reverb_mix, voice_mixer) = self.init_output(env_out);
+
+        ControlMap {
+            cutoff: Some(cutoff),
+            reso: Some(reso),
+            mod_matrix: Some(ModMatrixIds { matrix, lfo, lfo_rate, mod_wheel, aftertouch }),
+            note_pitch,
+            glide,
+            bend,
+            attack: Some(attack),
+            decay: Some(decay),
+            sustain: Some(sustain),
+            release: Some(release),
+            reverb_mix,
+            ext,
+            voice_mixer,
+            note_receivers: vec![note_pitch, adsr, lfo],
+            voice_nodes: vec![
+                cutoff, reso, attack, decay, sustain, release, adsr, lfo_rate, lfo, mod_wheel,
+                aftertouch, matrix, saw, filter_out, env_out,
+            ],
+            patch_nodes: Vec::new(),
+        }
+    }
+
+    // Build a sampler for each region, playing through the same note pitch
+    // and output chain as the mono synth. `samples` holds the decoded sample
+    // for each region.
+    fn init_sfz(&mut self, sfz: &Sfz, samples: &[Arc<modules::Sample>]) -> ControlMap {
+        let (note_pitch, glide, bend) = self.init_note_pitch();
+        let et = Tuning::equal_temperament();
+        let mut note_receivers = vec![note_pitch];
+        for (region, sample) in sfz.regions.iter().zip(samples) {
+            let len = sample.len();
+            let root = et.pitch(region.root_key());
+            let mut sampler = modules::Sampler::new(self.sample_rate, sample.clone(), root);
+            // SFZ end points are inclusive
+            sampler.set_range(region.offset, region.end.map_or(len, |end| end + 1));
+            sampler.set_loop(region.loop_mode.unwrap_or(modules::SamplerMode::NoLoop),
+                region.loop_start.unwrap_or(0), region.loop_end.map_or(len, |end| end + 1));
+            sampler.set_key_range(region.lokey as f32, region.hikey as f32);
+            sampler.set_vel_range(region.lovel as f32, region.hivel as f32);
+            sampler.set_velocity_sens(region.amp_veltrack * 0.01);
+            sampler.set_release(region.ampeg_release);
+            sampler.set_gain(region.volume * (1.0 / 6.0206));  // dB to log2
+            note_receivers.push(self.create_node(sampler, [], [(note_pitch, 0)]));
+        }
+        let mut voice_nodes = note_receivers[1..].to_vec();
+        let voice = self.sum_tree(&note_receivers[1..], &mut voice_nodes);
+        let (ext, reverb_mix, voice_mixer) = self.init_output(voice);
+
+        ControlMap {
+            cutoff: None,
+            reso: None,
+            mod_matrix: None,
+            note_pitch,
+            glide,
+            bend,
+            attack: None,
+            decay: None,
+            sustain: None,
+            release: None,
+            reverb_mix,
+            ext,
+            voice_mixer,
+            note_receivers,
+            voice_nodes,
+            patch_nodes: Vec::new(),
+        }
+    }
+
+    fn init_mpe(&mut self, zone: &MpeZone) -> (ControlMap, Vec<MpeVoice>) {
+        let sample_rate = self.sample_rate;
+        let glide = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let bend = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let reso = self.create_node(modules::SmoothCtrl::new(0.5), [], []);
+        let attack = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
+        let decay = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
+        let sustain = self.create_node(modules::SmoothCtrl::new(4.0), [], []);
+        let release = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
+
+        let mut voices = Vec::new();
+        let mut outputs = Vec::new();
+        let mut note_receivers = Vec::new();
+        let mut voice_nodes = vec![glide, bend, reso, attack, decay, sustain, release];
+        for channel in zone.member_channels() {
+            let mut expression = modules::NoteExpression::new(sample_rate);
+            expression.set_bend_range(zone.bend_range);
+            let expression = self.create_node(expression, [], []);
+            let mut note_pitch = modules::NotePitch::new();
+            note_pitch.set_bend_range(zone.master_bend_range);
+            let note_pitch = self.create_node(note_pitch, [],
+                vec![(glide, 0), (bend, 0), (expression, 0)]);
+            let saw = self.create_node(modules::Saw::new(sample_rate), [], [(note_pitch, 0)]);
+            // timbre sweeps the cutoff over 6 octaves up from 100Hz
+            let cutoff = self.create_node(modules::ScaleOffset::new(6.0, 100f32.log2()),
+                [], [(expression, 2)]);
+            let filter_out = self.create_node(modules::Biquad::new(sample_rate),
+                [(saw, 0)], [(cutoff, 0), (reso, 0)]);
+            let adsr = self.create_node(modules::Adsr::new(), [],
+                vec![(attack, 0), (decay, 0), (sustain, 0), (release, 0)]);
+            let env_out = self.create_node(modules::Gain::new(), [(filter_out, 0)], [(adsr, 0)]);
+            // pressure raises the level by up to 6dB
+            let level = self.create_node(modules::ScaleOffset::new(1.0, -1.0),
+                [], [(expression, 1)]);
+            let out = self.create_node(modules::Gain::new(), [(env_out, 0)], [(level, 0)]);
+            outputs.push(out);
+            voice_nodes.extend_from_slice(&[
+                expression, note_pitch, saw, cutoff, filter_out, adsr, env_out, level, out,
+            ]);
+            note_receivers.extend_from_slice(&[note_pitch, adsr, expression]);
+            voices.push(MpeVoice {
+                channel,
+                expression,
+                note_receivers: vec![note_pitch, adsr, expression],
+                note: None,
+            });
+        }
+        let voice = self.sum_tree(&outputs, &mut voice_nodes);
+        let (ext, reverb_mix, voice_mixer) = self.init_output(voice);
+
+        let control_map = ControlMap {
+            cutoff: None,
+            reso: Some(reso),
+            mod_matrix: None,
+            note_pitch: note_receivers[0],
+            glide,
+            bend,
+            attack: Some(attack),
+            decay: Some(decay),
+            sustain: Some(sustain),
+            release: Some(release),
+            reverb_mix,
+            ext,
+            voice_mixer,
+            note_receivers,
+            voice_nodes,
+            patch_nodes: Vec::new(),
+        };
+        (control_map, voices)
+    }
+
+    // The note pitch and output chain, with an empty voice for a patch.
+    fn init_patch_base(&mut self) -> ControlMap {
+        let (note_pitch, glide, bend) = self.init_note_pitch();
+        let voice = self.create_node(modules::Sum::new(), [], []);
+        let (ext, reverb_mix, voice_mixer) = self.init_output(voice);
+        ControlMap {
+            cutoff: None,
+            reso: None,
+            mod_matrix: None,
+            note_pitch,
+            glide,
+            bend,
+            attack: None,
+            decay: None,
+            sustain: None,
+            release: None,
+            reverb_mix,
+            ext,
+            voice_mixer,
+            note_receivers: vec![note_pitch],
+            voice_nodes: vec![voice],
+            patch_nodes: Vec::new(),
+        }
+    }
+
+    fn load_patch(&mut self, patch: &Patch, control_map: &mut ControlMap) -> io::Result<()> {
+        let mut built = Vec::with_capacity(patch.nodes.len());
+        // output counts (buffer, control) by node name, for checking wiring
+        let mut outputs = HashMap::new();
+        outputs.insert(patch::PITCH, (0, 3));
+        for node in &patch.nodes {
+            let module = node.build(self.sample_rate)?;
+            let inputs = node.buf_in.iter().map(|input| (input, true))
+                .chain(node.ctrl_in.iter().map(|input| (input, false)));
+            for (input, is_buf) in inputs {
+                let &(n_bufs, n_ctrl) = outputs.get(input.node.as_str())
+                    .ok_or_else(|| invalid(&format!("undefined node {}", input.node)))?;
+                if input.output >= if is_buf { n_bufs } else { n_ctrl } {
+                    return Err(invalid(&format!("{} has no output {}", input.node,
+                        input.output)));
+                }
+            }
+            outputs.insert(node.name.as_str(), (module.n_bufs_out(), module.n_ctrl_out()));
+            built.push(module);
+        }
+        if outputs.get(patch::OUT).map_or(true, |&(n_bufs, _)| n_bufs == 0) {
+            return Err(invalid("out node has no buffer output"));
+        }
+
+        let mut old_nodes = ::std::mem::take(&mut control_map.patch_nodes);
+        let mut old_ids: Vec<_> = old_nodes.iter().map(|old| old.id).collect();
+        old_ids.extend_from_slice(&control_map.voice_nodes);
+        let mut ids = HashMap::new();
+        ids.insert(patch::PITCH, control_map.note_pitch);
+        let mut messages = Vec::with_capacity(patch.nodes.len() + 1);
+        let mut patch_nodes = Vec::with_capacity(patch.nodes.len());
+        for (node, module) in patch.nodes.iter().zip(built) {
+            let reuse = old_nodes.iter()
+                .position(|old| old.name == node.name && old.module == node.module);
+            let id = match reuse {
+                Some(pos) => old_nodes.swap_remove(pos).id,
+                None => self.id_alloc.alloc(),
+            };
+            let buf_wiring: Vec<_> = node.buf_in.iter()
+                .map(|input| (ids[input.node.as_str()], input.output)).collect();
+            let ctrl_wiring: Vec<_> = node.ctrl_in.iter()
+                .map(|input| (ids[input.node.as_str()], input.output)).collect();
+            let receives_notes = module.receives_notes();
+            messages.push(Message::Node(Node::create(module, id, buf_wiring, ctrl_wiring)));
+            ids.insert(node.name.as_str(), id);
+            patch_nodes.push(PatchNodeId {
+                name: node.name.clone(),
+                module: node.module.clone(),
+                id,
+                receives_notes,
+            });
+        }
+        let voice_mixer = Node::create(Box::new(voice_mixer()), control_map.voice_mixer,
+            [(ids[patch::OUT], 0), (control_map.ext, 0)], []);
+        messages.push(Message::Node(voice_mixer));
+        // Old nodes that weren't reused are removed along with the switch to
+        // the new voice, so the graph never refers to a missing node.
+        let mut removed: Vec<_> = old_nodes.iter().map(|old| old.id).collect();
+        removed.append(&mut control_map.voice_nodes);
+        messages.extend(removed.iter().map(|&id| Message::Remove(id)));
+        self.tx.send_all(messages);
+
+        for id in removed {
+            self.id_alloc.free(id);
+        }
+        control_map.note_receivers.retain(|id| !old_ids.contains(id));
+        control_map.note_receivers.extend(patch_nodes.iter()
+            .filter(|n| n.receives_notes).map(|n| n.id));
+        let find = |name: &str| patch_nodes.iter().find(|n| n.name == name).map(|n| n.id);
+        control_map.cutoff = find("cutoff");
+        control_map.reso = find("reso");
+        control_map.mod_matrix = None;
+        control_map.attack = find("attack");
+        control_map.decay = find("decay");
+        control_map.sustain = find("sustain");
+        control_map.release = find("release");
+        control_map.patch_nodes = patch_nodes;
+        Ok(())
+    }
+
+    // Returns (note pitch, glide, bend) nodes.
+    fn init_note_pitch(&mut self) -> (usize, usize, usize) {
+        let glide = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let bend = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let note_pitch = self.create_node(modules::NotePitch::new(), [],
+            [(glide, 0), (bend, 0)]);
+        (note_pitch, glide, bend)
+    }
+
+    // Mix the voice with the external input, and run it through the effects to
+    // the monitor and the output bus. Returns (ext, reverb mix, voice mixer)
+    // nodes.
+    fn init_output(&mut self, voice: usize) -> (usize, usize, usize) {
+        let sample_rate = self.sample_rate;
         let ext = self.create_node(modules::Sum::new(), [], []);
-        let ext_gain = self.create_node(modules::ConstCtrl::new(-2.0), [], []);
-        let ext_atten = self.create_node(modules::Gain::new(), [(ext, 0)], [(ext_gain, 0)]);
+        let monitor_in = self.create_node(voice_mixer(), [(voice, 0), (ext, 0)], []);
 
-        let monitor_in = self.create_node(modules::Sum::new(), [(env_out, 0), (ext_atten, 0)], []);
+        let reverb_size = self.create_node(modules::ConstCtrl::new(0.5), [], []);
+        let reverb_damp = self.create_node(modules::ConstCtrl::new(0.5), [], []);
+        let reverb_pre = self.create_node(modules::ConstCtrl::new(0.02), [], []);
+        let reverb_mix = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let reverb = self.create_node(modules::Reverb::new(sample_rate), [(monitor_in, 0)],
+            vec![(reverb_size, 0), (reverb_damp, 0), (reverb_pre, 0), (reverb_mix, 0)]);
+
+        // guard against clipping, e.g. from high resonance
+        let limiter = self.create_node(modules::Limiter::new(sample_rate, 0.002),
+            [(reverb, 0)], []);
 
         let (monitor, tx, rx) = modules::Monitor::new();
         self.monitor_queues = Some(MonitorQueues { tx, rx });
-        let monitor = self.create_node(monitor, [(monitor_in, 0)], []);
+        let monitor = self.create_node(monitor, [(limiter, 0)], []);
 
         self.update_sum_node(0, &[monitor]);
+        (ext, reverb_mix, monitor_in)
+    }
 
-        ControlMap {
-            cutoff,
-            reso,
-            attack,
-            decay,
-            sustain,
-            release,
-            ext,
-            note_receivers: vec![note_pitch, adsr],
+    // Sum any number of outputs, using a tree of Sum nodes since each node
+    // has a limited number of inputs. The Sum nodes are added to `nodes`.
+    fn sum_tree(&mut self, inputs: &[usize], nodes: &mut Vec<usize>) -> usize {
+        let mut level = inputs.to_vec();
+        loop {
+            let next: Vec<_> = level.chunks(MAX_SUM_INPUTS).map(|chunk| {
+                let wiring: Vec<_> = chunk.iter().map(|&id| (id, 0)).collect();
+                self.create_node(modules::Sum::new(), wiring, [])
+            }).collect();
+            nodes.extend_from_slice(&next);
+            if next.len() <= 1 {
+                return match next.first() {
+                    Some(&root) => root,
+                    None => {
+                        let root = self.create_node(modules::Sum::new(), [], []);
+                        nodes.push(root);
+                        root
+                    }
+                };
+            }
+            level = next;
         }
     }
 
+    // Returns the voice, and the node for each operator. The number of
+    // operators must match the algorithm.
+    fn create_fm_voice(&mut self, algorithm: &FmAlgorithm, ops: Vec<modules::FmOperator>,
+        feedback: f32, pitch: usize) -> (FmVoice, Vec<usize>)
+    {
+        let levels: Vec<_> = (0..algorithm.n_ops)
+            .map(|_| self.create_node(modules::SmoothCtrl::new(0.0), [], []))
+            .collect();
+        let mut ops: Vec<_> = ops.into_iter().map(Some).collect();
+        let mut ids = vec![0; algorithm.n_ops + 1];
+        for op in algorithm.order().unwrap() {
+            let mut module = ops[op - 1].take().unwrap();
+            if algorithm.feedback == Some(op) {
+                module.set_feedback(feedback);
+            }
+            let mods: Vec<_> = algorithm.modulators(op).map(|m| (ids[m], 0)).collect();
+            ids[op] = self.create_node(module, mods, [(pitch, 0), (levels[op - 1], 0)]);
+        }
+        let carriers: Vec<_> = algorithm.carriers.iter().map(|&c| (ids[c], 0)).collect();
+        let out = self.create_node(modules::Sum::new(), carriers, []);
+        ids.remove(0);
+        (FmVoice { out, levels }, ids)
+    }
+
     fn send(&self, msg: Message) {
         self.tx.send(msg);
     }
@@ -259,6 +1167,10 @@ impl Midi {
         Midi {
             control_map,
             cur_note: None,
+            arpeggiator: None,
+            mpe: None,
+            bank: 0,
+            program_change: None,
         }
     }
 
@@ -288,50 +1200,107 @@ impl Midi {
         core.send(Message::Note(note));
     }
 
-    fn dispatch_midi(&mut self, core: &mut Core, data: &[u8], ts: u64) {
+    // Clock and song position messages are collected in `clock`, to be
+    // applied to the transport.
+    fn dispatch_midi(&mut self, core: &mut Core, data: &[u8], ts: u64,
+        clock: &mut Vec<ClockEvent>)
+    {
         let mut i = 0;
         while i < data.len() {
-            if data[i] == 0xb0 {
+            let mut status = data[i];
+            if let Some(ref mut mpe) = self.mpe {
+                let channel = status & 0x0f;
+                if (0x80..0xf0).contains(&status) {
+                    if mpe.zone.is_member(channel) {
+                        i += mpe.dispatch_member(core, &data[i..], ts);
+                        continue;
+                    }
+                    // the master channel stands in for channel 1
+                    if channel == mpe.zone.master_channel() {
+                        status &= 0xf0;
+                    }
+                }
+            }
+            if status == 0xb0 && i + 2 < data.len() {
                 let controller = data[i + 1];
                 let value = data[i + 2];
                 match controller {
-                    1 => {
-                        let cutoff = self.control_map.cutoff;
-                        self.set_ctrl_const(core, value, 0.0, 22_000f32.log2(), cutoff, ts);
-                    }
-                    2 => {
-                        let reso = self.control_map.reso;
+                    0 => self.bank = (value as u16) << 7 | (self.bank & 0x7f),
+                    32 => self.bank = (self.bank & !0x7f) | value as u16,
+                    1 => if let Some(mod_wheel) = self.mod_node(|ids| ids.mod_wheel) {
+                        self.set_ctrl_const(core, value, 0.0, 1.0, mod_wheel, ts);
+                    },
+                    2 => if let Some(reso) = self.control_map.reso {
                         self.set_ctrl_const(core, value, 0.0, 0.995, reso, ts);
+                    },
+                    3 => {
+                        let glide = self.control_map.glide;
+                        self.set_ctrl_const(core, value, 0.0, 1.0, glide, ts);
                     }
 
-                    5 => {
-                        let attack = self.control_map.attack;
+                    5 => if let Some(attack) = self.control_map.attack {
                         self.set_ctrl_const(core, value, 0.0, 10.0, attack, ts);
-                    }
-                    6 => {
-                        let decay = self.control_map.decay;
+                    },
+                    6 => if let Some(decay) = self.control_map.decay {
                         self.set_ctrl_const(core, value, 0.0, 10.0, decay, ts);
-                    }
-                    7 => {
-                        let sustain = self.control_map.sustain;
+                    },
+                    7 => if let Some(sustain) = self.control_map.sustain {
                         self.set_ctrl_const(core, value, 0.0, 6.0, sustain, ts);
-                    }
-                    8 => {
-                        let release = self.control_map.release;
+                    },
+                    8 => if let Some(release) = self.control_map.release {
                         self.set_ctrl_const(core, value, 0.0, 10.0, release, ts);
+                    },
+                    74 => if let Some(cutoff) = self.control_map.cutoff {
+                        self.set_ctrl_const(core, value, 0.0, 22_000f32.log2(), cutoff, ts);
+                    },
+                    91 => {
+                        let reverb_mix = self.control_map.reverb_mix;
+                        self.set_ctrl_const(core, value, 0.0, 1.0, reverb_mix, ts);
                     }
                     _ => println!("don't have handler for controller {}", controller),
                 }
                 i += 3;
-            } else if data[i] == 0x90 || data[i] == 0x80 {
+            } else if (status == 0x90 || status == 0x80) && i + 2 < data.len() {
                 let midi_num = data[i + 1];
                 let velocity = data[i + 2];
-                let on = data[i] == 0x90 && velocity > 0;
-                if on || self.cur_note == Some(midi_num) {
-                    let targets = self.control_map.note_receivers.clone();
-                    self.send_note(core, targets, midi_num as f32, velocity as f32, on, ts);
-                    self.cur_note = if on { Some(midi_num) } else { None }
+                let on = status == 0x90 && velocity > 0;
+                match self.arpeggiator {
+                    Some(ref mut arp) if on => arp.note_on(midi_num, velocity),
+                    Some(ref mut arp) => arp.note_off(midi_num),
+                    None => self.play_note(core, midi_num, velocity, on, ts),
+                }
+                i += 3;
+            } else if status == 0xc0 && i + 1 < data.len() {
+                self.program_change = Some((self.bank, data[i + 1]));
+                i += 2;
+            } else if status == 0xd0 && i + 1 < data.len() {
+                if let Some(aftertouch) = self.mod_node(|ids| ids.aftertouch) {
+                    self.set_ctrl_const(core, data[i + 1], 0.0, 1.0, aftertouch, ts);
+                }
+                i += 2;
+            } else if status == 0xe0 && i + 2 < data.len() {
+                let value = ((data[i + 2] as i32) << 7 | data[i + 1] as i32) - 8192;
+                let param = SetParam {
+                    ix: self.control_map.bend,
+                    param_ix: 0,
+                    val: value as f32 * (1.0 / 8192.0),
+                    timestamp: ts,
+                };
+                core.send(Message::SetParam(param));
+                i += 3;
+            } else if data[i] >= 0xf8 {
+                // realtime messages are a single byte
+                match data[i] {
+                    0xf8 => clock.push(ClockEvent::Tick),
+                    0xfa => clock.push(ClockEvent::Start),
+                    0xfb => clock.push(ClockEvent::Continue),
+                    0xfc => clock.push(ClockEvent::Stop),
+                    _ => (),
                 }
+                i += 1;
+            } else if data[i] == 0xf2 && i + 2 < data.len() {
+                let sixteenths = (data[i + 2] as u16) << 7 | data[i + 1] as u16;
+                clock.push(ClockEvent::SongPosition(sixteenths));
                 i += 3;
             } else {
                 break;
@@ -339,11 +1308,230 @@ impl Midi {
         }
     }
 
-    fn dispatch_note_event(&mut self, core: &mut Core, note_event: &NoteEvent) {
-        let mut data = [0u8; 3];
-        data[0] = if note_event.down { 0x90 } else { 0x80 };
-        data[1] = note_event.note;
-        data[2] = note_event.velocity;
-        self.dispatch_midi(core, &data, time::precise_time_ns());
+    // Play a note on the note receivers. Note-off only applies to the most
+    // recent note, as this is a mono synth.
+    fn play_note(&mut self, core: &mut Core, midi_num: u8, velocity: u8, on: bool, ts: u64) {
+        if let Some(ref mut mpe) = self.mpe {
+            mpe.play_note(core, midi_num, velocity, on, ts);
+            return;
+        }
+        if on || self.cur_note == Some(midi_num) {
+            let targets = self.control_map.note_receivers.clone();
+            self.send_note(core, targets, midi_num as f32, velocity as f32, on, ts);
+            self.cur_note = if on { Some(midi_num) } else { None }
+        }
+    }
+
+    fn scheduled_note(&mut self, core: &mut Core, note: &ScheduledNote) {
+        self.play_note(core, note.note, note.velocity, note.on, note.timestamp);
+    }
+
+    fn mod_node<F: Fn(&ModMatrixIds) -> usize>(&self, f: F) -> Option<usize> {
+        self.control_map.mod_matrix.as_ref().map(f)
+    }
+}
+
+impl Mpe {
+    // Handle a channel message on a member channel, returning its length.
+    fn dispatch_member(&mut self, core: &mut Core, data: &[u8], ts: u64) -> usize {
+        let status = data[0] & 0xf0;
+        let channel = data[0] & 0x0f;
+        let len = if status == 0xc0 || status == 0xd0 { 2 } else { 3 };
+        if data.len() < len {
+            return data.len();
+        }
+        let voice = match self.voices.iter_mut().find(|voice| voice.channel == channel) {
+            Some(voice) => voice,
+            None => return len,
+        };
+        match status {
+            0x80 | 0x90 => {
+                let on = status == 0x90 && data[2] > 0;
+                if on || voice.note == Some(data[1]) {
+                    voice.play(core, data[1], data[2], on, ts);
+                }
+            }
+            0xe0 => {
+                let value = ((data[2] as i32) << 7 | data[1] as i32) - 8192;
+                voice.set_expression(core, modules::NoteExpression::PARAM_BEND,
+                    value as f32 * (1.0 / 8192.0), ts);
+            }
+            0xd0 => voice.set_expression(core, modules::NoteExpression::PARAM_PRESSURE,
+                data[1] as f32 * (1.0 / 127.0), ts),
+            0xb0 if data[1] == 74 => voice.set_expression(core,
+                modules::NoteExpression::PARAM_TIMBRE, data[2] as f32 * (1.0 / 127.0), ts),
+            _ => (),
+        }
+        len
+    }
+
+    // Play a note that didn't arrive on a member channel, on a free voice if
+    // there is one.
+    fn play_note(&mut self, core: &mut Core, midi_num: u8, velocity: u8, on: bool, ts: u64) {
+        if on {
+            let ix = match self.voices.iter().position(|voice| voice.note.is_none()) {
+                Some(ix) => ix,
+                None => {
+                    let ix = self.next_steal % self.voices.len();
+                    self.next_steal = ix + 1;
+                    ix
+                }
+            };
+            self.voices[ix].play(core, midi_num, velocity, true, ts);
+        } else if let Some(voice) = self.voices.iter_mut().find(|v| v.note == Some(midi_num)) {
+            voice.play(core, midi_num, velocity, false, ts);
+        }
+    }
+}
+
+impl MpeVoice {
+    fn play(&mut self, core: &mut Core, midi_num: u8, velocity: u8, on: bool, ts: u64) {
+        let note = Note {
+            ixs: self.note_receivers.clone().into_boxed_slice(),
+            midi_num: midi_num as f32,
+            velocity: velocity as f32,
+            on,
+            timestamp: ts,
+        };
+        core.send(Message::Note(note));
+        self.note = if on { Some(midi_num) } else { None };
+    }
+
+    fn set_expression(&self, core: &mut Core, param_ix: usize, val: f32, ts: u64) {
+        let param = SetParam {
+            ix: self.expression,
+            param_ix,
+            val,
+            timestamp: ts,
+        };
+        core.send(Message::SetParam(param));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+    use worker::Worker;
+    use super::*;
+
+    // Render a note on the mono synth, after `setup` has been applied.
+    fn render_note<F: FnOnce(&mut Engine)>(setup: F) -> Vec<f32> {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(48_000.0, rx, tx);
+        engine.init_monosynth();
+        setup(&mut engine);
+        let t = time::precise_time_ns();
+        engine.dispatch_midi(&[0x90, 60, 100], t);
+        worker.render(t + 1_000_000, 100, 48_000.0)
+    }
+
+    fn control(engine: &mut Engine, controller: u8, value: u8) {
+        engine.dispatch_midi(&[0xb0, controller, value], time::precise_time_ns());
+    }
+
+    #[test]
+    fn mod_wheel_only_drives_matrix() {
+        let plain = render_note(|_| ());
+        let wheel = render_note(|engine| control(engine, 1, 127));
+        assert!(plain == wheel, "mod wheel changed the sound without a matrix slot");
+        let routed = render_note(|engine| {
+            engine.set_mod_slot(0, ModSource::ModWheel, ModDest::Cutoff, -3.0);
+            control(engine, 1, 127);
+        });
+        assert!(plain != routed);
+        let cutoff = render_note(|engine| control(engine, 74, 40));
+        assert!(plain != cutoff);
+    }
+
+    // Play a note on an SFZ instrument whose region's sample is `data`.
+    fn render_sfz(text: &str, data: Vec<f32>) -> Vec<f32> {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(48_000.0, rx, tx);
+        let sfz = Sfz::parse(text, Path::new("")).unwrap();
+        let sample = Arc::new(modules::Sample::new(48_000.0, data));
+        let control_map = engine.core.init_sfz(&sfz, &[sample]);
+        engine.midi = Some(Midi::new(control_map));
+        let t = time::precise_time_ns();
+        engine.dispatch_midi(&[0x90, 60, 127], t);
+        let out = worker.render(t + 1_000_000, 20, 48_000.0);
+        // skip the limiter's latency
+        let start = out.iter().position(|&x| x != 0.0).unwrap();
+        out[start..].to_vec()
+    }
+
+    #[test]
+    fn sfz_end_points_are_inclusive() {
+        let out = render_sfz("<region> sample=a.wav end=49", vec![0.5; 100]);
+        assert_eq!(out.iter().take_while(|&&x| x != 0.0).count(), 50);
+        assert!(out[50..].iter().all(|&x| x == 0.0));
+
+        let ramp = (0..100).map(|i| (i + 1) as f32 * 0.001).collect();
+        let out = render_sfz("<region> sample=a.wav loop_mode=loop_continuous loop_start=0 \
+            loop_end=9", ramp);
+        for i in 0..100 {
+            assert_eq!(out[i], out[i + 10]);
+        }
+        assert!(out[9] > out[8]);
+    }
+
+    fn mpe_notes(engine: &Engine) -> Vec<Option<u8>> {
+        let mpe = engine.midi.as_ref().unwrap().mpe.as_ref().unwrap();
+        mpe.voices.iter().map(|voice| voice.note).collect()
+    }
+
+    #[test]
+    fn mpe_voice_allocation() {
+        let (_worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(48_000.0, rx, tx);
+        engine.init_mpe(MpeZone::lower(3));
+        // each member channel plays its own voice
+        engine.dispatch_midi(&[0x92, 60, 100, 0x91, 64, 100], 0);
+        assert_eq!(mpe_notes(&engine), vec![Some(64), Some(60), None]);
+        // a note-off only ends the note on its channel
+        engine.dispatch_midi(&[0x82, 64, 0, 0x91, 64, 0], 0);
+        assert_eq!(mpe_notes(&engine), vec![None, Some(60), None]);
+
+        // notes on the master channel take free voices, then steal in turn
+        for &note in &[70, 71, 72, 73] {
+            engine.dispatch_midi(&[0x90, note, 100], 0);
+        }
+        assert_eq!(mpe_notes(&engine), vec![Some(72), Some(73), Some(71)]);
+        engine.dispatch_midi(&[0x80, 71, 0], 0);
+        assert_eq!(mpe_notes(&engine), vec![Some(72), Some(73), None]);
+    }
+
+    #[test]
+    fn truncated_messages() {
+        let (_worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(48_000.0, rx, tx);
+        engine.init_mpe(MpeZone::lower(3));
+        for data in &[&[0xe0, 0][..], &[0xe1, 0], &[0xb0, 1], &[0x90, 60], &[0xf2, 0]] {
+            engine.dispatch_midi(data, 0);
+        }
+        assert_eq!(mpe_notes(&engine), vec![None, None, None]);
+    }
+
+    #[test]
+    fn patch_replaces_monosynth() {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(48_000.0, rx, tx);
+        engine.init_monosynth();
+        worker.render(0, 100, 48_000.0);
+        engine.core.rx.recv().count();
+        let old = engine.midi.as_ref().unwrap().control_map.voice_nodes.clone();
+
+        engine.load_patch(&Patch::parse("out saw : pitch\n").unwrap()).unwrap();
+        worker.render(0, 100, 48_000.0);
+        let returned: Vec<_> = engine.core.rx.recv().filter_map(|msg| match msg {
+            Message::Node(ref node) => Some(node.ix),
+            _ => None,
+        }).collect();
+        for id in &old {
+            assert!(returned.contains(id), "node {} was left in the graph", id);
+            assert!(!engine.core.id_alloc.is_allocated(*id));
+        }
+        let control_map = &engine.midi.as_ref().unwrap().control_map;
+        assert_eq!(control_map.note_receivers, vec![control_map.note_pitch]);
+        assert!(control_map.mod_matrix.is_none());
     }
 }
@@ -0,0 +1,168 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A simple FFT, suitable for use on the audio thread.
+//!
+//! All tables are computed when the `Fft` is created, so transforms don't
+//! allocate.
+
+use std::f64::consts;
+use std::ops::{Add, Mul, Sub};
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Complex {
+        Complex { re, im }
+    }
+
+    pub fn conj(self) -> Complex {
+        Complex { re: self.re, im: -self.im }
+    }
+
+    pub fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex { re: self.re + other.re, im: self.im + other.im }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex { re: self.re - other.re, im: self.im - other.im }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+}
+
+/// An in-place radix-2 FFT of a fixed power-of-two size.
+pub struct Fft {
+    twiddles: Box<[Complex]>,
+    bitrev: Box<[usize]>,
+}
+
+impl Fft {
+    /// Create an FFT of the given size, which must be a power of two.
+    pub fn new(size: usize) -> Fft {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+        let twiddles = (0..size / 2).map(|i| {
+            let th = -2.0 * consts::PI * i as f64 / size as f64;
+            Complex::new(th.cos() as f32, th.sin() as f32)
+        }).collect::<Vec<_>>().into_boxed_slice();
+        let lg_size = size.trailing_zeros();
+        let bitrev = (0..size).map(|i| {
+            if lg_size == 0 { 0 } else { i.reverse_bits() >> (0usize.count_zeros() - lg_size) }
+        }).collect::<Vec<_>>().into_boxed_slice();
+        Fft { twiddles, bitrev }
+    }
+
+    pub fn size(&self) -> usize {
+        self.bitrev.len()
+    }
+
+    /// Forward transform, in place. The result is not scaled.
+    pub fn forward(&self, buf: &mut [Complex]) {
+        self.transform(buf, false);
+    }
+
+    /// Inverse transform, in place. The result is scaled by 1/n, so that it
+    /// inverts `forward`.
+    pub fn inverse(&self, buf: &mut [Complex]) {
+        self.transform(buf, true);
+        let scale = 1.0 / self.size() as f32;
+        for x in buf.iter_mut() {
+            x.re *= scale;
+            x.im *= scale;
+        }
+    }
+
+    fn transform(&self, buf: &mut [Complex], inverse: bool) {
+        let n = self.size();
+        assert_eq!(buf.len(), n);
+        for i in 0..n {
+            let j = self.bitrev[i];
+            if i < j {
+                buf.swap(i, j);
+            }
+        }
+        let mut half = 1;
+        while half < n {
+            let stride = n / (2 * half);
+            for start in (0..n).step_by(2 * half) {
+                for k in 0..half {
+                    let mut w = self.twiddles[k * stride];
+                    if inverse {
+                        w = w.conj();
+                    }
+                    let a = buf[start + k];
+                    let b = buf[start + k + half] * w;
+                    buf[start + k] = a + b;
+                    buf[start + k + half] = a - b;
+                }
+            }
+            half *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let fft = Fft::new(64);
+        let input: Vec<Complex> = (0..64)
+            .map(|i| Complex::new((i as f32 * 0.37).sin(), (i as f32 * 0.11).cos()))
+            .collect();
+        let mut buf = input.clone();
+        fft.forward(&mut buf);
+        fft.inverse(&mut buf);
+        for (x, y) in input.iter().zip(buf.iter()) {
+            assert!((x.re - y.re).abs() < 1e-5 && (x.im - y.im).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn sine_lands_in_bin() {
+        let fft = Fft::new(64);
+        let mut buf: Vec<Complex> = (0..64)
+            .map(|i| Complex::new((2.0 * consts::PI * 5.0 * i as f64 / 64.0).cos() as f32, 0.0))
+            .collect();
+        fft.forward(&mut buf);
+        for (k, x) in buf.iter().enumerate() {
+            let expected = if k == 5 || k == 59 { 32.0 * 32.0 } else { 0.0 };
+            assert!((x.norm_sqr() - expected).abs() < 1e-2, "bin {}: {}", k, x.norm_sqr());
+        }
+    }
+}
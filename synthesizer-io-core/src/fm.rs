@@ -0,0 +1,178 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Algorithms for FM synthesis: how a set of operators is wired together.
+//!
+//! Operators are numbered from 1 as on the DX series, with operator 1 always
+//! a carrier. In the presets, modulators always have higher numbers than the
+//! operators they modulate.
+
+/// A wiring of FM operators.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FmAlgorithm {
+    /// Number of operators.
+    pub n_ops: usize,
+    /// Pairs of (modulator, modulated) operator numbers.
+    pub edges: Vec<(usize, usize)>,
+    /// Operators whose outputs are summed into the voice output.
+    pub carriers: Vec<usize>,
+    /// The operator with self-feedback, if any.
+    pub feedback: Option<usize>,
+}
+
+// (edges, carriers, feedback operator) for the presets.
+type Preset = (&'static [(usize, usize)], &'static [usize], usize);
+
+// The eight algorithms of the 4-operator DX/TX family.
+const FOUR_OP: [Preset; 8] = [
+    (&[(4, 3), (3, 2), (2, 1)], &[1], 4),
+    (&[(4, 2), (3, 2), (2, 1)], &[1], 4),
+    (&[(4, 1), (3, 2), (2, 1)], &[1], 4),
+    (&[(4, 3), (3, 1), (2, 1)], &[1], 4),
+    (&[(4, 3), (2, 1)], &[1, 3], 4),
+    (&[(4, 1), (4, 2), (4, 3)], &[1, 2, 3], 4),
+    (&[(4, 3)], &[1, 2, 3], 4),
+    (&[], &[1, 2, 3, 4], 4),
+];
+
+// The DX7 algorithms, by number. Algorithms 4 and 6 are left out, as their
+// feedback loops run through more than one operator.
+const DX7: [(usize, Preset); 30] = [
+    (1, (&[(6, 5), (5, 4), (4, 3), (2, 1)], &[1, 3], 6)),
+    (2, (&[(6, 5), (5, 4), (4, 3), (2, 1)], &[1, 3], 2)),
+    (3, (&[(6, 5), (5, 4), (3, 2), (2, 1)], &[1, 4], 6)),
+    (5, (&[(6, 5), (4, 3), (2, 1)], &[1, 3, 5], 6)),
+    (7, (&[(6, 5), (5, 3), (4, 3), (2, 1)], &[1, 3], 6)),
+    (8, (&[(6, 5), (5, 3), (4, 3), (2, 1)], &[1, 3], 4)),
+    (9, (&[(6, 5), (5, 3), (4, 3), (2, 1)], &[1, 3], 2)),
+    (10, (&[(6, 4), (5, 4), (3, 2), (2, 1)], &[1, 4], 3)),
+    (11, (&[(6, 4), (5, 4), (3, 2), (2, 1)], &[1, 4], 6)),
+    (12, (&[(6, 3), (5, 3), (4, 3), (2, 1)], &[1, 3], 2)),
+    (13, (&[(6, 3), (5, 3), (4, 3), (2, 1)], &[1, 3], 6)),
+    (14, (&[(6, 4), (5, 4), (4, 3), (2, 1)], &[1, 3], 6)),
+    (15, (&[(6, 4), (5, 4), (4, 3), (2, 1)], &[1, 3], 2)),
+    (16, (&[(6, 5), (5, 1), (4, 3), (3, 1), (2, 1)], &[1], 6)),
+    (17, (&[(6, 5), (5, 1), (4, 3), (3, 1), (2, 1)], &[1], 2)),
+    (18, (&[(6, 5), (5, 4), (4, 1), (3, 1), (2, 1)], &[1], 3)),
+    (19, (&[(6, 5), (6, 4), (3, 2), (2, 1)], &[1, 4, 5], 6)),
+    (20, (&[(6, 4), (5, 4), (3, 2), (3, 1)], &[1, 2, 4], 3)),
+    (21, (&[(6, 5), (6, 4), (3, 2), (3, 1)], &[1, 2, 4, 5], 3)),
+    (22, (&[(6, 5), (6, 4), (6, 3), (2, 1)], &[1, 3, 4, 5], 6)),
+    (23, (&[(6, 5), (6, 4), (3, 2)], &[1, 2, 4, 5], 6)),
+    (24, (&[(6, 5), (6, 4), (6, 3)], &[1, 2, 3, 4, 5], 6)),
+    (25, (&[(6, 5), (6, 4)], &[1, 2, 3, 4, 5], 6)),
+    (26, (&[(6, 4), (5, 4), (3, 2)], &[1, 2, 4], 6)),
+    (27, (&[(6, 4), (5, 4), (3, 2)], &[1, 2, 4], 3)),
+    (28, (&[(5, 4), (4, 3), (2, 1)], &[1, 3, 6], 5)),
+    (29, (&[(6, 5), (4, 3)], &[1, 2, 3, 5], 6)),
+    (30, (&[(5, 4), (4, 3)], &[1, 2, 3, 6], 5)),
+    (31, (&[(6, 5)], &[1, 2, 3, 4, 5], 6)),
+    (32, (&[], &[1, 2, 3, 4, 5, 6], 6)),
+];
+
+impl FmAlgorithm {
+    /// Create an algorithm from explicit wiring.
+    ///
+    /// Panics if an operator number is out of range, or if the modulation
+    /// graph has a cycle (self-feedback is handled by `feedback`, not edges).
+    pub fn new(n_ops: usize, edges: Vec<(usize, usize)>, carriers: Vec<usize>,
+        feedback: Option<usize>) -> FmAlgorithm
+    {
+        let in_range = |op: usize| op >= 1 && op <= n_ops;
+        assert!(edges.iter().all(|&(m, c)| in_range(m) && in_range(c)), "operator out of range");
+        assert!(carriers.iter().cloned().all(in_range), "carrier out of range");
+        assert!(feedback.map_or(true, in_range), "feedback operator out of range");
+        let alg = FmAlgorithm { n_ops, edges, carriers, feedback };
+        assert!(alg.order().is_some(), "modulation graph has a cycle");
+        alg
+    }
+
+    fn from_preset(n_ops: usize, preset: &Preset) -> FmAlgorithm {
+        let &(edges, carriers, feedback) = preset;
+        FmAlgorithm::new(n_ops, edges.to_vec(), carriers.to_vec(), Some(feedback))
+    }
+
+    /// One of the eight 4-operator algorithms, numbered from 1.
+    pub fn four_op(n: usize) -> Option<FmAlgorithm> {
+        if n >= 1 && n <= FOUR_OP.len() {
+            Some(FmAlgorithm::from_preset(4, &FOUR_OP[n - 1]))
+        } else {
+            None
+        }
+    }
+
+    /// A 6-operator DX7 algorithm, by its number (1 to 32).
+    ///
+    /// Feedback here is always a single operator modulating itself, so
+    /// algorithms 4 and 6, whose feedback loops span two or three operators,
+    /// return `None`.
+    pub fn dx7(n: usize) -> Option<FmAlgorithm> {
+        DX7.iter().find(|&&(num, _)| num == n)
+            .map(|(_, preset)| FmAlgorithm::from_preset(6, preset))
+    }
+
+    /// The modulators of an operator.
+    pub fn modulators<'a>(&'a self, op: usize) -> impl Iterator<Item = usize> + 'a {
+        self.edges.iter().filter(move |&&(_, c)| c == op).map(|&(m, _)| m)
+    }
+
+    /// An order in which operators can be created so that every modulator
+    /// comes before the operators it modulates, or `None` if there's a cycle.
+    pub fn order(&self) -> Option<Vec<usize>> {
+        let mut done = vec![false; self.n_ops + 1];
+        let mut order = Vec::with_capacity(self.n_ops);
+        while order.len() < self.n_ops {
+            let next = (1..=self.n_ops).find(|&op| !done[op] &&
+                self.modulators(op).all(|m| done[m]))?;
+            done[next] = true;
+            order.push(next);
+        }
+        Some(order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dx7_by_number() {
+        for n in 1..=32 {
+            let alg = FmAlgorithm::dx7(n);
+            assert_eq!(alg.is_none(), n == 4 || n == 6, "algorithm {}", n);
+            if let Some(alg) = alg {
+                assert_eq!(alg.n_ops, 6);
+                // every operator is a carrier or modulates something
+                for op in 1..=6 {
+                    assert!(alg.carriers.contains(&op) || alg.edges.iter().any(|e| e.0 == op));
+                }
+            }
+        }
+        assert!(FmAlgorithm::dx7(0).is_none());
+        assert!(FmAlgorithm::dx7(33).is_none());
+        let alg = FmAlgorithm::dx7(18).unwrap();
+        assert_eq!(alg.carriers, vec![1]);
+        assert_eq!(alg.feedback, Some(3));
+        let mut mods: Vec<_> = alg.modulators(1).collect();
+        mods.sort();
+        assert_eq!(mods, vec![2, 3, 4]);
+        assert_eq!(alg.order().unwrap().last(), Some(&1));
+    }
+
+    #[test]
+    fn four_op_by_number() {
+        assert!(FmAlgorithm::four_op(0).is_none());
+        assert_eq!(FmAlgorithm::four_op(8).unwrap().carriers, vec![1, 2, 3, 4]);
+    }
+}
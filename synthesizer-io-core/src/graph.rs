@@ -18,15 +18,17 @@ use std::ops::DerefMut;
 use std::ptr;
 use std::mem;
 
+use automation::Lane;
 use queue::Item;
 use module::{Module, Buffer};
+use tuning::Tuning;
 
 
-// maximum number of control inputs
-const MAX_CTRL: usize = 16;
+/// Maximum number of control inputs to a node.
+pub const MAX_CTRL: usize = 16;
 
-// maximum number of buffer inputs
-const MAX_BUF: usize = 16;
+/// Maximum number of buffer inputs to a node.
+pub const MAX_BUF: usize = 16;
 
 const SENTINEL: usize = !0;
 
@@ -63,11 +65,30 @@ pub enum Message {
     /// it as three separate control lines (gate, pitch, velocity).
     Note(Note),
 
+    /// A new tuning table for pitch-producing modules.
+    SetTuning(SetTuning),
+
+    /// An automation lane for a node, replacing the one it has.
+    SetLane(SetLane),
+
+    /// Removal of the node at an id, which must no longer be an input to
+    /// any node in the graph. The node is sent back to be freed.
+    Remove(usize),
+
     /// A request to shut down in an orderly way. Currently does nothing.
     Quit,
 }
 
 impl Message {
+    /// The time at which the message should take effect, if it has one.
+    pub fn timestamp(&self) -> Option<u64> {
+        match *self {
+            Message::SetParam(ref param) => Some(param.timestamp),
+            Message::Note(ref note) => Some(note.timestamp),
+            _ => None,
+        }
+    }
+
     fn get_node(&self) -> Option<&Node> {
         match *self {
             Message::Node(ref node) => Some(node),
@@ -105,6 +126,18 @@ pub struct Note {
     pub timestamp: u64,
 }
 
+/// A struct that carries a tuning table to a set of nodes
+pub struct SetTuning {
+    pub ixs: Box<[usize]>,  // list of node ix's affected by this tuning
+    pub tuning: Box<Tuning>,
+}
+
+/// A struct that carries an automation lane to a node
+pub struct SetLane {
+    pub ix: usize,
+    pub lane: Lane,
+}
+
 pub trait IntoBoxedSlice<T> {
     fn into_box(self) -> Box<[T]>;
 }
@@ -195,13 +228,25 @@ impl Graph {
         self.get_node_mut(ix).unwrap().module.deref_mut()
     }
 
-    /// Replace a graph node with a new item, returning the old value.
-    /// Lock-free.
+    /// The module at the given index, or `None` if the index is out of range
+    /// or has no node. Lock-free.
+    pub fn module_mut(&mut self, ix: usize) -> Option<&mut dyn Module> {
+        if ix >= self.nodes.len() {
+            return None;
+        }
+        let node = self.get_node_mut(ix)?;
+        Some(node.module.deref_mut())
+    }
+
+    /// Replace a graph node with a new item (or remove it, with `None`),
+    /// returning the old value. Lock-free.
     pub fn replace(&mut self, ix: usize, item: Option<Item<Message>>) -> Option<Item<Message>> {
         let mut old_item = mem::replace(&mut self.nodes[ix], item);
         if let Some(ref mut old) = old_item {
             if let Message::Node(ref mut old_node) = *old.deref_mut() {
-                self.get_node_mut(ix).unwrap().module.migrate(old_node.module.deref_mut());
+                if let Some(node) = self.get_node_mut(ix) {
+                    node.module.migrate(old_node.module.deref_mut());
+                }
             }
         }
         old_item
@@ -53,6 +53,11 @@ impl IdAllocator {
         }
     }
 
+    /// Whether an id has been issued (or reserved) and not freed.
+    pub fn is_allocated(&self, id: usize) -> bool {
+        id < self.highwater && !self.free.contains(&id)
+    }
+
     /// Reserve an id, preventing it from being issued.
     pub fn reserve(&mut self, id: usize) {
         if id == self.highwater {
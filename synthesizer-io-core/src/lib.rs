@@ -19,10 +19,22 @@ extern crate lazy_static;
 
 extern crate time;
 
+pub mod automation;
 pub mod engine;
+pub mod fft;
+pub mod fm;
 pub mod graph;
 pub mod id_allocator;
+pub mod midi_out;
 pub mod module;
 pub mod modules;
+pub mod mpe;
+pub mod osc;
+pub mod oversample;
+pub mod patch;
 pub mod queue;
+pub mod sfz;
+pub mod transport;
+pub mod tuning;
+pub mod wav;
 pub mod worker;
@@ -0,0 +1,113 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MIDI output from the engine.
+//!
+//! The engine sends incoming events (when thru is enabled), notes from the
+//! sequencer and arpeggiator, MIDI clock, and control changes made from the
+//! UI, so that external synths and motorized controllers can follow it.
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+/// A destination for MIDI messages.
+///
+/// Each message comes with the time (as from `time::precise_time_ns`) at
+/// which it should be sent. Notes and clock are scheduled ahead of time, so
+/// timestamps may be in the future, and messages don't necessarily arrive in
+/// timestamp order; an implementation driving a device should hold each
+/// message until it is due.
+pub trait MidiOut: Send {
+    fn send(&mut self, timestamp: u64, data: &[u8]);
+}
+
+// Timestamped messages, in the order they were sent.
+type Messages = Vec<(u64, Vec<u8>)>;
+
+/// A MIDI output that records messages in memory. Clones share the same
+/// record, so one can be given to the engine and another kept to read back
+/// what was sent.
+#[derive(Clone, Default)]
+pub struct MidiBuffer {
+    messages: Arc<Mutex<Messages>>,
+}
+
+impl MidiBuffer {
+    pub fn new() -> MidiBuffer {
+        MidiBuffer::default()
+    }
+
+    /// Take the messages sent so far, in the order they were sent.
+    pub fn take(&self) -> Vec<(u64, Vec<u8>)> {
+        mem::take(&mut *self.messages.lock().unwrap())
+    }
+}
+
+impl MidiOut for MidiBuffer {
+    fn send(&mut self, timestamp: u64, data: &[u8]) {
+        self.messages.lock().unwrap().push((timestamp, data.to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::Engine;
+    use transport::{Step, StepSequencer};
+    use worker::Worker;
+
+    fn engine_with_output() -> (Engine, MidiBuffer) {
+        let (_worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(48_000.0, rx, tx);
+        engine.init_monosynth();
+        let buffer = MidiBuffer::new();
+        engine.set_midi_out(Some(Box::new(buffer.clone())));
+        (engine, buffer)
+    }
+
+    #[test]
+    fn thru() {
+        let (mut engine, buffer) = engine_with_output();
+        engine.dispatch_midi(&[0x90, 60, 100], 1000);
+        assert!(buffer.take().is_empty());
+        engine.set_midi_thru(true);
+        engine.dispatch_midi(&[0x90, 60, 100], 2000);
+        engine.dispatch_midi(&[0xb0, 1, 64], 3000);
+        assert_eq!(buffer.take(), vec![(2000, vec![0x90, 60, 100]), (3000, vec![0xb0, 1, 64])]);
+    }
+
+    #[test]
+    fn control_feedback() {
+        let (mut engine, buffer) = engine_with_output();
+        engine.set_control(1, 42);
+        let sent = buffer.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, vec![0xb0, 1, 42]);
+    }
+
+    #[test]
+    fn sequencer_notes() {
+        let (mut engine, buffer) = engine_with_output();
+        let mut sequencer = StepSequencer::new(1, 4);
+        sequencer.set_step(0, Some(Step { note: 64, velocity: 90, gate: 0.5 }));
+        engine.set_sequencer(Some(sequencer));
+        engine.start_transport();
+        let sent = buffer.take();
+        // 120 bpm, 4 steps per beat: a step every 125ms, so the first step
+        // (at the start time) is within the scheduling window
+        assert_eq!(sent[0].1, vec![0x90, 64, 90]);
+        assert_eq!(sent[1].1, vec![0x80, 64, 0]);
+        assert_eq!(sent[1].0 - sent[0].0, 62_500_000);
+    }
+}
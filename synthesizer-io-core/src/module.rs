@@ -16,6 +16,9 @@
 
 use std::any::Any;
 
+use automation::Lane;
+use tuning::Tuning;
+
 pub const N_SAMPLES_PER_CHUNK: usize = 32;
 
 pub struct Buffer {
@@ -46,6 +49,28 @@ impl Default for Buffer {
     }
 }
 
+/// Names of a module's inputs and outputs, so a patcher can lay it out
+/// without instantiating it.
+#[derive(Debug)]
+pub struct Ports {
+    pub buf_in: &'static [&'static str],
+    pub ctrl_in: &'static [&'static str],
+    pub buf_out: &'static [&'static str],
+    pub ctrl_out: &'static [&'static str],
+}
+
+impl Ports {
+    /// Total number of inputs, audio and control.
+    pub fn n_inputs(&self) -> usize {
+        self.buf_in.len() + self.ctrl_in.len()
+    }
+
+    /// Total number of outputs, audio and control.
+    pub fn n_outputs(&self) -> usize {
+        self.buf_out.len() + self.ctrl_out.len()
+    }
+}
+
 pub trait Module: ToAny + Send {
     /// Report the number of buffers this module is expected to generate.
     fn n_bufs_out(&self) -> usize { 0 }
@@ -76,9 +101,24 @@ pub trait Module: ToAny + Send {
     #[allow(unused)]
     fn set_param(&mut self, param_ix: usize, val: f32, timestamp: u64) {}
 
+    /// Whether the module responds to notes, so that it should be sent them.
+    /// Modules that implement `handle_note` should return true.
+    fn receives_notes(&self) -> bool { false }
+
     /// Handle a note on or off message.
     #[allow(unused)]
     fn handle_note(&mut self, midi_num: f32, velocity: f32, on: bool) {}
+
+    /// Accept a new tuning table. Modules that turn note numbers into pitch
+    /// should copy it (it's a fixed-size array, so this doesn't allocate).
+    #[allow(unused)]
+    fn set_tuning(&mut self, tuning: &Tuning) {}
+
+    /// Accept an automation lane. Modules that can be automated should swap
+    /// it with the lane they hold, so that the old one is returned with the
+    /// message and freed off the audio thread.
+    #[allow(unused)]
+    fn swap_lane(&mut self, lane: &mut Lane) {}
 }
 
 pub trait ToAny {
@@ -13,17 +13,41 @@
 // limitations under the License.
 
 //! Attack, decay, sustain, release.
+//!
+//! Control inputs: attack, decay, sustain, release, and optionally delay,
+//! hold and velocity sensitivity. Times are given as log2 of the number of
+//! chunks a full-scale stage takes; sustain is log2 gain plus 6.
+//!
+//! If a buffer input is wired, it is treated as a gate signal (high above
+//! 0.5), and notes only supply the velocity.
+//!
+//! Outputs: two control values (log2 gain at the end of the chunk, as consumed
+//! by `Gain`, then the same as linear gain, for use as a modulation source)
+//! and one buffer of linear gain, computed per sample so that stage
+//! transitions happen at sample granularity.
 
-use module::{Module, Buffer};
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
 
 pub struct Adsr {
-    value: f32,
+    level: f32,  // linear gain
     state: State,
+    stage_pos: f32,  // progress through delay or hold stage, 0..1
+    gate: bool,
+    // Whether a gate buffer was wired on the last run, in which case notes
+    // only set the velocity.
+    gate_wired: bool,
+    velocity: f32,  // 0..1
+    curve: Curve,
+    trigger: Trigger,
+    floor: f32,  // log2 gain considered silent
 }
 
+#[derive(Clone, Copy)]
 enum State {
     Quiet,
+    Delay,  // note is on, waiting to start
     Attack,  // note is on, rising
+    Hold,  // note is on, held at peak
     Decay,  // note is on, falling
     Sustain,  // note is on, steady
     Release,  // note is off, falling
@@ -31,60 +55,263 @@ enum State {
 
 use self::State::*;
 
+/// The shape of the decay and release stages.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AdsrCurve {
+    /// Attack linear in gain; decay and release linear in the log domain.
+    Exponential,
+    /// All stages linear in gain.
+    Linear,
+}
+
+/// Behavior when a note starts while another is still held.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AdsrTrigger {
+    /// Restart the envelope on every note-on.
+    Retrigger,
+    /// Only restart the envelope when no note is held.
+    Legato,
+}
+
+use self::AdsrCurve as Curve;
+use self::AdsrTrigger as Trigger;
+
+// Per-chunk values derived from the control inputs.
+struct Rates {
+    delay: f32,
+    attack: f32,
+    hold: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+}
+
 impl Adsr {
+    /// Parameter index for the curve (0 = exponential, 1 = linear).
+    pub const PARAM_CURVE: usize = 0;
+
+    /// Parameter index for the trigger mode (0 = retrigger, 1 = legato).
+    pub const PARAM_TRIGGER: usize = 1;
+
+    /// Parameter index for the floor, in log2 gain.
+    pub const PARAM_FLOOR: usize = 2;
+
     pub fn new() -> Adsr {
+        let floor: f32 = -24.0;
         Adsr {
-            value: -24.0,
+            level: floor.exp2(),
             state: Quiet,
+            stage_pos: 0.0,
+            gate: false,
+            gate_wired: false,
+            velocity: 1.0,
+            curve: Curve::Exponential,
+            trigger: Trigger::Retrigger,
+            floor,
         }
     }
-}
 
-impl Module for Adsr {
-    fn n_ctrl_out(&self) -> usize { 1 }
+    pub fn set_curve(&mut self, curve: AdsrCurve) {
+        self.curve = curve;
+    }
 
-    fn handle_note(&mut self, _midi_num: f32, _velocity: f32, on: bool) {
-        if on {
-            self.state = Attack;
-        } else {
+    pub fn set_trigger(&mut self, trigger: AdsrTrigger) {
+        self.trigger = trigger;
+    }
+
+    pub fn set_floor(&mut self, floor: f32) {
+        self.floor = floor;
+    }
+
+    fn gate_on(&mut self) {
+        if !(self.gate && self.trigger == Trigger::Legato) {
+            self.state = Delay;
+            self.stage_pos = 0.0;
+        }
+        self.gate = true;
+    }
+
+    fn gate_off(&mut self) {
+        if self.gate {
             self.state = Release;
         }
+        self.gate = false;
     }
 
-    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
-        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
-    {
+    fn rates(&self, control_in: &[f32]) -> Rates {
+        let per_sample = 1.0 / N_SAMPLES_PER_CHUNK as f32;
+        let stage = |ix: usize| match control_in.get(ix) {
+            Some(&t) => (-t).exp2() * per_sample,
+            None => 1.0,
+        };
+        let rate = |ix: usize| (-control_in[ix]).exp2() * per_sample;
+        let (decay, release) = match self.curve {
+            Curve::Exponential => ((-rate(1)).exp2(), (-rate(3)).exp2()),
+            Curve::Linear => (rate(1), rate(3)),
+        };
+        Rates {
+            delay: stage(4),
+            attack: rate(0),
+            hold: stage(5),
+            decay,
+            sustain: (control_in[2] - 6.0).exp2(),
+            release,
+        }
+    }
+
+    // Advance the envelope by one sample.
+    fn step(&mut self, r: &Rates, floor: f32) {
         match self.state {
-            Quiet => (),
+            Quiet => self.level = floor,
+            Delay => {
+                self.stage_pos += r.delay;
+                if self.stage_pos >= 1.0 {
+                    self.state = Attack;
+                }
+            }
             Attack => {
-                let mut l = self.value.exp2();
-                l += (-control_in[0]).exp2();
-                if l >= 1.0 {
-                    l = 1.0;
+                self.level += r.attack;
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.state = Hold;
+                    self.stage_pos = 0.0;
+                }
+            }
+            Hold => {
+                self.stage_pos += r.hold;
+                if self.stage_pos >= 1.0 {
                     self.state = Decay;
                 }
-                self.value = l.log2();
             }
             Decay => {
-                let sustain = control_in[2] - 6.0;
-                self.value -= (-control_in[1]).exp2();
-                if self.value < sustain {
-                    self.value = sustain;
+                match self.curve {
+                    Curve::Exponential => self.level *= r.decay,
+                    Curve::Linear => self.level -= r.decay,
+                }
+                if self.level < r.sustain {
+                    self.level = r.sustain;
                     self.state = Sustain;
                 }
             }
-            Sustain => {
-                let sustain = control_in[2] - 6.0;
-                self.value = sustain;
-            }
+            Sustain => self.level = r.sustain,
             Release => {
-                self.value -= (-control_in[3]).exp2();
-                if self.value < -24.0 {
-                    self.value = -24.0;
+                match self.curve {
+                    Curve::Exponential => self.level *= r.release,
+                    Curve::Linear => self.level -= r.release,
+                }
+                if self.level < floor {
+                    self.level = floor;
                     self.state = Quiet;
                 }
             }
         }
-        control_out[0] = self.value;
+    }
+}
+
+impl Module for Adsr {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn n_ctrl_out(&self) -> usize { 2 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_adsr) = old.to_any().downcast_ref::<Adsr>() {
+            self.level = old_adsr.level;
+            self.state = old_adsr.state;
+            self.stage_pos = old_adsr.stage_pos;
+            self.gate = old_adsr.gate;
+            self.gate_wired = old_adsr.gate_wired;
+            self.velocity = old_adsr.velocity;
+        }
+    }
+
+    fn receives_notes(&self) -> bool { true }
+
+    fn handle_note(&mut self, _midi_num: f32, velocity: f32, on: bool) {
+        if on {
+            self.velocity = velocity * (1.0 / 127.0);
+            if !self.gate_wired {
+                self.gate_on();
+            }
+        } else if !self.gate_wired {
+            self.gate_off();
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let r = self.rates(control_in);
+        let floor = self.floor.exp2();
+        let vel_sens = control_in.get(6).cloned().unwrap_or(0.0);
+        let vel_gain = self.velocity.max(floor).powf(vel_sens);
+        let gate_in = buf_in.first().map(|b| b.get());
+        self.gate_wired = gate_in.is_some();
+        let out = buf_out[0].get_mut();
+        for i in 0..out.len() {
+            if let Some(gate_in) = gate_in {
+                let gate = gate_in[i] > 0.5;
+                if gate && !self.gate {
+                    self.gate_on();
+                } else if !gate && self.gate {
+                    self.gate_off();
+                }
+            }
+            self.step(&r, floor);
+            out[i] = self.level * vel_gain;
+        }
+        control_out[0] = (self.level.max(floor).log2() + vel_gain.log2()).max(self.floor);
+        control_out[1] = self.level * vel_gain;
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            Adsr::PARAM_CURVE => self.curve = if val == 0.0 {
+                Curve::Exponential
+            } else {
+                Curve::Linear
+            },
+            Adsr::PARAM_TRIGGER => self.trigger = if val == 0.0 {
+                Trigger::Retrigger
+            } else {
+                Trigger::Legato
+            },
+            Adsr::PARAM_FLOOR => self.floor = val,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    // attack, decay, sustain (-2, so 0.25 gain), release
+    const CONTROLS: [f32; 4] = [0.0, 0.0, 4.0, 0.0];
+
+    fn run(adsr: &mut Adsr, gate: f32) -> Vec<f32> {
+        let mut gate_buf = Buffer::default();
+        for x in gate_buf.get_mut().iter_mut() {
+            *x = gate;
+        }
+        let mut ctrl = [0.0; 2];
+        let mut out = [Buffer::default()];
+        adsr.process(&CONTROLS, &mut ctrl, &[&gate_buf], &mut out);
+        out[0].get().to_vec()
+    }
+
+    #[test]
+    fn gate_input_ignores_note_off() {
+        let mut adsr = Adsr::new();
+        adsr.handle_note(60.0, 127.0, true);
+        for _ in 0..20 {
+            run(&mut adsr, 1.0);
+        }
+        assert!(run(&mut adsr, 1.0).iter().all(|&x| (x - 0.25).abs() < 1e-6));
+        // a note-off while the gate is high neither releases nor retriggers
+        adsr.handle_note(60.0, 0.0, false);
+        assert!(run(&mut adsr, 1.0).iter().all(|&x| (x - 0.25).abs() < 1e-6));
+        // dropping the gate does release
+        assert!(run(&mut adsr, 0.0).last().unwrap() < &0.25);
     }
 }
@@ -0,0 +1,99 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A module that converts an audio signal to a control signal, once per
+//! chunk.
+
+use module::{Module, Buffer, Ports};
+
+/// How a chunk of audio is reduced to one control value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AudioToCtrlMode {
+    /// The mean of the samples.
+    Mean,
+    /// The largest absolute value of the samples.
+    Peak,
+}
+
+impl AudioToCtrlMode {
+    /// Decode a mode from a parameter value, as sent by `set_param`.
+    pub fn from_param(val: f32) -> AudioToCtrlMode {
+        match val as i32 {
+            1 => AudioToCtrlMode::Peak,
+            _ => AudioToCtrlMode::Mean,
+        }
+    }
+}
+
+pub struct AudioToCtrl {
+    mode: AudioToCtrlMode,
+}
+
+impl AudioToCtrl {
+    /// Parameter index for the mode (see `AudioToCtrlMode::from_param`).
+    pub const PARAM_MODE: usize = 0;
+
+    pub const PORTS: Ports = Ports {
+        buf_in: &["in"],
+        ctrl_in: &[],
+        buf_out: &[],
+        ctrl_out: &["out"],
+    };
+
+    pub fn new(mode: AudioToCtrlMode) -> AudioToCtrl {
+        AudioToCtrl { mode }
+    }
+}
+
+impl Module for AudioToCtrl {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn process(&mut self, _control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        let buf = buf_in[0].get();
+        control_out[0] = match self.mode {
+            AudioToCtrlMode::Mean => buf.iter().sum::<f32>() * (1.0 / buf.len() as f32),
+            AudioToCtrlMode::Peak => buf.iter().fold(0.0, |m, &x| x.abs().max(m)),
+        };
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        if param_ix == AudioToCtrl::PARAM_MODE {
+            self.mode = AudioToCtrlMode::from_param(val);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    #[test]
+    fn mean_and_peak() {
+        let mut inb = Buffer::default();
+        for (i, x) in inb.get_mut().iter_mut().enumerate() {
+            *x = if i == 5 { -2.0 } else { 0.5 };
+        }
+        let mut module = AudioToCtrl::new(AudioToCtrlMode::Mean);
+        let mut out = [0.0];
+        module.process(&[], &mut out, &[&inb], &mut []);
+        assert_eq!(out[0], 13.5 / 32.0);
+        module.set_param(AudioToCtrl::PARAM_MODE, 1.0, 0);
+        module.process(&[], &mut out, &[&inb], &mut []);
+        assert_eq!(out[0], 2.0);
+        assert_eq!(AudioToCtrlMode::from_param(7.0), AudioToCtrlMode::Mean);
+    }
+}
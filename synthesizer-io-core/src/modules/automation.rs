@@ -0,0 +1,97 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A module that plays an automation lane, evaluated at the time of each
+//! sample.
+//!
+//! Outputs: one buffer with the value for each sample, and one control value
+//! (the value at the start of the chunk). Without a lane, both hold the
+//! module's constant value.
+
+use std::mem;
+
+use automation::Lane;
+use module::{Module, Buffer, Ports};
+
+pub struct Automation {
+    ns_per_sample: f64,
+    value: f32,
+    lane: Lane,
+}
+
+impl Automation {
+    /// Parameter index for the value used when there is no lane.
+    pub const PARAM_VALUE: usize = 0;
+
+    pub const PORTS: Ports = Ports {
+        buf_in: &[],
+        ctrl_in: &[],
+        buf_out: &["value"],
+        ctrl_out: &["value"],
+    };
+
+    pub fn new(sample_rate: f32, value: f32) -> Automation {
+        Automation {
+            ns_per_sample: 1e9 / sample_rate as f64,
+            value,
+            lane: Lane::default(),
+        }
+    }
+
+    /// Set the lane before the module is sent to the worker.
+    pub fn set_lane(&mut self, lane: Lane) {
+        self.lane = lane;
+    }
+}
+
+impl Module for Automation {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_mut::<Automation>() {
+            if self.lane.is_empty() {
+                mem::swap(&mut self.lane, &mut old.lane);
+            }
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        self.process_ts(control_in, control_out, buf_in, buf_out, 0);
+    }
+
+    fn process_ts(&mut self, _control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], buf_out: &mut [Buffer], timestamp: u64)
+    {
+        let out = buf_out[0].get_mut();
+        for (i, y) in out.iter_mut().enumerate() {
+            let t = timestamp + (i as f64 * self.ns_per_sample) as u64;
+            *y = self.lane.value_at(t).unwrap_or(self.value);
+        }
+        control_out[0] = out[0];
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        if param_ix == Automation::PARAM_VALUE {
+            self.value = val;
+        }
+    }
+
+    fn swap_lane(&mut self, lane: &mut Lane) {
+        mem::swap(&mut self.lane, lane);
+    }
+}
@@ -84,6 +84,12 @@ fn raise_matrix(params: StateParams) -> [f32; 16] {
 impl Module for Biquad {
     fn n_bufs_out(&self) -> usize { 1 }
 
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_biquad) = old.to_any().downcast_ref::<Biquad>() {
+            self.state = old_biquad.state;
+        }
+    }
+
     fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
         buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
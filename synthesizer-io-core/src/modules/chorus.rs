@@ -0,0 +1,109 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A stereo chorus, using a delay line modulated by a sine LFO.
+//!
+//! Control inputs (all optional): rate (log2 of Hz, default 0.5Hz), depth
+//! (0 to 1), feedback (0 to 1), mix (0 = dry, 1 = wet).
+//!
+//! The input is mono; outputs are left and right buffers, read from taps
+//! modulated in quadrature.
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+
+use super::delay::DelayLine;
+
+const BASE_DELAY: f32 = 0.015;  // in seconds
+const MAX_MOD: f32 = 0.007;  // in seconds, at full depth
+
+pub struct Chorus {
+    sample_rate: f32,
+    line: DelayLine,
+    phase: f32,  // LFO phase in cycles
+}
+
+impl Chorus {
+    pub fn new(sample_rate: f32) -> Chorus {
+        Chorus {
+            sample_rate,
+            line: DelayLine::new(((BASE_DELAY + MAX_MOD) * sample_rate) as usize + 1),
+            phase: 0.0,
+        }
+    }
+}
+
+impl Module for Chorus {
+    fn n_bufs_out(&self) -> usize { 2 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let ctrl = |ix: usize, default: f32| control_in.get(ix).cloned().unwrap_or(default);
+        let freq = ctrl(0, -1.0).exp2() / self.sample_rate;
+        let depth = ctrl(1, 0.5).clamp(0.0, 1.0) * MAX_MOD * self.sample_rate;
+        let feedback = ctrl(2, 0.0).clamp(0.0, 0.95);
+        let mix = ctrl(3, 0.5);
+        let base = BASE_DELAY * self.sample_rate;
+        let inb = buf_in[0].get();
+        let (out_l, out_r) = buf_out.split_at_mut(1);
+        let out_l = out_l[0].get_mut();
+        let out_r = out_r[0].get_mut();
+        for i in 0..inb.len() {
+            let x = inb[i];
+            let th = self.phase * 2.0 * consts::PI;
+            let wet_l = self.line.read(base + depth * th.sin());
+            let wet_r = self.line.read(base + depth * th.cos());
+            self.line.write(x + feedback * 0.5 * (wet_l + wet_r));
+            out_l[i] = x + mix * (wet_l - x);
+            out_r[i] = x + mix * (wet_r - x);
+            self.phase += freq;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    #[test]
+    fn taps_in_quadrature() {
+        // at 1kHz, the base delay is 15 samples and full depth adds 7
+        let mut chorus = Chorus::new(1000.0);
+        let mut out = Vec::new();
+        for chunk in 0..2 {
+            let mut inb = Buffer::default();
+            if chunk == 0 {
+                inb.get_mut()[0] = 1.0;
+            }
+            let mut bufs = [Buffer::default(), Buffer::default()];
+            // a slow LFO, starting where sine is 0 and cosine is 1
+            chorus.process(&[-10.0, 1.0, 0.0, 1.0], &mut [], &[&inb], &mut bufs);
+            out.push((bufs[0].get().to_vec(), bufs[1].get().to_vec()));
+        }
+        let (ref left, ref right) = out[0];
+        let peak = |buf: &[f32]| (0..buf.len()).max_by(|&a, &b| {
+            buf[a].partial_cmp(&buf[b]).unwrap()
+        }).unwrap();
+        assert_eq!(peak(left), 15);
+        assert_eq!(peak(right), 22);
+        assert!(left[15] > 0.95 && right[22] > 0.95);
+        assert!(out[1].0.iter().chain(&out[1].1).all(|&x| x.abs() < 1e-3));
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A module that limits a control signal to a range.
+
+use module::{Module, Buffer, Ports};
+
+pub struct Clamp {
+    min: f32,
+    max: f32,
+}
+
+impl Clamp {
+    /// Parameter index for the lower bound.
+    pub const PARAM_MIN: usize = 0;
+
+    /// Parameter index for the upper bound.
+    pub const PARAM_MAX: usize = 1;
+
+    pub const PORTS: Ports = Ports {
+        buf_in: &[],
+        ctrl_in: &["in"],
+        buf_out: &[],
+        ctrl_out: &["out"],
+    };
+
+    pub fn new(min: f32, max: f32) -> Clamp {
+        Clamp { min, max }
+    }
+}
+
+impl Module for Clamp {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        // max then min rather than clamp, which panics if the bounds cross
+        control_out[0] = control_in[0].max(self.min).min(self.max);
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            Clamp::PARAM_MIN => self.min = val,
+            Clamp::PARAM_MAX => self.max = val,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::Module;
+    use super::*;
+
+    fn clamp(module: &mut Clamp, x: f32) -> f32 {
+        let mut out = [0.0];
+        module.process(&[x], &mut out, &[], &mut []);
+        out[0]
+    }
+
+    #[test]
+    fn limits_to_range() {
+        let mut module = Clamp::new(-1.0, 2.0);
+        assert_eq!(clamp(&mut module, -3.0), -1.0);
+        assert_eq!(clamp(&mut module, 0.5), 0.5);
+        assert_eq!(clamp(&mut module, 3.0), 2.0);
+        module.set_param(Clamp::PARAM_MAX, 0.0, 0);
+        assert_eq!(clamp(&mut module, 0.5), 0.0);
+        // crossed bounds don't panic; the upper bound wins
+        module.set_param(Clamp::PARAM_MIN, 1.0, 0);
+        assert_eq!(clamp(&mut module, 0.5), 0.0);
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A feed-forward compressor with optional sidechain input.
+//!
+//! Buffer inputs: audio, and optionally a sidechain signal used for level
+//! detection in place of the audio.
+//!
+//! Control inputs (all optional): threshold (log2 gain, default -3), ratio
+//! (default 4), attack and release times in seconds, makeup gain (log2),
+//! knee width (in log2 units, default 1).
+//!
+//! Control output: the current gain reduction, as log2 gain (0 or negative).
+
+use module::{Module, Buffer};
+
+use super::envelope_follower::{Detector, DetectorMode};
+
+const FLOOR: f32 = -24.0;
+
+pub struct Compressor {
+    detector: Detector,
+    reduction: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: f32) -> Compressor {
+        Compressor {
+            detector: Detector::new(sample_rate, DetectorMode::Peak),
+            reduction: 0.0,
+        }
+    }
+}
+
+// Static gain curve: gain reduction in log2 units for a level in log2 units.
+fn gain_reduction(level: f32, threshold: f32, ratio: f32, knee: f32) -> f32 {
+    let over = level - threshold;
+    let slope = 1.0 - 1.0 / ratio.max(1.0);
+    if 2.0 * over <= -knee {
+        0.0
+    } else if 2.0 * over < knee {
+        // quadratic interpolation within the knee
+        let x = over + 0.5 * knee;
+        -slope * x * x / (2.0 * knee)
+    } else {
+        -slope * over
+    }
+}
+
+impl Module for Compressor {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let ctrl = |ix: usize, default: f32| control_in.get(ix).cloned().unwrap_or(default);
+        let threshold = ctrl(0, -3.0);
+        let ratio = ctrl(1, 4.0);
+        self.detector.set_times(ctrl(2, 0.005), ctrl(3, 0.1));
+        let makeup = ctrl(4, 0.0);
+        let knee = ctrl(5, 1.0);
+        let inb = buf_in[0].get();
+        let side = buf_in.get(1).unwrap_or(&buf_in[0]).get();
+        let out = buf_out[0].get_mut();
+        for i in 0..out.len() {
+            let level = self.detector.process(side[i]).max(FLOOR.exp2()).log2();
+            self.reduction = gain_reduction(level, threshold, ratio, knee);
+            out[i] = inb[i] * (self.reduction + makeup).exp2();
+        }
+        control_out[0] = self.reduction;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    // Compress a constant signal, with instant attack, returning the last
+    // output sample and the gain reduction.
+    fn compress(controls: &[f32], x: f32, side: Option<f32>) -> (f32, f32) {
+        let mut compressor = Compressor::new(48_000.0);
+        let mut inb = Buffer::default();
+        inb.get_mut().iter_mut().for_each(|y| *y = x);
+        let mut side_buf = Buffer::default();
+        side_buf.get_mut().iter_mut().for_each(|y| *y = side.unwrap_or(0.0));
+        let buf_in = match side {
+            Some(_) => vec![&inb, &side_buf],
+            None => vec![&inb],
+        };
+        let mut ctrl = [0.0];
+        let mut out = [Buffer::default()];
+        compressor.process(controls, &mut ctrl, &buf_in, &mut out);
+        (out[0].get()[31], ctrl[0])
+    }
+
+    #[test]
+    fn gain_curve() {
+        // threshold, ratio, attack, release, makeup, knee
+        let controls = [-3.0, 4.0, 0.0, 0.1, 0.0, 1.0];
+        // 3 octaves over the threshold at 4:1 comes out 0.75 over
+        let (y, reduction) = compress(&controls, 1.0, None);
+        assert!((reduction + 2.25).abs() < 1e-4);
+        assert!((y - (-2.25f32).exp2()).abs() < 1e-4);
+        // below the knee, nothing happens
+        assert_eq!(compress(&controls, 0.0625, None), (0.0625, 0.0));
+        // within the knee, a little
+        let (_, reduction) = compress(&controls, (-3f32).exp2(), None);
+        assert!(reduction < 0.0 && reduction > -0.1);
+        let makeup = [-3.0, 4.0, 0.0, 0.1, 1.0, 1.0];
+        assert!((compress(&makeup, 1.0, None).0 - (-1.25f32).exp2()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sidechain() {
+        let controls = [-3.0, 4.0, 0.0, 0.1, 0.0, 1.0];
+        assert_eq!(compress(&controls, 1.0, Some(0.0)), (1.0, 0.0));
+        let (y, reduction) = compress(&controls, 0.1, Some(1.0));
+        assert!((reduction + 2.25).abs() < 1e-4);
+        assert!(y < 0.03);
+    }
+}
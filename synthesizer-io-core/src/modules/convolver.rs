@@ -0,0 +1,165 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Convolution with an impulse response, for convolution reverb and cabinet
+//! simulation.
+//!
+//! This uses uniformly partitioned overlap-save convolution with a partition
+//! size of one chunk, so there is no added latency and the cost per chunk is
+//! fixed (proportional to the length of the impulse response). The impulse
+//! response is loaded and transformed at construction; `process` doesn't
+//! allocate.
+//!
+//! Control inputs (optional): mix (0 = dry, 1 = wet, default wet), log2 of
+//! wet gain.
+
+use std::io;
+use std::path::Path;
+
+use fft::{Complex, Fft};
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+use wav::{self, Wav};
+
+const BLOCK: usize = N_SAMPLES_PER_CHUNK;
+const FFT_SIZE: usize = 2 * BLOCK;
+
+pub struct Convolver {
+    fft: Fft,
+    // spectra of the impulse response partitions
+    partitions: Box<[[Complex; FFT_SIZE]]>,
+    // frequency-domain delay line of input spectra, a ring buffer
+    fdl: Box<[[Complex; FFT_SIZE]]>,
+    fdl_pos: usize,
+    // previous input block, for overlap-save
+    last_in: [f32; BLOCK],
+    scratch: [Complex; FFT_SIZE],
+}
+
+impl Convolver {
+    /// Create a convolver from an impulse response at the engine sample rate.
+    pub fn new(ir: &[f32]) -> Convolver {
+        let fft = Fft::new(FFT_SIZE);
+        let n_partitions = ((ir.len() + BLOCK - 1) / BLOCK).max(1);
+        let mut partitions = vec![[Complex::default(); FFT_SIZE]; n_partitions];
+        for (part, spectrum) in ir.chunks(BLOCK).zip(partitions.iter_mut()) {
+            for (x, y) in part.iter().zip(spectrum.iter_mut()) {
+                y.re = *x;
+            }
+            fft.forward(spectrum);
+        }
+        Convolver {
+            fft,
+            partitions: partitions.into_boxed_slice(),
+            fdl: vec![[Complex::default(); FFT_SIZE]; n_partitions].into_boxed_slice(),
+            fdl_pos: 0,
+            last_in: [0.0; BLOCK],
+            scratch: [Complex::default(); FFT_SIZE],
+        }
+    }
+
+    /// Load an impulse response from a WAV file, mixing it down to mono and
+    /// resampling to the engine sample rate if needed.
+    pub fn load<P: AsRef<Path>>(path: P, sample_rate: f32) -> io::Result<Convolver> {
+        let wav = Wav::load(path)?;
+        let ir = wav::resample(&wav.to_mono(), wav.sample_rate as f32, sample_rate);
+        Ok(Convolver::new(&ir))
+    }
+}
+
+impl Module for Convolver {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let mix = control_in.first().cloned().unwrap_or(1.0);
+        let wet_gain = control_in.get(1).cloned().unwrap_or(0.0).exp2();
+        let inb = buf_in[0].get();
+
+        // transform the last two input blocks into the delay line
+        let n = self.fdl.len();
+        self.fdl_pos = (self.fdl_pos + n - 1) % n;
+        {
+            let x = &mut self.fdl[self.fdl_pos];
+            for i in 0..BLOCK {
+                x[i] = Complex::new(self.last_in[i], 0.0);
+                x[i + BLOCK] = Complex::new(inb[i], 0.0);
+            }
+            self.fft.forward(x);
+        }
+        self.last_in.copy_from_slice(inb);
+
+        // multiply-accumulate against the partitions
+        let acc = &mut self.scratch;
+        *acc = [Complex::default(); FFT_SIZE];
+        for (p, h) in self.partitions.iter().enumerate() {
+            let x = &self.fdl[(self.fdl_pos + p) % n];
+            for k in 0..FFT_SIZE {
+                acc[k] = acc[k] + x[k] * h[k];
+            }
+        }
+        self.fft.inverse(acc);
+
+        // the second half is the valid (non-aliased) part
+        let out = buf_out[0].get_mut();
+        for i in 0..BLOCK {
+            let wet = acc[i + BLOCK].re * wet_gain;
+            out[i] = inb[i] + mix * (wet - inb[i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    // Convolve a ramp, a chunk at a time.
+    fn convolve(ir: &[f32], n_chunks: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut conv = Convolver::new(ir);
+        let mut input = Vec::new();
+        let mut output = Vec::new();
+        for c in 0..n_chunks {
+            let mut inb = Buffer::default();
+            for (i, x) in inb.get_mut().iter_mut().enumerate() {
+                *x = ((c * BLOCK + i) as f32 * 0.1).sin();
+            }
+            let mut out = [Buffer::default()];
+            conv.process(&[], &mut [], &[&inb], &mut out);
+            input.extend_from_slice(inb.get());
+            output.extend_from_slice(out[0].get());
+        }
+        (input, output)
+    }
+
+    #[test]
+    fn unit_impulse_has_no_latency() {
+        let (input, output) = convolve(&[1.0], 4);
+        for (x, y) in input.iter().zip(output.iter()) {
+            assert!((x - y).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn delayed_impulse_spans_partitions() {
+        let delay = BLOCK + 7;
+        let mut ir = vec![0.0; delay + 1];
+        ir[delay] = 0.5;
+        let (input, output) = convolve(&ir, 6);
+        assert!(output[..delay].iter().all(|y| y.abs() < 1e-5));
+        for (x, y) in input.iter().zip(output[delay..].iter()) {
+            assert!((0.5 * x - y).abs() < 1e-5);
+        }
+    }
+}
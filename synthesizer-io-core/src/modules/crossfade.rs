@@ -0,0 +1,86 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A linear crossfade between two audio inputs.
+//!
+//! Control input: position, from 0 (all of the first input) to 1 (all of the
+//! second). The position is ramped linearly over each chunk.
+
+use module::{Module, Buffer, Ports};
+
+#[derive(Default)]
+pub struct Crossfade {
+    last_x: f32,
+}
+
+impl Crossfade {
+    pub const PORTS: Ports = Ports {
+        buf_in: &["a", "b"],
+        ctrl_in: &["position"],
+        buf_out: &["out"],
+        ctrl_out: &[],
+    };
+
+    pub fn new() -> Crossfade {
+        Crossfade {
+            last_x: 0.0,
+        }
+    }
+}
+
+impl Module for Crossfade {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let x = control_in[0].clamp(0.0, 1.0);
+        let out = buf_out[0].get_mut();
+        let dx = (x - self.last_x) * (1.0 / out.len() as f32);
+        let mut pos = self.last_x + dx;
+        self.last_x = x;
+        let a = buf_in[0].get();
+        let b = buf_in[1].get();
+        for i in 0..out.len() {
+            out[i] = a[i] + (b[i] - a[i]) * pos;
+            pos += dx;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    #[test]
+    fn ramps_between_inputs() {
+        let mut a = Buffer::default();
+        let b = Buffer::default();
+        a.get_mut().iter_mut().for_each(|x| *x = 1.0);
+        let mut crossfade = Crossfade::new();
+        let mut out = [Buffer::default()];
+        crossfade.process(&[0.0], &mut [], &[&a, &b], &mut out);
+        assert!(out[0].get().iter().all(|&y| y == 1.0));
+        // moving to the second input ramps over the chunk
+        crossfade.process(&[1.0], &mut [], &[&a, &b], &mut out);
+        let out0 = out[0].get();
+        assert!(out0.windows(2).all(|w| w[1] < w[0]));
+        assert!((out0[15] - 0.5).abs() < 1e-6 && out0[31].abs() < 1e-6);
+        // the position is clamped to 0..1
+        crossfade.process(&[-1.0], &mut [], &[&a, &b], &mut out);
+        crossfade.process(&[-1.0], &mut [], &[&a, &b], &mut out);
+        assert!(out[0].get().iter().all(|&y| y == 1.0));
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A DC blocking filter: a one-pole highpass with a cutoff around 10Hz.
+
+use std::f32::consts;
+
+use module::{Module, Buffer, Ports};
+
+const CUTOFF: f32 = 10.0;  // in Hz
+
+pub struct DcBlocker {
+    r: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl DcBlocker {
+    pub const PORTS: Ports = Ports {
+        buf_in: &["in"],
+        ctrl_in: &[],
+        buf_out: &["out"],
+        ctrl_out: &[],
+    };
+
+    pub fn new(sample_rate: f32) -> DcBlocker {
+        DcBlocker {
+            r: (-2.0 * consts::PI * CUTOFF / sample_rate).exp(),
+            x1: 0.0,
+            y1: 0.0,
+        }
+    }
+}
+
+impl Module for DcBlocker {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_blocker) = old.to_any().downcast_ref::<DcBlocker>() {
+            self.x1 = old_blocker.x1;
+            self.y1 = old_blocker.y1;
+        }
+    }
+
+    fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let out = buf_out[0].get_mut();
+        for (y, &x) in out.iter_mut().zip(buf_in[0].get().iter()) {
+            self.y1 = x - self.x1 + self.r * self.y1;
+            self.x1 = x;
+            *y = self.y1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    // The peak output over the last of `n_chunks` chunks of a sine at `freq`
+    // Hz, offset by 1.
+    fn response(freq: f32, n_chunks: usize) -> f32 {
+        let mut blocker = DcBlocker::new(SAMPLE_RATE);
+        let mut peak = 0.0f32;
+        for chunk in 0..n_chunks {
+            let mut inb = Buffer::default();
+            for (i, x) in inb.get_mut().iter_mut().enumerate() {
+                let t = (chunk * 32 + i) as f32 / SAMPLE_RATE;
+                *x = 1.0 + (t * freq * 2.0 * consts::PI).sin();
+            }
+            let mut out = [Buffer::default()];
+            blocker.process(&[], &mut [], &[&inb], &mut out);
+            peak = out[0].get().iter().fold(0.0, |a, &y| a.max(y.abs()));
+        }
+        peak
+    }
+
+    #[test]
+    fn removes_offset() {
+        // after a second, the offset has decayed well below -60dB
+        assert!(response(0.0, 1500) < 1e-3);
+        // audio passes, with the offset gone
+        let peak = response(1000.0, 1500);
+        assert!((peak - 1.0).abs() < 0.01, "{}", peak);
+    }
+}
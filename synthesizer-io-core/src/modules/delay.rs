@@ -0,0 +1,192 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A delay line with feedback and modulated delay time.
+//!
+//! Control inputs: delay time, feedback (optional, 0 to 1), mix (optional,
+//! 0 = dry, 1 = wet). The delay time is in seconds, or when synced, the tempo
+//! in beats per minute. It is interpolated per sample, so it can be modulated
+//! for chorus and flanger effects.
+//!
+//! Since the feedback path is internal to the module, this provides
+//! recirculation without needing a cycle in the graph.
+
+use module::{Module, Buffer};
+
+/// A fractional delay line with a fixed maximum length, allocated at
+/// construction.
+pub struct DelayLine {
+    buf: Box<[f32]>,
+    mask: usize,
+    pos: usize,
+}
+
+impl DelayLine {
+    /// Create a delay line able to delay by at least `max_delay` samples.
+    pub fn new(max_delay: usize) -> DelayLine {
+        // room for the interpolation taps on either side
+        let size = (max_delay + 4).next_power_of_two();
+        DelayLine {
+            buf: vec![0.0; size].into_boxed_slice(),
+            mask: size - 1,
+            pos: 0,
+        }
+    }
+
+    /// The longest delay, in samples, that can be read.
+    pub fn max_delay(&self) -> f32 {
+        (self.buf.len() - 3) as f32
+    }
+
+    pub fn write(&mut self, x: f32) {
+        self.pos = (self.pos + 1) & self.mask;
+        self.buf[self.pos] = x;
+    }
+
+    /// Read the signal delayed by `delay` samples, relative to the next sample
+    /// to be written, using 4-point Hermite interpolation for fractional delays.
+    /// The delay is clamped to the range where all taps are valid (at least 2).
+    pub fn read(&self, delay: f32) -> f32 {
+        let delay = delay.max(2.0).min(self.max_delay());
+        let int = delay as usize;
+        let frac = delay - int as f32;
+        let ix = self.pos.wrapping_sub(int - 1) & self.mask;
+        let xm1 = self.buf[(ix + 1) & self.mask];
+        let x0 = self.buf[ix];
+        let x1 = self.buf[ix.wrapping_sub(1) & self.mask];
+        let x2 = self.buf[ix.wrapping_sub(2) & self.mask];
+        let c1 = 0.5 * (x1 - xm1);
+        let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+        let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+        ((c3 * frac + c2) * frac + c1) * frac + x0
+    }
+}
+
+pub struct Delay {
+    sample_rate: f32,
+    line: DelayLine,
+    // Number of beats of delay when synced to tempo.
+    sync_beats: Option<f32>,
+    last_delay: Option<f32>,  // in samples
+}
+
+impl Delay {
+    /// Create a delay with a maximum delay time of `max_time` seconds.
+    pub fn new(sample_rate: f32, max_time: f32) -> Delay {
+        Delay {
+            sample_rate,
+            line: DelayLine::new((max_time * sample_rate).ceil() as usize),
+            sync_beats: None,
+            last_delay: None,
+        }
+    }
+
+    /// Create a delay synced to tempo; the time input is in beats per minute,
+    /// and the delay lasts `beats` beats.
+    pub fn synced(sample_rate: f32, max_time: f32, beats: f32) -> Delay {
+        let mut delay = Delay::new(sample_rate, max_time);
+        delay.sync_beats = Some(beats);
+        delay
+    }
+}
+
+impl Module for Delay {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_mut::<Delay>() {
+            if old.line.buf.len() == self.line.buf.len() {
+                ::std::mem::swap(&mut self.line, &mut old.line);
+            }
+            self.last_delay = old.last_delay;
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let time = match self.sync_beats {
+            Some(beats) => beats * 60.0 / control_in[0].max(1.0),
+            None => control_in[0],
+        };
+        let delay = time * self.sample_rate;
+        let feedback = control_in.get(1).cloned().unwrap_or(0.0);
+        let mix = control_in.get(2).cloned().unwrap_or(0.5);
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        let last_delay = self.last_delay.unwrap_or(delay);
+        let dd = (delay - last_delay) * (1.0 / out.len() as f32);
+        let mut d = last_delay + dd;
+        self.last_delay = Some(delay);
+        for (x, y) in inb.iter().zip(out.iter_mut()) {
+            let wet = self.line.read(d);
+            self.line.write(x + feedback * wet);
+            *y = x + mix * (wet - x);
+            d += dd;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    // The output for an impulse, with wet output only.
+    fn impulse_response(delay: &mut Delay, time: f32, feedback: f32) -> Vec<f32> {
+        let mut result = Vec::new();
+        for chunk in 0..2 {
+            let mut inb = Buffer::default();
+            if chunk == 0 {
+                inb.get_mut()[0] = 1.0;
+            }
+            let mut out = [Buffer::default()];
+            delay.process(&[time, feedback, 1.0], &mut [], &[&inb], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    #[test]
+    fn fractional_delay() {
+        let mut delay = Delay::new(SAMPLE_RATE, 0.1);
+        let out = impulse_response(&mut delay, 0.01, 0.0);
+        assert_eq!(out[10], 1.0);
+        assert!(out.iter().enumerate().all(|(i, &x)| i == 10 || x == 0.0));
+
+        // halfway between samples, the impulse is split between them
+        let mut delay = Delay::new(SAMPLE_RATE, 0.1);
+        let out = impulse_response(&mut delay, 0.0105, 0.0);
+        assert!((out[10] - 0.5625).abs() < 1e-6 && (out[11] - 0.5625).abs() < 1e-6);
+        let sum: f32 = out.iter().sum();
+        let centroid: f32 = out.iter().enumerate().map(|(i, &x)| i as f32 * x).sum::<f32>() / sum;
+        assert!((sum - 1.0).abs() < 1e-6 && (centroid - 10.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn feedback_decays() {
+        let mut delay = Delay::new(SAMPLE_RATE, 0.1);
+        let out = impulse_response(&mut delay, 0.01, 0.5);
+        assert_eq!((out[10], out[20], out[30], out[40]), (1.0, 0.5, 0.25, 0.125));
+    }
+
+    #[test]
+    fn synced_time() {
+        // half a beat at 6000 bpm is 5ms
+        let mut delay = Delay::synced(SAMPLE_RATE, 0.1, 0.5);
+        assert_eq!(impulse_response(&mut delay, 6000.0, 0.0)[5], 1.0);
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An envelope follower, tracking the level of an audio signal.
+//!
+//! Control inputs (optional): attack time, release time, both in seconds.
+//!
+//! Control output: the level, as log2 gain (so it can drive `Gain` or a
+//! filter cutoff directly).
+
+use module::{Module, Buffer};
+
+/// How the level of the signal is measured.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DetectorMode {
+    Peak,
+    Rms,
+}
+
+const FLOOR: f32 = -24.0;
+
+/// Level detection with separate attack and release smoothing. Shared by the
+/// dynamics modules.
+pub struct Detector {
+    sample_rate: f32,
+    mode: DetectorMode,
+    state: f32,  // linear level, or squared level in RMS mode
+    attack_coef: f32,
+    release_coef: f32,
+    attack_time: f32,
+    release_time: f32,
+}
+
+impl Detector {
+    pub fn new(sample_rate: f32, mode: DetectorMode) -> Detector {
+        let mut detector = Detector {
+            sample_rate,
+            mode,
+            state: 0.0,
+            attack_coef: 0.0,
+            release_coef: 0.0,
+            attack_time: -1.0,
+            release_time: -1.0,
+        };
+        detector.set_times(0.005, 0.1);
+        detector
+    }
+
+    // one-pole coefficient for a time constant in seconds
+    fn coef(&self, time: f32) -> f32 {
+        if time <= 0.0 {
+            0.0
+        } else {
+            (-1.0 / (time * self.sample_rate)).exp()
+        }
+    }
+
+    /// Set attack and release times in seconds. Coefficients are only
+    /// recomputed when the times change.
+    pub fn set_times(&mut self, attack: f32, release: f32) {
+        if attack != self.attack_time {
+            self.attack_coef = self.coef(attack);
+            self.attack_time = attack;
+        }
+        if release != self.release_time {
+            self.release_coef = self.coef(release);
+            self.release_time = release;
+        }
+    }
+
+    /// Feed one sample, returning the current linear level.
+    pub fn process(&mut self, x: f32) -> f32 {
+        let inp = match self.mode {
+            DetectorMode::Peak => x.abs(),
+            DetectorMode::Rms => x * x,
+        };
+        let coef = if inp > self.state { self.attack_coef } else { self.release_coef };
+        self.state = inp + (self.state - inp) * coef;
+        self.level()
+    }
+
+    /// The current linear level.
+    pub fn level(&self) -> f32 {
+        match self.mode {
+            DetectorMode::Peak => self.state,
+            DetectorMode::Rms => self.state.sqrt(),
+        }
+    }
+}
+
+pub struct EnvelopeFollower {
+    detector: Detector,
+}
+
+impl EnvelopeFollower {
+    pub fn new(sample_rate: f32, mode: DetectorMode) -> EnvelopeFollower {
+        EnvelopeFollower { detector: Detector::new(sample_rate, mode) }
+    }
+}
+
+impl Module for EnvelopeFollower {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        let attack = control_in.first().cloned().unwrap_or(0.005);
+        let release = control_in.get(1).cloned().unwrap_or(0.1);
+        self.detector.set_times(attack, release);
+        for &x in buf_in[0].get().iter() {
+            self.detector.process(x);
+        }
+        control_out[0] = self.detector.level().max(FLOOR.exp2()).log2();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    // Follow a sine of the given amplitude for `n_chunks`, with 1ms attack
+    // and 100ms release, returning the last level.
+    fn follow(follower: &mut EnvelopeFollower, amp: f32, n_chunks: usize) -> f32 {
+        follow_with(follower, amp, n_chunks, [0.001, 0.1])
+    }
+
+    fn follow_with(follower: &mut EnvelopeFollower, amp: f32, n_chunks: usize,
+        times: [f32; 2]) -> f32
+    {
+        let mut ctrl = [0.0];
+        for _ in 0..n_chunks {
+            let mut inb = Buffer::default();
+            // 1.5kHz, a whole number of cycles per chunk
+            for (i, x) in inb.get_mut().iter_mut().enumerate() {
+                *x = amp * (i as f32 * ::std::f32::consts::PI / 16.0).sin();
+            }
+            follower.process(&times, &mut ctrl, &[&inb], &mut []);
+        }
+        ctrl[0]
+    }
+
+    #[test]
+    fn peak_and_rms() {
+        let mut peak = EnvelopeFollower::new(SAMPLE_RATE, DetectorMode::Peak);
+        let level = follow(&mut peak, 0.5, 100);
+        assert!(level < -1.0 && level > -1.1, "{}", level);
+        let mut rms = EnvelopeFollower::new(SAMPLE_RATE, DetectorMode::Rms);
+        // with equal attack and release, this is the true RMS level
+        let level = follow_with(&mut rms, 0.5, 100, [0.01, 0.01]);
+        assert!((level + 1.5).abs() < 0.05, "{}", level);
+    }
+
+    #[test]
+    fn attack_and_release() {
+        let mut follower = EnvelopeFollower::new(SAMPLE_RATE, DetectorMode::Peak);
+        assert_eq!(follow(&mut follower, 0.0, 1), FLOOR);
+        // a 1ms attack rises most of the way within 7ms
+        assert!(follow(&mut follower, 0.5, 10) > -1.25);
+        // a 100ms release takes 100ms to fall by an e-fold or so
+        let released = follow(&mut follower, 0.0, 150);
+        assert!(released < -2.0 && released > -4.0, "{}", released);
+        assert_eq!(follow(&mut follower, 0.0, 3000), FLOOR);
+    }
+}
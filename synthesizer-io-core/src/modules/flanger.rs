@@ -0,0 +1,110 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A flanger, using a short delay line modulated by a sine LFO.
+//!
+//! Control inputs (all optional): rate (log2 of Hz, default 0.25Hz), depth
+//! (0 to 1), feedback (-1 to 1; negative values invert the recirculated
+//! signal), mix (0 = dry, 1 = wet).
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+
+use super::delay::DelayLine;
+
+const MIN_DELAY: f32 = 0.0001;  // in seconds
+const MAX_DELAY: f32 = 0.005;  // in seconds, at full depth
+
+pub struct Flanger {
+    sample_rate: f32,
+    line: DelayLine,
+    phase: f32,  // LFO phase in cycles
+}
+
+impl Flanger {
+    pub fn new(sample_rate: f32) -> Flanger {
+        Flanger {
+            sample_rate,
+            line: DelayLine::new((MAX_DELAY * sample_rate) as usize + 1),
+            phase: 0.0,
+        }
+    }
+}
+
+impl Module for Flanger {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let ctrl = |ix: usize, default: f32| control_in.get(ix).cloned().unwrap_or(default);
+        let freq = ctrl(0, -2.0).exp2() / self.sample_rate;
+        let depth = ctrl(1, 0.5).clamp(0.0, 1.0);
+        let feedback = ctrl(2, 0.5).clamp(-0.95, 0.95);
+        let mix = ctrl(3, 0.5);
+        let min_delay = MIN_DELAY * self.sample_rate;
+        let sweep = depth * (MAX_DELAY - MIN_DELAY) * self.sample_rate;
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        for (&x, y) in inb.iter().zip(out.iter_mut()) {
+            let lfo = 0.5 - 0.5 * (self.phase * 2.0 * consts::PI).cos();
+            let wet = self.line.read(min_delay + sweep * lfo);
+            self.line.write(x + feedback * wet);
+            *y = x + mix * (wet - x);
+            self.phase += freq;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    // The wet output for an impulse, at zero depth, where the delay is
+    // 10 samples at this rate.
+    fn impulse_response(feedback: f32) -> Vec<f32> {
+        let mut flanger = Flanger::new(100_000.0);
+        let mut inb = Buffer::default();
+        inb.get_mut()[0] = 1.0;
+        let mut out = [Buffer::default()];
+        flanger.process(&[0.0, 0.0, feedback, 1.0], &mut [], &[&inb], &mut out);
+        out[0].get().to_vec()
+    }
+
+    #[test]
+    fn feedback_recirculates() {
+        let out = impulse_response(0.5);
+        for &(i, expected) in &[(10, 1.0), (20, 0.5), (30, 0.25)] {
+            assert!((out[i] - expected).abs() < 1e-3, "{:?}", out);
+        }
+        // negative feedback inverts each pass
+        let out = impulse_response(-0.5);
+        assert!((out[20] + 0.5).abs() < 1e-3 && (out[30] - 0.25).abs() < 1e-3);
+    }
+
+    #[test]
+    fn dry_passes_through() {
+        let mut flanger = Flanger::new(48_000.0);
+        let mut inb = Buffer::default();
+        inb.get_mut()[3] = 1.0;
+        let mut out = [Buffer::default()];
+        flanger.process(&[0.0, 1.0, 0.9, 0.0], &mut [], &[&inb], &mut out);
+        assert_eq!(out[0].get(), inb.get());
+    }
+}
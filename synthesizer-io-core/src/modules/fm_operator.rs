@@ -0,0 +1,229 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An operator for phase modulation (FM) synthesis: a sine oscillator with
+//! its own envelope, in the style of the DX series.
+//!
+//! Control inputs: pitch (log2 of Hz, usually from `NotePitch`), output level
+//! (optional, log2 of gain).
+//!
+//! Buffer inputs: any number of modulators, which are summed. The sum is a
+//! phase offset in radians, so a modulator with a peak output of 1 gives a
+//! modulation index of 1.
+//!
+//! Output: one buffer, the sine scaled by envelope, velocity and level.
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+
+use super::sin::sin_lookup;
+
+/// How the operator's frequency is derived.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FmFreq {
+    /// A multiple of the pitch input.
+    Ratio(f32),
+    /// A fixed frequency in Hz, ignoring the pitch input.
+    Fixed(f32),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Release,
+}
+
+pub struct FmOperator {
+    sample_period: f32,
+    freq: FmFreq,
+    phase: f32,  // in cycles
+    feedback: f32,
+    fb_hist: [f32; 2],
+    attack: f32,  // all times in seconds
+    decay: f32,
+    sustain: f32,  // linear, 0 to 1
+    release: f32,
+    vel_sens: f32,
+    vel_gain: f32,
+    stage: Stage,
+    env: f32,
+}
+
+// Coefficient for a one-pole approach that covers 60dB in `time` seconds.
+fn approach_coef(time: f32, sample_period: f32) -> f32 {
+    (-6.9078 * sample_period / time.max(1e-4)).exp()
+}
+
+impl FmOperator {
+    /// Parameter index for the frequency ratio; selects ratio mode.
+    pub const PARAM_RATIO: usize = 0;
+
+    /// Parameter index for the fixed frequency in Hz; selects fixed mode.
+    pub const PARAM_FIXED: usize = 1;
+
+    /// Parameter index for the self-feedback amount (0 to 1).
+    pub const PARAM_FEEDBACK: usize = 2;
+
+    /// Parameter index for the attack time in seconds.
+    pub const PARAM_ATTACK: usize = 3;
+
+    /// Parameter index for the decay time in seconds.
+    pub const PARAM_DECAY: usize = 4;
+
+    /// Parameter index for the sustain level (linear, 0 to 1).
+    pub const PARAM_SUSTAIN: usize = 5;
+
+    /// Parameter index for the release time in seconds.
+    pub const PARAM_RELEASE: usize = 6;
+
+    /// Parameter index for velocity sensitivity (0 to 1).
+    pub const PARAM_VEL_SENS: usize = 7;
+
+    pub fn new(sample_rate: f32, freq: FmFreq) -> FmOperator {
+        // make table initialization happen here so it doesn't happen in process
+        let _ = sin_lookup(0.0);
+        FmOperator {
+            sample_period: 1.0 / sample_rate,
+            freq,
+            phase: 0.0,
+            feedback: 0.0,
+            fb_hist: [0.0; 2],
+            attack: 0.002,
+            decay: 1.0,
+            sustain: 1.0,
+            release: 0.2,
+            vel_sens: 0.5,
+            vel_gain: 1.0,
+            stage: Stage::Idle,
+            env: 0.0,
+        }
+    }
+
+    pub fn set_freq(&mut self, freq: FmFreq) {
+        self.freq = freq;
+    }
+
+    /// Set the self-feedback amount, from 0 to 1. At 1, the previous output
+    /// modulates the phase by up to π radians.
+    pub fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = feedback;
+    }
+
+    /// Set the envelope. Times are in seconds, sustain is linear (0 to 1).
+    pub fn set_envelope(&mut self, attack: f32, decay: f32, sustain: f32, release: f32) {
+        self.attack = attack;
+        self.decay = decay;
+        self.sustain = sustain;
+        self.release = release;
+    }
+
+    /// Set how much velocity affects the output level, from 0 (not at all)
+    /// to 1 (level proportional to velocity).
+    pub fn set_velocity_sens(&mut self, vel_sens: f32) {
+        self.vel_sens = vel_sens;
+    }
+
+    fn next_env(&mut self, attack_inc: f32, decay_c: f32, release_c: f32) -> f32 {
+        match self.stage {
+            Stage::Idle => (),
+            Stage::Attack => {
+                self.env += attack_inc;
+                if self.env >= 1.0 {
+                    self.env = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => self.env = self.sustain + (self.env - self.sustain) * decay_c,
+            Stage::Release => {
+                self.env *= release_c;
+                if self.env < 1e-4 {
+                    self.env = 0.0;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+        self.env
+    }
+}
+
+impl Module for FmOperator {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_op) = old.to_any().downcast_ref::<FmOperator>() {
+            self.phase = old_op.phase;
+            self.fb_hist = old_op.fb_hist;
+            self.vel_gain = old_op.vel_gain;
+            self.stage = old_op.stage;
+            self.env = old_op.env;
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let hz = match self.freq {
+            FmFreq::Ratio(ratio) => ratio * control_in[0].exp2(),
+            FmFreq::Fixed(hz) => hz,
+        };
+        let freq = hz * self.sample_period;
+        let level = control_in.get(1).cloned().unwrap_or(0.0).exp2() * self.vel_gain;
+        let attack_inc = self.sample_period / self.attack.max(1e-4);
+        let decay_c = approach_coef(self.decay, self.sample_period);
+        let release_c = approach_coef(self.release, self.sample_period);
+        // π radians at full feedback, in cycles, applied to a sum of two
+        let fb_scale = self.feedback * 0.25;
+        let pm_scale = 0.5 * consts::FRAC_1_PI;
+        let out = buf_out[0].get_mut();
+        for (i, out) in out.iter_mut().enumerate() {
+            let pm = buf_in.iter().fold(0.0, |acc, buf| acc + buf.get()[i]);
+            // feedback uses the average of the last two outputs, to tame it
+            let fb = fb_scale * (self.fb_hist[0] + self.fb_hist[1]);
+            let y = sin_lookup(self.phase + pm * pm_scale + fb);
+            self.fb_hist[1] = self.fb_hist[0];
+            self.fb_hist[0] = y;
+            *out = y * level * self.next_env(attack_inc, decay_c, release_c);
+            self.phase += freq;
+            self.phase -= self.phase.floor();
+        }
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            FmOperator::PARAM_RATIO => self.freq = FmFreq::Ratio(val),
+            FmOperator::PARAM_FIXED => self.freq = FmFreq::Fixed(val),
+            FmOperator::PARAM_FEEDBACK => self.feedback = val,
+            FmOperator::PARAM_ATTACK => self.attack = val,
+            FmOperator::PARAM_DECAY => self.decay = val,
+            FmOperator::PARAM_SUSTAIN => self.sustain = val,
+            FmOperator::PARAM_RELEASE => self.release = val,
+            FmOperator::PARAM_VEL_SENS => self.vel_sens = val,
+            _ => (),
+        }
+    }
+
+    fn receives_notes(&self) -> bool { true }
+
+    fn handle_note(&mut self, _midi_num: f32, velocity: f32, on: bool) {
+        if on {
+            self.vel_gain = 1.0 - self.vel_sens + self.vel_sens * velocity * (1.0 / 127.0);
+            self.stage = Stage::Attack;
+        } else if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+        }
+    }
+}
@@ -32,6 +32,12 @@ impl Gain {
 impl Module for Gain {
     fn n_bufs_out(&self) -> usize { 1 }
 
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_gain) = old.to_any().downcast_ref::<Gain>() {
+            self.last_g = old_gain.last_g;
+        }
+    }
+
     fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
         buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
@@ -0,0 +1,252 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A low frequency oscillator for modulation.
+//!
+//! Control inputs: rate, phase offset (optional, in cycles). In free-running
+//! mode the rate is log2 of the frequency in Hz; when synced, it is the tempo
+//! in beats per minute.
+//!
+//! Outputs: one control value (the value at the start of the chunk) and one
+//! audio-rate buffer. Both range from -1 to 1.
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// A new random value is chosen at the start of each cycle.
+    Random,
+}
+
+impl LfoShape {
+    /// Decode a shape from a parameter value, as sent by `set_param`.
+    pub fn from_param(val: f32) -> LfoShape {
+        match val as i32 {
+            1 => LfoShape::Triangle,
+            2 => LfoShape::Saw,
+            3 => LfoShape::Square,
+            4 => LfoShape::Random,
+            _ => LfoShape::Sine,
+        }
+    }
+}
+
+pub struct Lfo {
+    sample_period: f32,
+    shape: LfoShape,
+    // Number of beats per cycle when synced to tempo.
+    sync_beats: Option<f32>,
+    retrigger: bool,
+    phase: f32,  // in cycles, 0 <= phase < 1
+    rand_state: u32,
+    rand_value: f32,
+}
+
+impl Lfo {
+    /// Parameter index for selecting the shape (see `LfoShape::from_param`).
+    pub const PARAM_SHAPE: usize = 0;
+
+    /// Parameter index for enabling (nonzero) or disabling retrigger on note-on.
+    pub const PARAM_RETRIGGER: usize = 1;
+
+    /// Create a free-running LFO; the rate input is log2 of Hz.
+    pub fn new(sample_rate: f32, shape: LfoShape) -> Lfo {
+        Lfo {
+            sample_period: 1.0 / sample_rate,
+            shape,
+            sync_beats: None,
+            retrigger: false,
+            phase: 0.0,
+            rand_state: 0x9e37_79b9,
+            rand_value: 0.0,
+        }
+    }
+
+    /// Create an LFO synced to tempo; the rate input is in beats per minute,
+    /// and one cycle lasts `beats` beats.
+    pub fn synced(sample_rate: f32, shape: LfoShape, beats: f32) -> Lfo {
+        let mut lfo = Lfo::new(sample_rate, shape);
+        lfo.sync_beats = Some(beats);
+        lfo
+    }
+
+    /// Reset the phase on every note-on.
+    pub fn set_retrigger(&mut self, retrigger: bool) {
+        self.retrigger = retrigger;
+    }
+
+    // Frequency in cycles per sample.
+    fn freq(&self, rate: f32) -> f32 {
+        let hz = match self.sync_beats {
+            Some(beats) => rate * (1.0 / 60.0) / beats,
+            None => rate.exp2(),
+        };
+        hz * self.sample_period
+    }
+
+    // xorshift32, mapped to -1..1
+    fn next_random(&mut self) -> f32 {
+        let mut x = self.rand_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rand_state = x;
+        (x as f32) * (2.0 / 4_294_967_296.0) - 1.0
+    }
+
+    fn value_at(&self, phase: f32) -> f32 {
+        match self.shape {
+            LfoShape::Sine => (phase * 2.0 * consts::PI).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            LfoShape::Random => self.rand_value,
+        }
+    }
+}
+
+impl Module for Lfo {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_lfo) = old.to_any().downcast_ref::<Lfo>() {
+            self.phase = old_lfo.phase;
+            self.rand_state = old_lfo.rand_state;
+            self.rand_value = old_lfo.rand_value;
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let freq = self.freq(control_in[0]);
+        let offset = control_in.get(1).cloned().unwrap_or(0.0);
+        let out = buf_out[0].get_mut();
+        let mut phase = self.phase;
+        for y in out.iter_mut() {
+            let p = phase + offset;
+            *y = self.value_at(p - p.floor());
+            phase += freq;
+            if phase >= 1.0 {
+                phase -= phase.floor();
+                if self.shape == LfoShape::Random {
+                    self.rand_value = self.next_random();
+                }
+            }
+        }
+        self.phase = phase;
+        control_out[0] = out[0];
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            Lfo::PARAM_SHAPE => self.shape = LfoShape::from_param(val),
+            Lfo::PARAM_RETRIGGER => self.retrigger = val != 0.0,
+            _ => (),
+        }
+    }
+
+    fn receives_notes(&self) -> bool { true }
+
+    fn handle_note(&mut self, _midi_num: f32, _velocity: f32, on: bool) {
+        if on && self.retrigger {
+            self.phase = 0.0;
+            if self.shape == LfoShape::Random {
+                self.rand_value = self.next_random();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    // At this rate, a 100Hz LFO's cycle is one 32-sample chunk.
+    const SAMPLE_RATE: f32 = 3200.0;
+
+    fn run(lfo: &mut Lfo, rate: f32) -> Vec<f32> {
+        let mut ctrl = [0.0];
+        let mut out = [Buffer::default()];
+        lfo.process(&[rate], &mut ctrl, &[], &mut out);
+        assert_eq!(ctrl[0], out[0].get()[0]);
+        out[0].get().to_vec()
+    }
+
+    // Check the values 1/8, 3/8, 5/8 and 7/8 of the way through a cycle.
+    fn assert_eighths(out: &[f32], expected: [f32; 4]) {
+        for (i, &expected) in expected.iter().enumerate() {
+            assert!((out[i * 8 + 4] - expected).abs() < 1e-3, "{:?} != {:?}", out, expected);
+        }
+    }
+
+    #[test]
+    fn shapes() {
+        let cases = [
+            (LfoShape::Sine, [0.7071, 0.7071, -0.7071, -0.7071]),
+            (LfoShape::Triangle, [-0.5, 0.5, 0.5, -0.5]),
+            (LfoShape::Saw, [-0.75, -0.25, 0.25, 0.75]),
+            (LfoShape::Square, [1.0, 1.0, -1.0, -1.0]),
+        ];
+        for &(shape, expected) in &cases {
+            let mut lfo = Lfo::new(SAMPLE_RATE, shape);
+            assert_eighths(&run(&mut lfo, 100f32.log2()), expected);
+        }
+    }
+
+    #[test]
+    fn random_holds_for_a_cycle() {
+        let mut lfo = Lfo::new(SAMPLE_RATE, LfoShape::Random);
+        let first = run(&mut lfo, 100f32.log2());
+        let second = run(&mut lfo, 100f32.log2());
+        assert!(second[1..].iter().all(|&x| x == second[1]));
+        assert!(second[1] != first[1]);
+        assert!(second[1] >= -1.0 && second[1] <= 1.0);
+    }
+
+    #[test]
+    fn synced_period() {
+        // 3000 bpm is 50 beats a second, so half-beat cycles are 100Hz
+        let mut lfo = Lfo::synced(SAMPLE_RATE, LfoShape::Saw, 0.5);
+        for _ in 0..3 {
+            assert_eighths(&run(&mut lfo, 3000.0), [-0.75, -0.25, 0.25, 0.75]);
+        }
+        // and one-beat cycles are 50Hz
+        let mut lfo = Lfo::synced(SAMPLE_RATE, LfoShape::Saw, 1.0);
+        assert!((run(&mut lfo, 3000.0)[16] + 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn retrigger() {
+        let mut lfo = Lfo::new(SAMPLE_RATE, LfoShape::Saw);
+        // half a cycle per chunk
+        let half = 50f32.log2();
+        run(&mut lfo, half);
+        // without retrigger, the phase carries on
+        lfo.handle_note(60.0, 100.0, true);
+        assert!(run(&mut lfo, half)[0] > -0.01);
+        lfo.set_retrigger(true);
+        lfo.handle_note(60.0, 100.0, true);
+        assert!((run(&mut lfo, half)[0] + 1.0).abs() < 1e-6);
+    }
+}
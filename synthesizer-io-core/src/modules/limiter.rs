@@ -0,0 +1,190 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A lookahead brickwall limiter, suitable as a guard on the master bus.
+//!
+//! The gain needed to keep each sample under the ceiling is held at its
+//! minimum over the lookahead window, then smoothed with a moving average of
+//! the same length. The audio is delayed to match, so the gain has reached its
+//! target by the time a peak arrives, and the output never exceeds the ceiling.
+//!
+//! Control inputs (optional): ceiling (log2 gain, default -0.1), release time
+//! in seconds (default 0.05).
+//!
+//! Latency is the lookahead time, rounded to whole samples.
+
+use module::{Module, Buffer};
+
+pub struct Limiter {
+    sample_rate: f32,
+    window: usize,
+    // audio delay line, `window - 1` samples long (plus one slot)
+    delay: Box<[f32]>,
+    delay_pos: usize,
+    // monotonic deque of (sample index, gain) for the sliding minimum
+    dq_ix: Box<[u64]>,
+    dq_gain: Box<[f32]>,
+    dq_head: usize,
+    dq_len: usize,
+    // ring of held gains, for the moving average
+    held: Box<[f32]>,
+    held_sum: f64,
+    held_pos: usize,
+    release_gain: f32,  // held gain after release smoothing
+    n: u64,
+    release_time: f32,
+    release_coef: f32,
+}
+
+impl Limiter {
+    /// Create a limiter with the given lookahead time in seconds.
+    pub fn new(sample_rate: f32, lookahead: f32) -> Limiter {
+        // the audio is delayed by one sample less than the window
+        let window = (lookahead * sample_rate).round() as usize + 1;
+        Limiter {
+            sample_rate,
+            window,
+            delay: vec![0.0; window].into_boxed_slice(),
+            delay_pos: 0,
+            dq_ix: vec![0; window].into_boxed_slice(),
+            dq_gain: vec![0.0; window].into_boxed_slice(),
+            dq_head: 0,
+            dq_len: 0,
+            held: vec![1.0; window].into_boxed_slice(),
+            held_sum: window as f64,
+            held_pos: 0,
+            release_gain: 1.0,
+            n: 0,
+            release_time: -1.0,
+            release_coef: 0.0,
+        }
+    }
+
+    /// The delay introduced by the lookahead, in samples.
+    pub fn latency(&self) -> usize {
+        self.window - 1
+    }
+
+    // Push a required gain, returning the minimum over the window.
+    fn sliding_min(&mut self, gain: f32) -> f32 {
+        let cap = self.window;
+        // drop entries from the back that are no smaller than the new one
+        while self.dq_len > 0 {
+            let back = (self.dq_head + self.dq_len - 1) % cap;
+            if self.dq_gain[back] >= gain {
+                self.dq_len -= 1;
+            } else {
+                break;
+            }
+        }
+        // drop the front if it has left the window
+        if self.dq_len > 0 && self.dq_ix[self.dq_head] + cap as u64 <= self.n {
+            self.dq_head = (self.dq_head + 1) % cap;
+            self.dq_len -= 1;
+        }
+        let back = (self.dq_head + self.dq_len) % cap;
+        self.dq_ix[back] = self.n;
+        self.dq_gain[back] = gain;
+        self.dq_len += 1;
+        self.dq_gain[self.dq_head]
+    }
+}
+
+impl Module for Limiter {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let ceiling = control_in.first().cloned().unwrap_or(-0.1).exp2();
+        let release = control_in.get(1).cloned().unwrap_or(0.05);
+        if release != self.release_time {
+            self.release_coef = (-1.0 / (release.max(1e-4) * self.sample_rate)).exp();
+            self.release_time = release;
+        }
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        let window_recip = 1.0 / self.window as f64;
+        for (&x, y) in inb.iter().zip(out.iter_mut()) {
+            let required = if x.abs() > ceiling { ceiling / x.abs() } else { 1.0 };
+            let min_gain = self.sliding_min(required);
+            // fall instantly, recover at the release rate
+            let released = 1.0 + (self.release_gain - 1.0) * self.release_coef;
+            self.release_gain = released.min(min_gain);
+            self.held_sum += (self.release_gain - self.held[self.held_pos]) as f64;
+            self.held[self.held_pos] = self.release_gain;
+            self.held_pos = (self.held_pos + 1) % self.window;
+            let gain = (self.held_sum * window_recip) as f32;
+
+            // delay the audio by window - 1 samples
+            let delayed = if self.window == 1 {
+                x
+            } else {
+                let d = self.delay[self.delay_pos];
+                self.delay[self.delay_pos] = x;
+                self.delay_pos = (self.delay_pos + 1) % (self.window - 1);
+                d
+            };
+            *y = delayed * gain;
+            self.n += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn run(limiter: &mut Limiter, input: &[f32]) -> Vec<f32> {
+        let mut result = Vec::new();
+        for chunk in input.chunks(32) {
+            let mut inb = Buffer::default();
+            inb.get_mut().copy_from_slice(chunk);
+            let mut out = [Buffer::default()];
+            limiter.process(&[], &mut [], &[&inb], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    #[test]
+    fn latency_is_lookahead() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 0.002);
+        assert_eq!(limiter.latency(), 96);
+        let mut input = vec![0.0; 256];
+        input[0] = 0.5;
+        let out = run(&mut limiter, &input);
+        assert_eq!(out[96], 0.5);
+        assert!(out.iter().enumerate().all(|(i, &x)| i == 96 || x == 0.0));
+    }
+
+    #[test]
+    fn peaks_stay_under_ceiling() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 0.002);
+        let ceiling = (-0.1f32).exp2();
+        // a loud sine with sudden spikes
+        let input: Vec<f32> = (0..4800).map(|i| {
+            let spike = if i % 1000 == 500 { 8.0 } else { 1.0 };
+            spike * 2.0 * (i as f32 * 0.05).sin()
+        }).collect();
+        let out = run(&mut limiter, &input);
+        assert!(out.iter().all(|&x| x.abs() <= ceiling * 1.0001));
+        // the sine is still there, just quieter
+        let peak = out[2000..].iter().fold(0.0f32, |a, &x| a.max(x.abs()));
+        assert!(peak > 0.5 * ceiling);
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A mixer with per-input level, pan, mute and aux sends.
+//!
+//! Buffer inputs: one per channel, each mono. Control input (optional):
+//! master level, log2 of gain.
+//!
+//! Per-channel settings are parameters, set with `set_param` at index
+//! `Mixer::param(channel, Mixer::PARAM_LEVEL)` and so on. Levels and sends
+//! are log2 of gain; pan ranges from -1 (left) to 1 (right); mute is on when
+//! nonzero.
+//!
+//! Outputs: left and right master buffers, then one mono buffer per aux send.
+//! Sends are post-fader and post-mute, but not affected by pan or master
+//! level. All gains are ramped linearly over each chunk, as in `Gain`.
+
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+
+const MAX_AUX: usize = 4;
+
+struct Channel {
+    level: f32,  // all log2 gain
+    pan: f32,
+    mute: bool,
+    sends: [f32; MAX_AUX],
+    // linear gains applied at the end of the last chunk
+    last_l: f32,
+    last_r: f32,
+    last_sends: [f32; MAX_AUX],
+}
+
+pub struct Mixer {
+    channels: Vec<Channel>,
+    n_aux: usize,
+    last_master: f32,
+}
+
+// Linear ramp from `g0` to `g1` over the chunk, applied to `x` and added to `out`.
+fn mix_ramp(out: &mut [f32], x: &[f32], g0: f32, g1: f32) {
+    if g0 == 0.0 && g1 == 0.0 {
+        return;
+    }
+    let dg = (g1 - g0) * (1.0 / out.len() as f32);
+    let mut g = g0 + dg;
+    for (y, &x) in out.iter_mut().zip(x.iter()) {
+        *y += x * g;
+        g += dg;
+    }
+}
+
+impl Mixer {
+    /// Maximum number of aux sends.
+    pub const MAX_AUX: usize = MAX_AUX;
+
+    /// Offset of the level parameter for a channel.
+    pub const PARAM_LEVEL: usize = 0;
+
+    /// Offset of the pan parameter for a channel.
+    pub const PARAM_PAN: usize = 1;
+
+    /// Offset of the mute parameter for a channel.
+    pub const PARAM_MUTE: usize = 2;
+
+    /// Offset of the first aux send level for a channel; send `k` is at
+    /// `PARAM_SEND + k`.
+    pub const PARAM_SEND: usize = 3;
+
+    /// Number of parameter indices used by each channel.
+    pub const PARAMS_PER_CHANNEL: usize = Mixer::PARAM_SEND + MAX_AUX;
+
+    /// Create a mixer with `n_inputs` channels and `n_aux` sends (at most
+    /// `Mixer::MAX_AUX`). Channels start at unity level, centered, with sends off.
+    pub fn new(n_inputs: usize, n_aux: usize) -> Mixer {
+        assert!(n_aux <= MAX_AUX, "too many aux sends");
+        let channels = (0..n_inputs).map(|_| Channel {
+            level: 0.0,
+            pan: 0.0,
+            mute: false,
+            sends: [-f32::INFINITY; MAX_AUX],
+            last_l: 1.0,
+            last_r: 1.0,
+            last_sends: [0.0; MAX_AUX],
+        }).collect();
+        Mixer { channels, n_aux, last_master: 1.0 }
+    }
+
+    /// The parameter index of a setting (one of the `PARAM_` offsets) for a
+    /// channel.
+    pub fn param(channel: usize, offset: usize) -> usize {
+        channel * Mixer::PARAMS_PER_CHANNEL + offset
+    }
+
+    /// Set the initial level of a channel, before the mixer is added to a graph.
+    pub fn set_level(&mut self, channel: usize, level: f32) {
+        self.channels[channel].level = level;
+        let g = level.exp2();
+        self.channels[channel].last_l = g;
+        self.channels[channel].last_r = g;
+    }
+}
+
+impl Module for Mixer {
+    fn n_bufs_out(&self) -> usize { 2 + self.n_aux }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_mixer) = old.to_any().downcast_ref::<Mixer>() {
+            for (ch, old_ch) in self.channels.iter_mut().zip(old_mixer.channels.iter()) {
+                ch.last_l = old_ch.last_l;
+                ch.last_r = old_ch.last_r;
+                ch.last_sends = old_ch.last_sends;
+            }
+            self.last_master = old_mixer.last_master;
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        for buf in buf_out.iter_mut() {
+            buf.set_zero();
+        }
+        let (master, aux) = buf_out.split_at_mut(2);
+        let (out_l, out_r) = master.split_at_mut(1);
+        let out_l = out_l[0].get_mut();
+        let out_r = out_r[0].get_mut();
+        for (ch, buf) in self.channels.iter_mut().zip(buf_in.iter()) {
+            let x = buf.get();
+            let g = if ch.mute { 0.0 } else { ch.level.exp2() };
+            // balance law: a centered channel is at unity on both sides
+            let pan = ch.pan.clamp(-1.0, 1.0);
+            let g_l = g * (1.0 - pan).min(1.0);
+            let g_r = g * (1.0 + pan).min(1.0);
+            mix_ramp(out_l, x, ch.last_l, g_l);
+            mix_ramp(out_r, x, ch.last_r, g_r);
+            ch.last_l = g_l;
+            ch.last_r = g_r;
+            for (k, out) in aux.iter_mut().enumerate() {
+                let g_send = g * ch.sends[k].exp2();
+                mix_ramp(out.get_mut(), x, ch.last_sends[k], g_send);
+                ch.last_sends[k] = g_send;
+            }
+        }
+
+        let master = control_in.first().cloned().unwrap_or(0.0).exp2();
+        if master != 1.0 || self.last_master != 1.0 {
+            let dg = (master - self.last_master) * (1.0 / N_SAMPLES_PER_CHUNK as f32);
+            let mut g = self.last_master + dg;
+            for (l, r) in out_l.iter_mut().zip(out_r.iter_mut()) {
+                *l *= g;
+                *r *= g;
+                g += dg;
+            }
+        }
+        self.last_master = master;
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        let channel = param_ix / Mixer::PARAMS_PER_CHANNEL;
+        let offset = param_ix % Mixer::PARAMS_PER_CHANNEL;
+        if let Some(ch) = self.channels.get_mut(channel) {
+            match offset {
+                Mixer::PARAM_LEVEL => ch.level = val,
+                Mixer::PARAM_PAN => ch.pan = val,
+                Mixer::PARAM_MUTE => ch.mute = val != 0.0,
+                _ => ch.sends[offset - Mixer::PARAM_SEND] = val,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    // Runs a chunk with constant inputs `xs`, returning all output buffers.
+    fn run(mixer: &mut Mixer, master: f32, xs: &[f32]) -> Vec<Vec<f32>> {
+        let inputs: Vec<Buffer> = xs.iter().map(|&x| {
+            let mut buf = Buffer::default();
+            for y in buf.get_mut().iter_mut() {
+                *y = x;
+            }
+            buf
+        }).collect();
+        let buf_in: Vec<&Buffer> = inputs.iter().collect();
+        let mut out: Vec<Buffer> = (0..mixer.n_bufs_out()).map(|_| Buffer::default()).collect();
+        mixer.process(&[master], &mut [], &buf_in, &mut out);
+        out.iter().map(|buf| buf.get().to_vec()).collect()
+    }
+
+    // Runs two chunks so that gains have settled, returning the last sample
+    // of each output.
+    fn settle(mixer: &mut Mixer, master: f32, xs: &[f32]) -> Vec<f32> {
+        run(mixer, master, xs);
+        run(mixer, master, xs).iter().map(|buf| buf[buf.len() - 1]).collect()
+    }
+
+    #[test]
+    fn pan_law() {
+        let mut mixer = Mixer::new(1, 0);
+        for &(pan, left, right) in &[(0.0, 1.0, 1.0), (-1.0, 1.0, 0.0), (1.0, 0.0, 1.0),
+            (0.5, 0.5, 1.0), (-0.25, 1.0, 0.75), (2.0, 0.0, 1.0)]
+        {
+            mixer.set_param(Mixer::param(0, Mixer::PARAM_PAN), pan, 0);
+            assert_eq!(settle(&mut mixer, 0.0, &[0.5]), vec![0.5 * left, 0.5 * right]);
+        }
+    }
+
+    #[test]
+    fn levels_and_master() {
+        let mut mixer = Mixer::new(2, 0);
+        mixer.set_level(1, -1.0);
+        assert_eq!(settle(&mut mixer, 0.0, &[1.0, 1.0]), vec![1.5, 1.5]);
+        assert_eq!(settle(&mut mixer, -1.0, &[1.0, 1.0]), vec![0.75, 0.75]);
+    }
+
+    #[test]
+    fn mute_ramps_down() {
+        let mut mixer = Mixer::new(2, 1);
+        mixer.set_param(Mixer::param(0, Mixer::PARAM_SEND), 0.0, 0);
+        settle(&mut mixer, 0.0, &[1.0, 1.0]);
+        mixer.set_param(Mixer::param(0, Mixer::PARAM_MUTE), 1.0, 0);
+        let out = run(&mut mixer, 0.0, &[1.0, 1.0]);
+        // the muted channel fades out over the chunk, the other is untouched
+        assert!(out[0][0] > 1.9 && out[0][31] == 1.0);
+        assert!(out[2][0] > 0.9 && out[2][31] == 0.0);
+        assert_eq!(settle(&mut mixer, 0.0, &[1.0, 1.0]), vec![1.0, 1.0, 0.0]);
+        mixer.set_param(Mixer::param(0, Mixer::PARAM_MUTE), 0.0, 0);
+        assert_eq!(settle(&mut mixer, 0.0, &[1.0, 1.0]), vec![2.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn sends() {
+        let mut mixer = Mixer::new(2, 2);
+        mixer.set_param(Mixer::param(0, Mixer::PARAM_SEND), -1.0, 0);
+        mixer.set_param(Mixer::param(1, Mixer::PARAM_SEND + 1), 0.0, 0);
+        assert_eq!(settle(&mut mixer, 0.0, &[1.0, 0.25]), vec![1.25, 1.25, 0.5, 0.25]);
+        // post-fader, but not affected by pan or master level
+        mixer.set_param(Mixer::param(0, Mixer::PARAM_LEVEL), -1.0, 0);
+        mixer.set_param(Mixer::param(0, Mixer::PARAM_PAN), -1.0, 0);
+        assert_eq!(settle(&mut mixer, -2.0, &[1.0, 0.25]), vec![0.1875, 0.0625, 0.25, 0.25]);
+    }
+}
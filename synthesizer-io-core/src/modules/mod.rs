@@ -22,9 +22,33 @@ mod biquad;
 mod const_ctrl;
 mod smooth_ctrl;
 mod note_pitch;
+mod note_expression;
 mod adsr;
 mod gain;
 mod monitor;
+mod lfo;
+mod delay;
+mod reverb;
+mod convolver;
+mod saturator;
+mod envelope_follower;
+mod compressor;
+mod limiter;
+mod chorus;
+mod flanger;
+mod phaser;
+mod pluck;
+mod fm_operator;
+mod mixer;
+mod multiply;
+mod crossfade;
+mod scale_offset;
+mod clamp;
+mod audio_to_ctrl;
+mod dc_blocker;
+mod sampler;
+mod automation;
+mod mod_matrix;
 
 pub use self::sum::Sum;
 pub use self::buzz::Buzz;
@@ -33,7 +57,50 @@ pub use self::saw::Saw;
 pub use self::biquad::Biquad;
 pub use self::const_ctrl::ConstCtrl;
 pub use self::smooth_ctrl::SmoothCtrl;
-pub use self::note_pitch::NotePitch;
-pub use self::adsr::Adsr;
+pub use self::note_pitch::{NotePitch, GlideMode};
+pub use self::note_expression::NoteExpression;
+pub use self::adsr::{Adsr, AdsrCurve, AdsrTrigger};
 pub use self::gain::Gain;
 pub use self::monitor::Monitor;
+pub use self::lfo::{Lfo, LfoShape};
+pub use self::delay::Delay;
+pub use self::reverb::Reverb;
+pub use self::convolver::Convolver;
+pub use self::saturator::{Saturator, SaturatorCurve};
+pub use self::envelope_follower::{EnvelopeFollower, DetectorMode};
+pub use self::compressor::Compressor;
+pub use self::limiter::Limiter;
+pub use self::chorus::Chorus;
+pub use self::flanger::Flanger;
+pub use self::phaser::Phaser;
+pub use self::pluck::Pluck;
+pub use self::fm_operator::{FmOperator, FmFreq};
+pub use self::mixer::Mixer;
+pub use self::multiply::Multiply;
+pub use self::crossfade::Crossfade;
+pub use self::scale_offset::ScaleOffset;
+pub use self::clamp::Clamp;
+pub use self::audio_to_ctrl::{AudioToCtrl, AudioToCtrlMode};
+pub use self::dc_blocker::DcBlocker;
+pub use self::sampler::{Sampler, Sample, SamplerMode};
+pub use self::automation::Automation;
+pub use self::mod_matrix::ModMatrix;
+
+use module::Ports;
+
+/// Look up port metadata for a module by its patcher name.
+///
+/// Only modules that declare their ports are listed so far.
+pub fn ports(name: &str) -> Option<&'static Ports> {
+    match name {
+        "multiply" => Some(&Multiply::PORTS),
+        "crossfade" => Some(&Crossfade::PORTS),
+        "scale_offset" => Some(&ScaleOffset::PORTS),
+        "clamp" => Some(&Clamp::PORTS),
+        "audio_to_ctrl" => Some(&AudioToCtrl::PORTS),
+        "dc_blocker" => Some(&DcBlocker::PORTS),
+        "note_expression" => Some(&NoteExpression::PORTS),
+        "automation" => Some(&Automation::PORTS),
+        _ => None,
+    }
+}
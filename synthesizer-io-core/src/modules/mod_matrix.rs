@@ -0,0 +1,166 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A modulation matrix, routing each of a set of sources to any number of
+//! destinations with a bipolar depth.
+//!
+//! Control inputs: the sources, then a base value for each destination
+//! (optional, defaulting to 0).
+//!
+//! Outputs: one control value per destination, the base value plus the sum of
+//! depth times source over the slots that target it, limited to the
+//! destination's range.
+
+use graph::MAX_CTRL;
+use module::{Module, Buffer};
+
+/// Maximum number of routing slots.
+const MAX_SLOTS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    source: usize,
+    dest: usize,
+    depth: f32,
+}
+
+pub struct ModMatrix {
+    n_sources: usize,
+    slots: [Slot; MAX_SLOTS],
+    ranges: Vec<(f32, f32)>,
+}
+
+impl ModMatrix {
+    /// Maximum number of routing slots.
+    pub const MAX_SLOTS: usize = MAX_SLOTS;
+
+    /// Offset of the source index parameter for a slot.
+    pub const PARAM_SOURCE: usize = 0;
+
+    /// Offset of the destination index parameter for a slot.
+    pub const PARAM_DEST: usize = 1;
+
+    /// Offset of the depth parameter for a slot. Depth is bipolar; 0 turns
+    /// the slot off.
+    pub const PARAM_DEPTH: usize = 2;
+
+    /// Number of parameter indices used by each slot.
+    pub const PARAMS_PER_SLOT: usize = 3;
+
+    /// Create a matrix with `n_sources` sources and `n_dests` destinations.
+    /// All slots start with zero depth, and destinations are unbounded.
+    pub fn new(n_sources: usize, n_dests: usize) -> ModMatrix {
+        assert!(n_sources + n_dests <= MAX_CTRL, "too many matrix inputs");
+        let slot = Slot { source: 0, dest: 0, depth: 0.0 };
+        ModMatrix {
+            n_sources,
+            slots: [slot; MAX_SLOTS],
+            ranges: vec![(-f32::INFINITY, f32::INFINITY); n_dests],
+        }
+    }
+
+    /// The parameter index of a setting (one of the `PARAM_` offsets) for a
+    /// slot.
+    pub fn param(slot: usize, offset: usize) -> usize {
+        slot * ModMatrix::PARAMS_PER_SLOT + offset
+    }
+
+    /// Set a slot before the matrix is added to a graph.
+    pub fn set_slot(&mut self, slot: usize, source: usize, dest: usize, depth: f32) {
+        self.slots[slot] = Slot { source, dest, depth };
+    }
+
+    /// Limit the output for a destination, so modulation can't drive it
+    /// somewhere the module it feeds can't handle.
+    pub fn set_range(&mut self, dest: usize, min: f32, max: f32) {
+        self.ranges[dest] = (min, max);
+    }
+}
+
+impl Module for ModMatrix {
+    fn n_ctrl_out(&self) -> usize { self.ranges.len() }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_ref::<ModMatrix>() {
+            self.slots = old.slots;
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        for (i, out) in control_out.iter_mut().enumerate() {
+            *out = control_in.get(self.n_sources + i).cloned().unwrap_or(0.0);
+        }
+        let sources = &control_in[..self.n_sources.min(control_in.len())];
+        for slot in &self.slots {
+            if slot.depth == 0.0 || slot.dest >= control_out.len() {
+                continue;
+            }
+            if let Some(&source) = sources.get(slot.source) {
+                control_out[slot.dest] += slot.depth * source;
+            }
+        }
+        for (out, &(min, max)) in control_out.iter_mut().zip(self.ranges.iter()) {
+            // max then min rather than clamp, which panics if the bounds cross
+            *out = out.max(min).min(max);
+        }
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        let slot = param_ix / ModMatrix::PARAMS_PER_SLOT;
+        if slot >= MAX_SLOTS {
+            return;
+        }
+        match param_ix % ModMatrix::PARAMS_PER_SLOT {
+            ModMatrix::PARAM_SOURCE => self.slots[slot].source = val as usize,
+            ModMatrix::PARAM_DEST => self.slots[slot].dest = val as usize,
+            _ => self.slots[slot].depth = val,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::Module;
+    use super::*;
+
+    fn run(matrix: &mut ModMatrix, control_in: &[f32]) -> Vec<f32> {
+        let mut out = vec![0.0; matrix.n_ctrl_out()];
+        matrix.process(control_in, &mut out, &[], &mut []);
+        out
+    }
+
+    #[test]
+    fn sums_slots_into_dests() {
+        let mut matrix = ModMatrix::new(2, 2);
+        matrix.set_slot(0, 0, 1, 0.5);
+        matrix.set_slot(1, 1, 1, -2.0);
+        matrix.set_param(ModMatrix::param(2, ModMatrix::PARAM_DEST), 0.0, 0);
+        matrix.set_param(ModMatrix::param(2, ModMatrix::PARAM_SOURCE), 1.0, 0);
+        matrix.set_param(ModMatrix::param(2, ModMatrix::PARAM_DEPTH), 3.0, 0);
+        assert_eq!(run(&mut matrix, &[1.0, 0.25, 10.0, 20.0]), vec![10.75, 20.0]);
+        // the base inputs are optional
+        assert_eq!(run(&mut matrix, &[-1.0, 1.0]), vec![3.0, -2.5]);
+    }
+
+    #[test]
+    fn limits_to_range() {
+        let mut matrix = ModMatrix::new(1, 1);
+        matrix.set_slot(0, 0, 0, 4.0);
+        matrix.set_range(0, 0.0, 0.995);
+        assert_eq!(run(&mut matrix, &[1.0, 0.5]), vec![0.995]);
+        assert_eq!(run(&mut matrix, &[-1.0, 0.5]), vec![0.0]);
+    }
+}
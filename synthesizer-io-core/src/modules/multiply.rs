@@ -0,0 +1,79 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A module that multiplies its inputs, sample by sample. With two audio
+//! inputs, this is a ring modulator.
+
+use module::{Module, Buffer, Ports};
+
+#[derive(Default)]
+pub struct Multiply;
+
+impl Multiply {
+    pub const PORTS: Ports = Ports {
+        buf_in: &["a", "b"],
+        ctrl_in: &[],
+        buf_out: &["out"],
+        ctrl_out: &[],
+    };
+
+    pub fn new() -> Multiply {
+        Multiply
+    }
+}
+
+impl Module for Multiply {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let out = buf_out[0].get_mut();
+        match buf_in.split_first() {
+            Some((first, rest)) => {
+                out.copy_from_slice(first.get());
+                for buf in rest {
+                    for (y, &x) in out.iter_mut().zip(buf.get().iter()) {
+                        *y *= x;
+                    }
+                }
+            }
+            None => out.iter_mut().for_each(|y| *y = 0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    #[test]
+    fn ring_modulates() {
+        let mut a = Buffer::default();
+        let mut b = Buffer::default();
+        for (i, (x, y)) in a.get_mut().iter_mut().zip(b.get_mut().iter_mut()).enumerate() {
+            *x = i as f32;
+            *y = if i % 2 == 0 { 1.0 } else { -0.5 };
+        }
+        let mut out = [Buffer::default()];
+        Multiply::new().process(&[], &mut [], &[&a, &b], &mut out);
+        assert_eq!(&out[0].get()[..4], &[0.0, -0.5, 2.0, -1.5]);
+        // a single input passes through, and none gives silence
+        Multiply::new().process(&[], &mut [], &[&a], &mut out);
+        assert_eq!(out[0].get(), a.get());
+        Multiply::new().process(&[], &mut [], &[], &mut out);
+        assert!(out[0].get().iter().all(|&y| y == 0.0));
+    }
+}
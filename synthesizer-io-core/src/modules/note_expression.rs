@@ -0,0 +1,111 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-note expression for one voice of an MPE instrument: pitch bend,
+//! pressure and timbre, as set from the MIDI messages on the voice's channel.
+//!
+//! Control outputs: pitch bend in octaves (as consumed by the pitch offset
+//! input of `NotePitch`), pressure (0 to 1), timbre (0 to 1).
+//!
+//! The outputs are smoothed, except that a note-on jumps straight to the
+//! current values, since MPE controllers send a note's initial expression
+//! just before the note itself.
+
+use module::{Module, Buffer, Ports, N_SAMPLES_PER_CHUNK};
+
+pub struct NoteExpression {
+    bend_range: f32,  // in semitones
+    coef: f32,  // smoothing coefficient per chunk
+    target: [f32; 3],
+    value: [f32; 3],
+}
+
+impl NoteExpression {
+    /// Parameter index for pitch bend, -1 to 1.
+    pub const PARAM_BEND: usize = 0;
+
+    /// Parameter index for pressure (channel aftertouch), 0 to 1.
+    pub const PARAM_PRESSURE: usize = 1;
+
+    /// Parameter index for timbre (CC 74), 0 to 1.
+    pub const PARAM_TIMBRE: usize = 2;
+
+    /// Parameter index for the pitch bend range, in semitones.
+    pub const PARAM_BEND_RANGE: usize = 3;
+
+    pub const PORTS: Ports = Ports {
+        buf_in: &[],
+        ctrl_in: &[],
+        buf_out: &[],
+        ctrl_out: &["bend", "pressure", "timbre"],
+    };
+
+    pub fn new(sample_rate: f32) -> NoteExpression {
+        // 5ms time constant
+        let coef = 1.0 - (-(N_SAMPLES_PER_CHUNK as f32) / (0.005 * sample_rate)).exp();
+        // timbre rests at its center value
+        let initial = [0.0, 0.0, 0.5];
+        NoteExpression {
+            bend_range: 48.0,
+            coef,
+            target: initial,
+            value: initial,
+        }
+    }
+
+    /// Set the pitch bend range, in semitones. The MPE default is 48.
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        self.bend_range = semitones;
+    }
+}
+
+impl Module for NoteExpression {
+    fn n_ctrl_out(&self) -> usize { 3 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_ref::<NoteExpression>() {
+            self.target = old.target;
+            self.value = old.value;
+        }
+    }
+
+    fn receives_notes(&self) -> bool { true }
+
+    fn handle_note(&mut self, _midi_num: f32, _velocity: f32, on: bool) {
+        if on {
+            self.value = self.target;
+        }
+    }
+
+    fn process(&mut self, _control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        for (value, &target) in self.value.iter_mut().zip(self.target.iter()) {
+            *value += (target - *value) * self.coef;
+        }
+        control_out[0] = self.value[0] * self.bend_range * (1.0 / 12.0);
+        control_out[1] = self.value[1];
+        control_out[2] = self.value[2];
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            NoteExpression::PARAM_BEND => self.target[0] = val.clamp(-1.0, 1.0),
+            NoteExpression::PARAM_PRESSURE => self.target[1] = val.clamp(0.0, 1.0),
+            NoteExpression::PARAM_TIMBRE => self.target[2] = val.clamp(0.0, 1.0),
+            NoteExpression::PARAM_BEND_RANGE => self.bend_range = val,
+            _ => (),
+        }
+    }
+}
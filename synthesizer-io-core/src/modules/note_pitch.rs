@@ -12,32 +12,152 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! A simple module that just holds a note at a constant pitch.
+//! A module that holds the pitch of the most recent note, with optional
+//! portamento and pitch bend.
+//!
+//! Control inputs (all optional): glide time in seconds, pitch bend (-1 to 1),
+//! pitch offset in octaves (such as per-note bend from `NoteExpression`).
+//!
+//! Control outputs: log2 of frequency in Hz, velocity (0 to 1), gate (0 or 1).
 
 use module::{Module, Buffer};
+use tuning::Tuning;
 
 pub struct NotePitch {
-    value: f32,
+    value: f32,  // current pitch, not including bend
+    target: f32,
+    glide_dist: f32,  // distance to target when the note started
+    glide_mode: GlideMode,
+    bend_range: f32,  // in semitones
+    velocity: f32,
+    gate: bool,
+    has_note: bool,
+    t: u64,
+    tuning: Tuning,
+}
+
+/// How glide time is interpreted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GlideMode {
+    /// Every glide takes the glide time, regardless of interval.
+    ConstantTime,
+    /// The glide time is the time taken to move one octave.
+    ConstantRate,
 }
 
 impl NotePitch {
+    /// Parameter index for the glide mode (0 = constant time, 1 = constant rate).
+    pub const PARAM_GLIDE_MODE: usize = 0;
+
+    /// Parameter index for the pitch bend range, in semitones.
+    pub const PARAM_BEND_RANGE: usize = 1;
+
     pub fn new() -> NotePitch {
-        NotePitch { value: 0.0 }
+        NotePitch {
+            value: 0.0,
+            target: 0.0,
+            glide_dist: 0.0,
+            glide_mode: GlideMode::ConstantTime,
+            bend_range: 2.0,
+            velocity: 0.0,
+            gate: false,
+            has_note: false,
+            t: 0,
+            tuning: Tuning::equal_temperament(),
+        }
+    }
+
+    pub fn set_glide_mode(&mut self, glide_mode: GlideMode) {
+        self.glide_mode = glide_mode;
+    }
+
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        self.bend_range = semitones;
+    }
+
+    fn advance_to(&mut self, t: u64, glide_time: f32) {
+        let dt = if self.t == 0 || t <= self.t { 0.0 } else { (t - self.t) as f32 * 1e-9 };
+        self.t = t;
+        if glide_time <= 0.0 {
+            self.value = self.target;
+            return;
+        }
+        let rate = match self.glide_mode {
+            GlideMode::ConstantTime => self.glide_dist / glide_time,
+            GlideMode::ConstantRate => 1.0 / glide_time,
+        };
+        let step = rate * dt;
+        let delta = self.target - self.value;
+        if delta.abs() <= step {
+            self.value = self.target;
+        } else {
+            self.value += step.copysign(delta);
+        }
     }
 }
 
 impl Module for NotePitch {
-    fn n_ctrl_out(&self) -> usize { 1 }
+    fn n_ctrl_out(&self) -> usize { 3 }
 
-    fn handle_note(&mut self, midi_num: f32, _velocity: f32, on: bool) {
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_ref::<NotePitch>() {
+            self.value = old.value;
+            self.target = old.target;
+            self.glide_dist = old.glide_dist;
+            self.velocity = old.velocity;
+            self.gate = old.gate;
+            self.has_note = old.has_note;
+            self.t = old.t;
+            self.tuning = old.tuning;
+        }
+    }
+
+    fn receives_notes(&self) -> bool { true }
+
+    fn handle_note(&mut self, midi_num: f32, velocity: f32, on: bool) {
         if on {
-            self.value = midi_num * (1.0 / 12.0) + (440f32.log2() - 69.0 / 12.0);
+            self.target = self.tuning.pitch(midi_num);
+            if !self.has_note {
+                self.value = self.target;
+                self.has_note = true;
+            }
+            self.glide_dist = (self.target - self.value).abs();
+            self.velocity = velocity * (1.0 / 127.0);
         }
+        self.gate = on;
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        self.process_ts(control_in, control_out, buf_in, buf_out, 0);
     }
 
-    fn process(&mut self, _control_in: &[f32], control_out: &mut [f32],
-        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    fn process_ts(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer], timestamp: u64)
     {
-        control_out[0] = self.value;
+        let glide_time = control_in.first().cloned().unwrap_or(0.0);
+        let bend = control_in.get(1).cloned().unwrap_or(0.0);
+        let offset = control_in.get(2).cloned().unwrap_or(0.0);
+        self.advance_to(timestamp, glide_time);
+        control_out[0] = self.value + bend * self.bend_range * (1.0 / 12.0) + offset;
+        control_out[1] = self.velocity;
+        control_out[2] = if self.gate { 1.0 } else { 0.0 };
+    }
+
+    fn set_tuning(&mut self, tuning: &Tuning) {
+        self.tuning = *tuning;
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            NotePitch::PARAM_GLIDE_MODE => self.glide_mode = if val == 0.0 {
+                GlideMode::ConstantTime
+            } else {
+                GlideMode::ConstantRate
+            },
+            NotePitch::PARAM_BEND_RANGE => self.bend_range = val,
+            _ => (),
+        }
     }
 }
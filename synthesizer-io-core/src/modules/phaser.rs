@@ -0,0 +1,166 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A stereo phaser, using a cascade of first-order allpass filters whose
+//! break frequency is swept by a sine LFO.
+//!
+//! Control inputs (all optional): rate (log2 of Hz, default 0.5Hz), depth
+//! (0 to 1), feedback (-1 to 1), mix (0 = dry, 1 = wet; 0.5 gives the
+//! deepest notches).
+//!
+//! The input is mono; outputs are left and right buffers, swept with LFOs a
+//! quarter cycle apart.
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+
+const N_STAGES: usize = 6;
+
+// Range of the swept break frequency, as log2 of Hz.
+const MIN_FREQ: f32 = 7.64;  // about 200Hz
+const MAX_FREQ: f32 = 12.0;  // about 4kHz
+
+// One channel: a cascade of allpass stages with feedback.
+#[derive(Default)]
+struct Channel {
+    state: [f32; N_STAGES],
+    last: f32,
+}
+
+impl Channel {
+    fn process(&mut self, x: f32, a: f32, feedback: f32) -> f32 {
+        let mut y = x + feedback * self.last;
+        for s in self.state.iter_mut() {
+            // first-order allpass: H(z) = (a + z^-1) / (1 + a z^-1)
+            let out = a * y + *s;
+            *s = y - a * out;
+            y = out;
+        }
+        self.last = y;
+        y
+    }
+}
+
+pub struct Phaser {
+    sample_rate: f32,
+    left: Channel,
+    right: Channel,
+    phase: f32,  // LFO phase in cycles
+}
+
+impl Phaser {
+    pub fn new(sample_rate: f32) -> Phaser {
+        Phaser {
+            sample_rate,
+            left: Channel::default(),
+            right: Channel::default(),
+            phase: 0.0,
+        }
+    }
+
+    // allpass coefficient for a break frequency given as log2 of Hz
+    fn coef(&self, log_f: f32) -> f32 {
+        let t = (consts::PI * log_f.exp2() / self.sample_rate).tan();
+        (t - 1.0) / (t + 1.0)
+    }
+}
+
+impl Module for Phaser {
+    fn n_bufs_out(&self) -> usize { 2 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let ctrl = |ix: usize, default: f32| control_in.get(ix).cloned().unwrap_or(default);
+        let n = buf_in[0].get().len();
+        let freq = ctrl(0, -1.0).exp2() / self.sample_rate;
+        let depth = ctrl(1, 0.5).clamp(0.0, 1.0) * (MAX_FREQ - MIN_FREQ);
+        let feedback = ctrl(2, 0.3).clamp(-0.95, 0.95);
+        let mix = ctrl(3, 0.5);
+
+        // The sweep is computed at chunk rate and interpolated, since the
+        // coefficient needs a tan.
+        let th = self.phase * 2.0 * consts::PI;
+        let end_th = th + freq * n as f32 * 2.0 * consts::PI;
+        let sweep = |th: f32| MIN_FREQ + depth * (0.5 - 0.5 * th.cos());
+        let a_l0 = self.coef(sweep(th));
+        let a_r0 = self.coef(sweep(th + 0.5 * consts::PI));
+        let da_l = (self.coef(sweep(end_th)) - a_l0) / n as f32;
+        let da_r = (self.coef(sweep(end_th + 0.5 * consts::PI)) - a_r0) / n as f32;
+
+        let inb = buf_in[0].get();
+        let (out_l, out_r) = buf_out.split_at_mut(1);
+        let out_l = out_l[0].get_mut();
+        let out_r = out_r[0].get_mut();
+        for i in 0..n {
+            let x = inb[i];
+            let wet_l = self.left.process(x, a_l0 + da_l * i as f32, feedback);
+            let wet_r = self.right.process(x, a_r0 + da_r * i as f32, feedback);
+            out_l[i] = x + mix * (wet_l - x);
+            out_r[i] = x + mix * (wet_r - x);
+        }
+        self.phase += freq * n as f32;
+        self.phase -= self.phase.floor();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    // The peak left and right output over the last half of a second of a
+    // sine at `freq` Hz.
+    fn sine_response(controls: &[f32], freq: f32) -> (f32, f32) {
+        let mut phaser = Phaser::new(SAMPLE_RATE);
+        let (mut peak_l, mut peak_r) = (0.0f32, 0.0f32);
+        for chunk in 0..1500 {
+            let mut inb = Buffer::default();
+            for (i, x) in inb.get_mut().iter_mut().enumerate() {
+                let t = (chunk * 32 + i) as f32 / SAMPLE_RATE;
+                *x = (t * freq * 2.0 * consts::PI).sin();
+            }
+            let mut out = [Buffer::default(), Buffer::default()];
+            phaser.process(controls, &mut [], &[&inb], &mut out);
+            if chunk >= 750 {
+                peak_l = out[0].get().iter().fold(peak_l, |a, &x| a.max(x.abs()));
+                peak_r = out[1].get().iter().fold(peak_r, |a, &x| a.max(x.abs()));
+            }
+        }
+        (peak_l, peak_r)
+    }
+
+    #[test]
+    fn allpass_keeps_level() {
+        // wet only, no feedback
+        for &freq in &[100.0, 1000.0, 5000.0] {
+            let (left, right) = sine_response(&[0.0, 1.0, 0.0, 1.0], freq);
+            assert!((left - 1.0).abs() < 0.01 && (right - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn notch_at_break_frequency() {
+        // At zero depth the stages break at MIN_FREQ, where six of them
+        // shift the phase by 540 degrees, cancelling the dry signal.
+        let controls = [0.0, 0.0, 0.0, 0.5];
+        let (notch, _) = sine_response(&controls, MIN_FREQ.exp2());
+        assert!(notch < 0.01, "{}", notch);
+        let (other, _) = sine_response(&controls, 2000.0);
+        assert!(other > 0.5);
+    }
+}
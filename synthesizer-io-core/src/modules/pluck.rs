@@ -0,0 +1,218 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A Karplus-Strong plucked string.
+//!
+//! Each note-on fills a delay line with a burst of noise, which then
+//! circulates through a loop filter. The loop length is tuned to the pitch,
+//! with the fractional part of the delay made up by a first-order allpass.
+//!
+//! Control inputs: pitch (log2 of Hz, usually from `NotePitch`), damping
+//! (optional, 0 to 1), brightness (optional, 0 to 1).
+//!
+//! Damping sets the decay time of the string, from about 8 seconds at 0 down
+//! to 30ms at 1. Brightness sets both the color of the excitation and the
+//! loss of high frequencies in the loop.
+
+use module::{Module, Buffer};
+
+// Lowest fundamental the delay line can hold, in Hz.
+const MIN_FREQ: f32 = 20.0;
+
+// Decay time after note-off, in seconds.
+const RELEASE_T60: f32 = 0.15;
+
+pub struct Pluck {
+    sample_rate: f32,
+    buf: Vec<f32>,
+    mask: usize,
+    pos: usize,
+    prev: f32,  // previous input to the loop filter
+    ap_state: f32,
+    excite: Option<f32>,  // pending excitation level
+    gate: bool,
+    rand_state: u32,
+}
+
+impl Pluck {
+    pub fn new(sample_rate: f32) -> Pluck {
+        let size = ((sample_rate / MIN_FREQ) as usize + 4).next_power_of_two();
+        Pluck {
+            sample_rate,
+            buf: vec![0.0; size],
+            mask: size - 1,
+            pos: 0,
+            prev: 0.0,
+            ap_state: 0.0,
+            excite: None,
+            gate: false,
+            rand_state: 0x2545_f491,
+        }
+    }
+
+    // xorshift32, mapped to -1..1
+    fn next_random(&mut self) -> f32 {
+        let mut x = self.rand_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rand_state = x;
+        (x as f32) * (2.0 / 4_294_967_296.0) - 1.0
+    }
+
+    // Fill the part of the line that is about to be read with filtered noise.
+    fn pluck(&mut self, len: usize, level: f32, brightness: f32) {
+        let coef = 0.1 + 0.9 * brightness;
+        let mut lp = 0.0;
+        let mut sum = 0.0;
+        for k in 0..len {
+            lp += coef * (self.next_random() - lp);
+            let ix = self.pos.wrapping_sub(len - k) & self.mask;
+            self.buf[ix] = lp;
+            sum += lp;
+        }
+        // remove DC, which would otherwise circulate without loss
+        let mean = sum / len as f32;
+        for k in 0..len {
+            let ix = self.pos.wrapping_sub(len - k) & self.mask;
+            self.buf[ix] = level * (self.buf[ix] - mean);
+        }
+        self.prev = 0.0;
+        self.ap_state = 0.0;
+    }
+}
+
+impl Module for Pluck {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_pluck) = old.to_any().downcast_mut::<Pluck>() {
+            if old_pluck.buf.len() == self.buf.len() {
+                ::std::mem::swap(&mut self.buf, &mut old_pluck.buf);
+                self.pos = old_pluck.pos;
+                self.prev = old_pluck.prev;
+                self.ap_state = old_pluck.ap_state;
+                self.gate = old_pluck.gate;
+            }
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let freq = control_in[0].exp2().max(MIN_FREQ);
+        let damping = control_in.get(1).cloned().unwrap_or(0.3).clamp(0.0, 1.0);
+        let brightness = control_in.get(2).cloned().unwrap_or(0.7).clamp(0.0, 1.0);
+
+        // The loop filter is a two-point average, weighted by brightness,
+        // with a phase delay of `s` samples.
+        let s = 0.5 - 0.4 * brightness;
+        let t60 = if self.gate { 8.0 * (-8.0 * damping).exp2() } else { RELEASE_T60 };
+        // -60dB in t60, applied once per trip round the loop
+        let g = (-6.9078 / (t60 * freq)).exp();
+
+        // Split the period into an integer delay and an allpass delay in the
+        // range 0.1..1.1, where its phase delay is close to flat.
+        let period = self.sample_rate / freq - s;
+        let n = ((period - 0.1).floor() as usize).clamp(1, self.mask);
+        let d = period - n as f32;
+        let a = (1.0 - d) / (1.0 + d);
+
+        if let Some(level) = self.excite.take() {
+            self.pluck(n, level, brightness);
+        }
+
+        for y in buf_out[0].get_mut().iter_mut() {
+            let x = self.buf[self.pos.wrapping_sub(n) & self.mask];
+            let lp = (1.0 - s) * x + s * self.prev;
+            self.prev = x;
+            let ap = a * lp + self.ap_state;
+            self.ap_state = lp - a * ap;
+            let out = g * ap;
+            self.buf[self.pos] = out;
+            self.pos = (self.pos + 1) & self.mask;
+            *y = out;
+        }
+    }
+
+    fn receives_notes(&self) -> bool { true }
+
+    fn handle_note(&mut self, _midi_num: f32, velocity: f32, on: bool) {
+        if on {
+            self.excite = Some(velocity * (1.0 / 127.0));
+        }
+        self.gate = on;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    // Plucks a string at `freq` Hz and measures the frequency from the
+    // autocorrelation at a lag of many periods, so that interpolating the
+    // peak is accurate to a small fraction of a cent.
+    fn measure(freq: f32) -> f32 {
+        let mut pluck = Pluck::new(SAMPLE_RATE);
+        pluck.handle_note(0.0, 100.0, true);
+        let controls = [freq.log2(), 0.0, 0.5];
+        let mut signal = Vec::new();
+        for _ in 0..300 {
+            let mut out = [Buffer::default()];
+            pluck.process(&controls, &mut [], &[], &mut out);
+            signal.extend_from_slice(out[0].get());
+        }
+        let signal = &signal[1024..];
+        let corr = |lag: usize| -> f32 {
+            signal[..4096].iter().zip(&signal[lag..]).map(|(a, b)| a * b).sum()
+        };
+        let period = SAMPLE_RATE / freq;
+        let periods = (2048.0 / period).round();
+        let guess = (periods * period).round() as usize;
+        let lag = (guess - 3..guess + 4).max_by(|&a, &b| {
+            corr(a).partial_cmp(&corr(b)).unwrap()
+        }).unwrap();
+        let (c0, c1, c2) = (corr(lag - 1), corr(lag), corr(lag + 1));
+        let peak = lag as f32 + 0.5 * (c0 - c2) / (c0 - 2.0 * c1 + c2);
+        periods * SAMPLE_RATE / peak
+    }
+
+    #[test]
+    fn pitch_is_accurate() {
+        // within a cent, up to the top of the piano
+        for &note in &[45.0, 69.0, 93.0, 108.0] {
+            let freq = 440.0 * ((note - 69.0) / 12.0f32).exp2();
+            let cents = 1200.0 * (measure(freq) / freq).log2();
+            assert!(cents.abs() < 1.0, "note {}: {} cents", note, cents);
+        }
+    }
+
+    #[test]
+    fn decays_after_note_off() {
+        let mut pluck = Pluck::new(SAMPLE_RATE);
+        pluck.handle_note(0.0, 127.0, true);
+        let mut out = [Buffer::default()];
+        pluck.process(&[8.0], &mut [], &[], &mut out);
+        assert!(out[0].get().iter().any(|y| y.abs() > 0.1));
+        pluck.handle_note(0.0, 0.0, false);
+        // the release T60 is 0.15s
+        for _ in 0..450 {
+            pluck.process(&[8.0], &mut [], &[], &mut out);
+        }
+        assert!(out[0].get().iter().all(|y| y.abs() < 1e-3));
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A stereo algorithmic reverb, following the structure of Freeverb: parallel
+//! lowpass-feedback comb filters followed by series allpass filters.
+//!
+//! Control inputs (all optional): room size (0 to 1), damping (0 to 1),
+//! pre-delay in seconds, mix (0 = dry, 1 = wet).
+//!
+//! The input is mono; outputs are left and right buffers.
+
+use module::{Module, Buffer};
+
+use super::delay::DelayLine;
+
+// Tunings for 44.1kHz, from Freeverb.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+
+const FIXED_GAIN: f32 = 0.015;
+const SCALE_ROOM: f32 = 0.28;
+const OFFSET_ROOM: f32 = 0.7;
+const SCALE_DAMP: f32 = 0.4;
+const SCALE_WET: f32 = 3.0;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+const MAX_PRE_DELAY: f32 = 0.5;  // in seconds
+
+struct Comb {
+    buf: Box<[f32]>,
+    pos: usize,
+    filter_store: f32,
+}
+
+struct Allpass {
+    buf: Box<[f32]>,
+    pos: usize,
+}
+
+impl Comb {
+    fn new(len: usize) -> Comb {
+        Comb { buf: vec![0.0; len].into_boxed_slice(), pos: 0, filter_store: 0.0 }
+    }
+
+    fn process(&mut self, x: f32, feedback: f32, damp: f32) -> f32 {
+        let y = self.buf[self.pos];
+        self.filter_store = y + (self.filter_store - y) * damp;
+        self.buf[self.pos] = x + self.filter_store * feedback;
+        self.pos += 1;
+        if self.pos == self.buf.len() {
+            self.pos = 0;
+        }
+        y
+    }
+}
+
+impl Allpass {
+    fn new(len: usize) -> Allpass {
+        Allpass { buf: vec![0.0; len].into_boxed_slice(), pos: 0 }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let bufout = self.buf[self.pos];
+        self.buf[self.pos] = x + bufout * ALLPASS_FEEDBACK;
+        self.pos += 1;
+        if self.pos == self.buf.len() {
+            self.pos = 0;
+        }
+        bufout - x
+    }
+}
+
+// One channel of the reverb network.
+struct Channel {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Channel {
+    fn new(scale: f32, spread: usize) -> Channel {
+        let len = |tuning: usize| (((tuning + spread) as f32 * scale) as usize).max(1);
+        Channel {
+            combs: COMB_TUNING.iter().map(|&t| Comb::new(len(t))).collect(),
+            allpasses: ALLPASS_TUNING.iter().map(|&t| Allpass::new(len(t))).collect(),
+        }
+    }
+
+    fn process(&mut self, x: f32, feedback: f32, damp: f32) -> f32 {
+        let mut y = 0.0;
+        for comb in &mut self.combs {
+            y += comb.process(x, feedback, damp);
+        }
+        for allpass in &mut self.allpasses {
+            y = allpass.process(y);
+        }
+        y
+    }
+}
+
+pub struct Reverb {
+    sample_rate: f32,
+    pre_delay: DelayLine,
+    left: Channel,
+    right: Channel,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f32) -> Reverb {
+        let scale = sample_rate / 44_100.0;
+        Reverb {
+            sample_rate,
+            pre_delay: DelayLine::new((MAX_PRE_DELAY * sample_rate) as usize),
+            left: Channel::new(scale, 0),
+            right: Channel::new(scale, STEREO_SPREAD),
+        }
+    }
+}
+
+impl Module for Reverb {
+    fn n_bufs_out(&self) -> usize { 2 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let ctrl = |ix: usize, default: f32| control_in.get(ix).cloned().unwrap_or(default);
+        let feedback = ctrl(0, 0.5).clamp(0.0, 1.0) * SCALE_ROOM + OFFSET_ROOM;
+        let damp = ctrl(1, 0.5).clamp(0.0, 1.0) * SCALE_DAMP;
+        let pre_delay = ctrl(2, 0.0) * self.sample_rate;
+        let mix = ctrl(3, 0.3);
+        let inb = buf_in[0].get();
+        let (out_l, out_r) = buf_out.split_at_mut(1);
+        let out_l = out_l[0].get_mut();
+        let out_r = out_r[0].get_mut();
+        for i in 0..inb.len() {
+            let x = inb[i];
+            let wet_in = if pre_delay < 2.0 {
+                x
+            } else {
+                self.pre_delay.read(pre_delay)
+            };
+            self.pre_delay.write(x);
+            let wet_in = wet_in * FIXED_GAIN;
+            let wet_l = self.left.process(wet_in, feedback, damp) * SCALE_WET;
+            let wet_r = self.right.process(wet_in, feedback, damp) * SCALE_WET;
+            out_l[i] = x + mix * (wet_l - x);
+            out_r[i] = x + mix * (wet_r - x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    // The left and right output for an impulse, over `n_chunks` chunks.
+    fn impulse_response(controls: &[f32], n_chunks: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        for chunk in 0..n_chunks {
+            let mut inb = Buffer::default();
+            if chunk == 0 {
+                inb.get_mut()[0] = 1.0;
+            }
+            let mut out = [Buffer::default(), Buffer::default()];
+            reverb.process(controls, &mut [], &[&inb], &mut out);
+            left.extend_from_slice(out[0].get());
+            right.extend_from_slice(out[1].get());
+        }
+        (left, right)
+    }
+
+    fn onset(out: &[f32]) -> usize {
+        out.iter().position(|&x| x.abs() > 1e-6).unwrap()
+    }
+
+    fn energy(out: &[f32]) -> f32 {
+        out.iter().map(|&x| x * x).sum()
+    }
+
+    #[test]
+    fn dry_passes_through() {
+        let (left, right) = impulse_response(&[0.5, 0.5, 0.0, 0.0], 100);
+        assert_eq!(left[0], 1.0);
+        assert!(left[1..].iter().chain(&right[1..]).all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn wet_tail() {
+        // one second
+        let (left, right) = impulse_response(&[0.5, 0.5, 0.0, 1.0], 1378);
+        // nothing comes out before the shortest comb's delay
+        assert!(onset(&left) >= COMB_TUNING[0]);
+        assert!(left != right);
+        // and the tail decays
+        assert!(energy(&left[22_050..]) < 0.1 * energy(&left[..22_050]));
+    }
+
+    #[test]
+    fn room_size_and_pre_delay() {
+        let (small, _) = impulse_response(&[0.0, 0.5, 0.0, 1.0], 1378);
+        let (large, _) = impulse_response(&[1.0, 0.5, 0.0, 1.0], 1378);
+        assert!(energy(&large[22_050..]) > 10.0 * energy(&small[22_050..]));
+        // 0.1s of pre-delay
+        let (delayed, _) = impulse_response(&[0.5, 0.5, 0.1, 1.0], 1378);
+        assert_eq!(onset(&delayed), onset(&small) + 4410);
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A sample playback module.
+//!
+//! The sample is decoded on the non-realtime side and shared by reference, so
+//! any number of samplers can play it; the module never allocates.
+//!
+//! Control inputs: pitch (log2 of Hz, usually from `NotePitch`), level
+//! (optional, log2 of gain). The sample plays at its original speed when the
+//! pitch equals the root pitch given at construction.
+//!
+//! Start, end and loop points are in frames of the sample. Playback is
+//! interpolated with a 4-point Hermite spline.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use module::{Module, Buffer};
+use wav::Wav;
+
+/// Decoded mono audio, at its own sample rate.
+pub struct Sample {
+    pub sample_rate: f32,
+    pub data: Box<[f32]>,
+}
+
+impl Sample {
+    pub fn new(sample_rate: f32, data: Vec<f32>) -> Sample {
+        Sample { sample_rate, data: data.into_boxed_slice() }
+    }
+
+    /// Use a decoded WAV file, mixing it down to mono.
+    pub fn from_wav(wav: &Wav) -> Sample {
+        Sample::new(wav.sample_rate as f32, wav.to_mono())
+    }
+
+    /// Load a WAV file, mixing it down to mono.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Sample> {
+        Ok(Sample::from_wav(&Wav::load(path)?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// What happens at the loop points and at note-off. The names follow the
+/// SFZ `loop_mode` opcode.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplerMode {
+    /// Play to the end, fading out on note-off.
+    NoLoop,
+    /// Play to the end, ignoring note-off.
+    OneShot,
+    /// Loop until the note has faded out after note-off.
+    LoopContinuous,
+    /// Loop while the note is held, then play on to the end while fading out.
+    LoopSustain,
+}
+
+impl SamplerMode {
+    /// Decode a mode from a parameter value, as sent by `set_param`.
+    pub fn from_param(val: f32) -> SamplerMode {
+        match val as i32 {
+            1 => SamplerMode::OneShot,
+            2 => SamplerMode::LoopContinuous,
+            3 => SamplerMode::LoopSustain,
+            _ => SamplerMode::NoLoop,
+        }
+    }
+}
+
+pub struct Sampler {
+    sample: Arc<Sample>,
+    root: f32,  // log2 Hz
+    rate_ratio: f32,  // sample rate / engine sample rate
+    sample_period: f32,
+    mode: SamplerMode,
+    start: usize,
+    end: usize,
+    loop_start: usize,
+    loop_end: usize,
+    vel_sens: f32,
+    release: f32,  // in seconds
+    gain: f32,  // log2
+    key_range: (f32, f32),
+    vel_range: (f32, f32),
+
+    pos: f64,  // in frames; f64 so long samples don't lose precision
+    playing: bool,
+    gate: bool,
+    vel_gain: f32,
+    fade: f32,
+}
+
+impl Sampler {
+    /// Parameter index for the start point, in frames.
+    pub const PARAM_START: usize = 0;
+
+    /// Parameter index for the end point, in frames.
+    pub const PARAM_END: usize = 1;
+
+    /// Parameter index for the loop start, in frames.
+    pub const PARAM_LOOP_START: usize = 2;
+
+    /// Parameter index for the loop end, in frames.
+    pub const PARAM_LOOP_END: usize = 3;
+
+    /// Parameter index for the mode (see `SamplerMode::from_param`).
+    pub const PARAM_MODE: usize = 4;
+
+    /// Parameter index for velocity sensitivity (0 to 1).
+    pub const PARAM_VEL_SENS: usize = 5;
+
+    /// Parameter index for the release time in seconds.
+    pub const PARAM_RELEASE: usize = 6;
+
+    /// Create a sampler that plays the whole sample once, with `root` (log2
+    /// of Hz) as its original pitch.
+    pub fn new(sample_rate: f32, sample: Arc<Sample>, root: f32) -> Sampler {
+        let len = sample.len();
+        Sampler {
+            rate_ratio: sample.sample_rate / sample_rate,
+            sample,
+            root,
+            sample_period: 1.0 / sample_rate,
+            mode: SamplerMode::NoLoop,
+            start: 0,
+            end: len,
+            loop_start: 0,
+            loop_end: len,
+            vel_sens: 1.0,
+            release: 0.01,
+            gain: 0.0,
+            key_range: (0.0, 127.0),
+            vel_range: (0.0, 127.0),
+            pos: 0.0,
+            playing: false,
+            gate: false,
+            vel_gain: 1.0,
+            fade: 1.0,
+        }
+    }
+
+    /// Set the start and end points. The end is exclusive, and clamped to the
+    /// length of the sample.
+    pub fn set_range(&mut self, start: usize, end: usize) {
+        self.start = start;
+        self.end = end.min(self.sample.len());
+    }
+
+    /// Set the mode and loop points. The loop end is exclusive.
+    pub fn set_loop(&mut self, mode: SamplerMode, loop_start: usize, loop_end: usize) {
+        self.mode = mode;
+        self.loop_start = loop_start;
+        self.loop_end = loop_end.min(self.sample.len());
+    }
+
+    /// Set how much velocity affects the level, from 0 (not at all) to 1
+    /// (level proportional to velocity).
+    pub fn set_velocity_sens(&mut self, vel_sens: f32) {
+        self.vel_sens = vel_sens;
+    }
+
+    /// Set the fade-out time after note-off, in seconds.
+    pub fn set_release(&mut self, release: f32) {
+        self.release = release;
+    }
+
+    /// Set a fixed gain, log2, applied along with the level input.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Only play notes within a range of MIDI note numbers (inclusive). Other
+    /// notes release the current one, as a new note would.
+    pub fn set_key_range(&mut self, lo: f32, hi: f32) {
+        self.key_range = (lo, hi);
+    }
+
+    /// Only play notes within a range of velocities (inclusive), as for
+    /// `set_key_range`.
+    pub fn set_vel_range(&mut self, lo: f32, hi: f32) {
+        self.vel_range = (lo, hi);
+    }
+
+    fn looping(&self) -> bool {
+        let valid = self.loop_start < self.loop_end;
+        match self.mode {
+            SamplerMode::LoopContinuous => valid,
+            SamplerMode::LoopSustain => valid && self.gate,
+            _ => false,
+        }
+    }
+
+    // 4-point Hermite interpolation at `pos`. Frames past the loop end wrap
+    // while looping; frames outside the sample are silence.
+    fn interp(&self, pos: f64) -> f32 {
+        let data = &self.sample.data;
+        let i = pos.floor() as isize;
+        let frac = (pos - i as f64) as f32;
+        let (loop_start, loop_end) = (self.loop_start as isize, self.loop_end as isize);
+        let looping = self.looping();
+        let get = |j: isize| {
+            let j = if looping && j >= loop_end { j - (loop_end - loop_start) } else { j };
+            if j >= 0 && (j as usize) < data.len() { data[j as usize] } else { 0.0 }
+        };
+        let (xm1, x0, x1, x2) = (get(i - 1), get(i), get(i + 1), get(i + 2));
+        let c1 = 0.5 * (x1 - xm1);
+        let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+        let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+        ((c3 * frac + c2) * frac + c1) * frac + x0
+    }
+}
+
+impl Module for Sampler {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_sampler) = old.to_any().downcast_ref::<Sampler>() {
+            if Arc::ptr_eq(&self.sample, &old_sampler.sample) {
+                self.pos = old_sampler.pos;
+                self.playing = old_sampler.playing;
+                self.gate = old_sampler.gate;
+                self.vel_gain = old_sampler.vel_gain;
+                self.fade = old_sampler.fade;
+            }
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let out = buf_out[0].get_mut();
+        if !self.playing {
+            out.iter_mut().for_each(|y| *y = 0.0);
+            return;
+        }
+        let step = ((control_in[0] - self.root).exp2() * self.rate_ratio) as f64;
+        let level = (control_in.get(1).cloned().unwrap_or(0.0) + self.gain).exp2() * self.vel_gain;
+        let fade_step = self.sample_period / self.release.max(1e-4);
+        let releasing = !self.gate && self.mode != SamplerMode::OneShot;
+        for y in out.iter_mut() {
+            if !self.playing {
+                *y = 0.0;
+                continue;
+            }
+            *y = self.interp(self.pos) * level * self.fade;
+            self.pos += step;
+            if self.looping() && self.pos >= self.loop_end as f64 {
+                self.pos -= (self.loop_end - self.loop_start) as f64;
+            }
+            if releasing {
+                self.fade -= fade_step;
+            }
+            if self.pos >= self.end as f64 || self.fade <= 0.0 {
+                self.playing = false;
+            }
+        }
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        let frames = val.max(0.0) as usize;
+        match param_ix {
+            Sampler::PARAM_START => self.start = frames,
+            Sampler::PARAM_END => self.end = frames.min(self.sample.len()),
+            Sampler::PARAM_LOOP_START => self.loop_start = frames,
+            Sampler::PARAM_LOOP_END => self.loop_end = frames.min(self.sample.len()),
+            Sampler::PARAM_MODE => self.mode = SamplerMode::from_param(val),
+            Sampler::PARAM_VEL_SENS => self.vel_sens = val,
+            Sampler::PARAM_RELEASE => self.release = val,
+            _ => (),
+        }
+    }
+
+    fn receives_notes(&self) -> bool { true }
+
+    fn handle_note(&mut self, midi_num: f32, velocity: f32, on: bool) {
+        let in_range = |x: f32, (lo, hi): (f32, f32)| x >= lo && x <= hi;
+        let on = on && in_range(midi_num, self.key_range) && in_range(velocity, self.vel_range);
+        if on {
+            self.vel_gain = 1.0 - self.vel_sens + self.vel_sens * velocity * (1.0 / 127.0);
+            self.pos = self.start as f64;
+            self.playing = self.start < self.end;
+            self.fade = 1.0;
+        }
+        self.gate = on;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, Buffer};
+    use super::*;
+
+    // At this rate, with the sample at the same rate, a pitch of 0 plays one
+    // frame per output sample.
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    // Frame i of the sample is i + 1, so silence is easy to tell apart.
+    fn sampler(mode: SamplerMode, release: f32) -> Sampler {
+        let data = (0..100).map(|i| i as f32 + 1.0).collect();
+        let sample = Arc::new(Sample::new(SAMPLE_RATE, data));
+        let mut sampler = Sampler::new(SAMPLE_RATE, sample, 0.0);
+        sampler.set_loop(mode, 10, 20);
+        sampler.set_velocity_sens(0.0);
+        sampler.set_release(release);
+        sampler
+    }
+
+    fn run(sampler: &mut Sampler, pitch: f32, n_chunks: usize) -> Vec<f32> {
+        let mut result = Vec::new();
+        for _ in 0..n_chunks {
+            let mut out = [Buffer::default()];
+            sampler.process(&[pitch], &mut [], &[], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    #[test]
+    fn loop_wraps() {
+        let mut sampler = sampler(SamplerMode::LoopContinuous, 0.01);
+        sampler.handle_note(60.0, 100.0, true);
+        let out = run(&mut sampler, 0.0, 2);
+        assert_eq!(&out[..3], &[1.0, 2.0, 3.0]);
+        assert_eq!(out[19], 20.0);
+        assert_eq!(out[20], 11.0);
+        assert_eq!(out[40], 11.0);
+    }
+
+    #[test]
+    fn repitch_steps_through_sample() {
+        let mut sampler = sampler(SamplerMode::NoLoop, 0.01);
+        sampler.handle_note(60.0, 100.0, true);
+        // an octave up plays every other frame
+        let out = run(&mut sampler, 1.0, 2);
+        assert_eq!(&out[..4], &[1.0, 3.0, 5.0, 7.0]);
+        // and stops at the end of the sample
+        assert_eq!(out[49], 99.0);
+        assert!(out[50..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn modes_at_note_off() {
+        // (mode, release, whether the note still sounds a chunk after
+        // note-off, highest value played)
+        let cases = [
+            (SamplerMode::NoLoop, 0.01, false, 65.0),
+            (SamplerMode::OneShot, 0.01, true, 100.0),
+            (SamplerMode::LoopContinuous, 10.0, true, 20.0),
+            (SamplerMode::LoopSustain, 10.0, true, 78.0),
+        ];
+        for &(mode, release, sounding, max) in &cases {
+            let mut sampler = sampler(mode, release);
+            sampler.handle_note(60.0, 100.0, true);
+            run(&mut sampler, 0.0, 2);
+            sampler.handle_note(60.0, 0.0, false);
+            let out = run(&mut sampler, 0.0, 1);
+            let tail = run(&mut sampler, 0.0, 1);
+            assert_eq!(tail.iter().any(|&x| x != 0.0), sounding, "{:?}", mode);
+            let top = out.iter().chain(&tail).fold(0.0f32, |a, &x| a.max(x));
+            assert!(top <= max && top > max - 1.0, "{:?} reached {}", mode, top);
+        }
+    }
+
+    #[test]
+    fn key_and_velocity_ranges() {
+        let mut sampler = sampler(SamplerMode::NoLoop, 0.01);
+        sampler.set_key_range(60.0, 64.0);
+        sampler.set_vel_range(0.0, 63.0);
+        sampler.handle_note(59.0, 50.0, true);
+        assert!(run(&mut sampler, 0.0, 1).iter().all(|&x| x == 0.0));
+        sampler.handle_note(62.0, 100.0, true);
+        assert!(run(&mut sampler, 0.0, 1).iter().all(|&x| x == 0.0));
+        sampler.handle_note(64.0, 63.0, true);
+        assert_eq!(run(&mut sampler, 0.0, 1)[0], 1.0);
+    }
+}
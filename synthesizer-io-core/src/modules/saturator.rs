@@ -0,0 +1,235 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A waveshaper for saturation and distortion, using fast sigmoid
+//! approximations (see `benches/sigmoid.rs` for their relative speed).
+//!
+//! Control inputs (optional): drive (log2 of input gain), bias. Bias is added
+//! after drive, making the curve asymmetric (adding even harmonics). The
+//! static offset is subtracted, so silence in gives silence out.
+//!
+//! To reduce aliasing, the curve can be evaluated at 2x, 4x or 8x
+//! oversampling.
+
+use std::f64::consts;
+
+use module::{Module, Buffer};
+use oversample::Oversampler;
+
+/// The transfer curve of a `Saturator`. All have unit slope at the origin.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SaturatorCurve {
+    /// x / sqrt(1 + x^2)
+    Algebraic,
+    /// Approximation of tanh, max error 2e-4.
+    Tanh5,
+    /// Approximation of erf(x * sqrt(pi) / 2).
+    Erf7,
+    /// Approximation of tanh from https://arxiv.org/pdf/1702.07825.pdf, max
+    /// error ~1.5e-3.
+    TanhEtilde,
+    /// Approximation of erf from Abramowitz and Stegun, max error 5e-4.
+    ErfAs,
+    HardClip,
+}
+
+impl SaturatorCurve {
+    /// Decode a curve from a parameter value, as sent by `set_param`.
+    pub fn from_param(val: f32) -> SaturatorCurve {
+        match val as i32 {
+            1 => SaturatorCurve::Tanh5,
+            2 => SaturatorCurve::Erf7,
+            3 => SaturatorCurve::TanhEtilde,
+            4 => SaturatorCurve::ErfAs,
+            5 => SaturatorCurve::HardClip,
+            _ => SaturatorCurve::Algebraic,
+        }
+    }
+
+    pub fn eval(self, x: f32) -> f32 {
+        match self {
+            SaturatorCurve::Algebraic => x / (1.0 + x * x).sqrt(),
+            SaturatorCurve::Tanh5 => {
+                let xx = x * x;
+                let x = x + (0.16489087 + 0.00985468 * xx) * (x * xx);
+                x / (1.0 + x * x).sqrt()
+            }
+            SaturatorCurve::Erf7 => {
+                let xx = x * x;
+                let x = x + (0.24295 + (0.03395 + 0.0104 * xx) * xx) * (x * xx);
+                x / (1.0 + x * x).sqrt()
+            }
+            SaturatorCurve::TanhEtilde => {
+                let xx = x * x;
+                let etilde = 1.0 + x.abs() + (0.5658 + 0.143 * xx) * xx;
+                let erecip = etilde.recip();
+                x.signum() * (etilde - erecip) / (etilde + erecip)
+            }
+            SaturatorCurve::ErfAs => {
+                // Scaled for unit slope at the origin, like Erf7.
+                let a = x.abs() * (consts::PI.sqrt() * 0.5) as f32;
+                let b = 1.0 + (0.278393 + (0.230389 + (0.000972 + 0.078108 * a) * a) * a) * a;
+                let b2 = b * b;
+                let b4 = b2 * b2;
+                x.signum() * (1.0 - b4.recip())
+            }
+            SaturatorCurve::HardClip => x.clamp(-1.0, 1.0),
+        }
+    }
+}
+
+pub struct Saturator {
+    curve: SaturatorCurve,
+    oversampler: Oversampler,
+}
+
+impl Saturator {
+    /// Parameter index for selecting the curve (see `SaturatorCurve::from_param`).
+    pub const PARAM_CURVE: usize = 0;
+
+    /// Create a saturator. The oversampling factor must be 1, 2, 4 or 8.
+    pub fn new(curve: SaturatorCurve, oversample: usize) -> Saturator {
+        Saturator {
+            curve,
+            oversampler: Oversampler::new(oversample),
+        }
+    }
+}
+
+impl Module for Saturator {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let drive = control_in.first().cloned().unwrap_or(0.0).exp2();
+        let bias = control_in.get(1).cloned().unwrap_or(0.0);
+        let curve = self.curve;
+        let dc = curve.eval(bias);
+        let shape = |x: f32| curve.eval(x * drive + bias) - dc;
+        self.oversampler.process(buf_in[0], &mut buf_out[0], |buf| {
+            for x in buf.iter_mut() {
+                *x = shape(*x);
+            }
+        });
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        if param_ix == Saturator::PARAM_CURVE {
+            self.curve = SaturatorCurve::from_param(val);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fft::{Complex, Fft};
+    use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+    use super::*;
+
+    const SIZE: usize = 4096;
+    // Test tone frequency, in bins (about 5kHz at 44.1kHz).
+    const F_BIN: usize = 465;
+
+    const CURVES: [SaturatorCurve; 6] = [
+        SaturatorCurve::Algebraic, SaturatorCurve::Tanh5, SaturatorCurve::Erf7,
+        SaturatorCurve::TanhEtilde, SaturatorCurve::ErfAs, SaturatorCurve::HardClip,
+    ];
+
+    // Saturate a sine wave and return the power spectrum of the result.
+    fn spectrum(curve: SaturatorCurve, oversample: usize, drive: f32, bias: f32) -> Vec<f32> {
+        let mut sat = Saturator::new(curve, oversample);
+        let mut audio = Vec::new();
+        let mut i = 0;
+        // run extra chunks first to get past the filter startup
+        while audio.len() < SIZE + 4 * N_SAMPLES_PER_CHUNK {
+            let mut inb = Buffer::default();
+            for x in inb.get_mut().iter_mut() {
+                let th = 2.0 * consts::PI * (F_BIN * i) as f64 / SIZE as f64;
+                *x = th.sin() as f32;
+                i += 1;
+            }
+            let mut out = [Buffer::default()];
+            sat.process(&[drive, bias], &mut [], &[&inb], &mut out);
+            audio.extend_from_slice(out[0].get());
+        }
+        let audio = &audio[audio.len() - SIZE..];
+        // Blackman-Harris window
+        let mut buf: Vec<Complex> = audio.iter().enumerate().map(|(i, &x)| {
+            let w = 2.0 * consts::PI * i as f64 / SIZE as f64;
+            let win = 0.35875 - 0.48829 * w.cos() + 0.14128 * (2.0 * w).cos()
+                - 0.01168 * (3.0 * w).cos();
+            Complex::new(x * win as f32, 0.0)
+        }).collect();
+        Fft::new(SIZE).forward(&mut buf);
+        buf[..SIZE / 2].iter().map(|c| c.norm_sqr()).collect()
+    }
+
+    // Power in the bins around `bin`.
+    fn power_near(spect: &[f32], bin: usize) -> f32 {
+        spect[bin - 4..bin + 5].iter().sum()
+    }
+
+    // Power in all bins that aren't near DC or an unaliased harmonic, in dB
+    // relative to the fundamental.
+    fn alias_db(spect: &[f32]) -> f32 {
+        let mut alias = 0.0;
+        for (bin, p) in spect.iter().enumerate().skip(5) {
+            let harmonic = (bin + F_BIN / 2) / F_BIN;
+            if harmonic == 0 || (bin as i64 - (harmonic * F_BIN) as i64).abs() > 4 {
+                alias += p;
+            }
+        }
+        10.0 * (alias / power_near(spect, F_BIN)).log10()
+    }
+
+    #[test]
+    fn curves_match_reference() {
+        for i in -400..400 {
+            let x = i as f32 * 0.01;
+            let tanh = x.tanh();
+            assert!((SaturatorCurve::Tanh5.eval(x) - tanh).abs() < 3e-4);
+            assert!((SaturatorCurve::TanhEtilde.eval(x) - tanh).abs() < 2e-3);
+            assert!((SaturatorCurve::Erf7.eval(x) - SaturatorCurve::ErfAs.eval(x)).abs() < 2e-3);
+        }
+    }
+
+    #[test]
+    fn symmetric_curves_make_odd_harmonics() {
+        for &curve in &CURVES {
+            let spect = spectrum(curve, 4, 2.0, 0.0);
+            let fund = power_near(&spect, F_BIN);
+            assert!(power_near(&spect, 3 * F_BIN) > fund * 1e-4, "{:?}: no 3rd harmonic", curve);
+            assert!(power_near(&spect, 2 * F_BIN) < fund * 1e-6, "{:?}: 2nd harmonic", curve);
+        }
+    }
+
+    #[test]
+    fn bias_makes_even_harmonics() {
+        let spect = spectrum(SaturatorCurve::Tanh5, 4, 2.0, 0.5);
+        let fund = power_near(&spect, F_BIN);
+        assert!(power_near(&spect, 2 * F_BIN) > fund * 1e-3);
+    }
+
+    #[test]
+    fn oversampling_reduces_aliasing() {
+        for &curve in &CURVES {
+            let alias1 = alias_db(&spectrum(curve, 1, 3.0, 0.0));
+            let alias2 = alias_db(&spectrum(curve, 2, 3.0, 0.0));
+            let alias4 = alias_db(&spectrum(curve, 4, 3.0, 0.0));
+            assert!(alias2 < alias1 - 10.0, "{:?}: 2x {} vs 1x {}", curve, alias2, alias1);
+            assert!(alias4 < alias2 - 10.0, "{:?}: 4x {} vs 2x {}", curve, alias4, alias2);
+        }
+    }
+}
@@ -96,6 +96,12 @@ fn compute(tab_ix: usize, phasefrac: f32) -> f32 {
 impl Module for Saw {
     fn n_bufs_out(&self) -> usize { 1 }
 
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_saw) = old.to_any().downcast_ref::<Saw>() {
+            self.phase = old_saw.phase;
+        }
+    }
+
     fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
         _buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
@@ -0,0 +1,78 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A module that applies a scale and offset to a control signal:
+//! out = in * scale + offset.
+
+use module::{Module, Buffer, Ports};
+
+pub struct ScaleOffset {
+    scale: f32,
+    offset: f32,
+}
+
+impl ScaleOffset {
+    /// Parameter index for the scale.
+    pub const PARAM_SCALE: usize = 0;
+
+    /// Parameter index for the offset.
+    pub const PARAM_OFFSET: usize = 1;
+
+    pub const PORTS: Ports = Ports {
+        buf_in: &[],
+        ctrl_in: &["in"],
+        buf_out: &[],
+        ctrl_out: &["out"],
+    };
+
+    pub fn new(scale: f32, offset: f32) -> ScaleOffset {
+        ScaleOffset { scale, offset }
+    }
+}
+
+impl Module for ScaleOffset {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        control_out[0] = control_in[0] * self.scale + self.offset;
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            ScaleOffset::PARAM_SCALE => self.scale = val,
+            ScaleOffset::PARAM_OFFSET => self.offset = val,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::Module;
+    use super::*;
+
+    #[test]
+    fn scale_and_offset() {
+        let mut module = ScaleOffset::new(2.0, -1.0);
+        let mut out = [0.0];
+        module.process(&[3.0], &mut out, &[], &mut []);
+        assert_eq!(out[0], 5.0);
+        module.set_param(ScaleOffset::PARAM_SCALE, -0.5, 0);
+        module.set_param(ScaleOffset::PARAM_OFFSET, 0.25, 0);
+        module.process(&[3.0], &mut out, &[], &mut []);
+        assert_eq!(out[0], -1.25);
+    }
+}
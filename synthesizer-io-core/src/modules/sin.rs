@@ -37,6 +37,16 @@ lazy_static! {
     };
 }
 
+/// Look up sin(2πx) in the shared table, with linear interpolation.
+pub fn sin_lookup(x: f32) -> f32 {
+    let tab = SINTAB.deref();
+    let p = (x - x.floor()) * N_SAMPLES as f32;
+    let ix = p as usize;
+    let frac = p - ix as f32;
+    let ix = ix & (N_SAMPLES - 1);
+    tab[ix] + (tab[ix + 1] - tab[ix]) * frac
+}
+
 pub struct Sin {
     sr_offset: f32,
     phase: f32,
@@ -13,7 +13,13 @@
 // limitations under the License.
 
 //! A module that smooths parameters (optimized for midi controllers).
+//!
+//! It can also follow an automation lane, which takes over the output (with
+//! no smoothing) up to the lane's last breakpoint.
 
+use std::mem;
+
+use automation::Lane;
 use module::{Module, Buffer};
 
 pub struct SmoothCtrl {
@@ -24,6 +30,7 @@ pub struct SmoothCtrl {
     inp: f32,  // raw, unsmoothed value
     mid: f32,  // result of 1 pole of lowpass filtering
     out: f32,  // result of 2 poles of lowpass filtering
+    lane: Lane,
 }
 
 impl SmoothCtrl {
@@ -36,6 +43,7 @@ impl SmoothCtrl {
             inp: value,
             mid: value,
             out: value,
+            lane: Lane::default(),
         }
     }
 }
@@ -43,6 +51,20 @@ impl SmoothCtrl {
 impl Module for SmoothCtrl {
     fn n_ctrl_out(&self) -> usize { 1 }
 
+    // Keeps the new target value, so the output glides to it from the old
+    // module's output.
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_mut::<SmoothCtrl>() {
+            mem::swap(&mut self.lane, &mut old.lane);
+            self.rate = old.rate;
+            self.rategoal = old.rategoal;
+            self.t = old.t;
+            self.last_set_t = old.last_set_t;
+            self.mid = old.mid;
+            self.out = old.out;
+        }
+    }
+
     // maybe empty impl belongs in Module?
     fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
         _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
@@ -52,10 +74,22 @@ impl Module for SmoothCtrl {
     fn process_ts(&mut self, _control_in: &[f32], control_out: &mut [f32],
         _buf_in: &[&Buffer], _buf_out: &mut [Buffer], timestamp: u64)
     {
+        if self.lane.end_time().map_or(false, |end| timestamp <= end) {
+            // jump to the lane's value, so smoothing continues from there
+            let value = self.lane.value_at(timestamp).unwrap();
+            self.inp = value;
+            self.mid = value;
+            self.out = value;
+            self.t = timestamp;
+        }
         self.advance_to(timestamp);
         control_out[0] = self.out;
     }
 
+    fn swap_lane(&mut self, lane: &mut Lane) {
+        mem::swap(&mut self.lane, lane);
+    }
+
     fn set_param(&mut self, _param_ix: usize, val: f32, timestamp: u64) {
         self.advance_to(timestamp);
         if timestamp > self.last_set_t {
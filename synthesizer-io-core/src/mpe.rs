@@ -0,0 +1,99 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration for MPE (MIDI Polyphonic Expression).
+//!
+//! An MPE zone has a master channel, which carries messages for the whole
+//! instrument, and a range of member channels, each of which carries one
+//! note at a time along with its own pitch bend, pressure and timbre.
+//! Channels are numbered from 0 here, as they appear in status bytes.
+
+use std::ops::Range;
+
+/// Which end of the channel range a zone occupies.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ZoneKind {
+    /// Master channel 1 (0 here), members counting up from channel 2.
+    Lower,
+    /// Master channel 16 (15 here), members counting down from channel 15.
+    Upper,
+}
+
+#[derive(Clone, Debug)]
+pub struct MpeZone {
+    pub kind: ZoneKind,
+    /// Number of member channels, 1 to 15.
+    pub n_members: u8,
+    /// Pitch bend range of the member channels, in semitones.
+    pub bend_range: f32,
+    /// Pitch bend range of the master channel, in semitones.
+    pub master_bend_range: f32,
+}
+
+impl MpeZone {
+    /// A lower zone with the default bend ranges (48 semitones per note, 2
+    /// on the master channel).
+    pub fn lower(n_members: u8) -> MpeZone {
+        MpeZone::new(ZoneKind::Lower, n_members)
+    }
+
+    /// An upper zone with the default bend ranges.
+    pub fn upper(n_members: u8) -> MpeZone {
+        MpeZone::new(ZoneKind::Upper, n_members)
+    }
+
+    fn new(kind: ZoneKind, n_members: u8) -> MpeZone {
+        assert!((1..=15).contains(&n_members), "an MPE zone has 1 to 15 member channels");
+        MpeZone {
+            kind,
+            n_members,
+            bend_range: 48.0,
+            master_bend_range: 2.0,
+        }
+    }
+
+    pub fn master_channel(&self) -> u8 {
+        match self.kind {
+            ZoneKind::Lower => 0,
+            ZoneKind::Upper => 15,
+        }
+    }
+
+    pub fn member_channels(&self) -> Range<u8> {
+        match self.kind {
+            ZoneKind::Lower => 1..1 + self.n_members,
+            ZoneKind::Upper => 15 - self.n_members..15,
+        }
+    }
+
+    pub fn is_member(&self, channel: u8) -> bool {
+        self.member_channels().contains(&channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn member_channels() {
+        let lower = MpeZone::lower(3);
+        assert_eq!((lower.master_channel(), lower.member_channels()), (0, 1..4));
+        let upper = MpeZone::upper(3);
+        assert_eq!((upper.master_channel(), upper.member_channels()), (15, 12..15));
+        assert!(upper.is_member(12) && upper.is_member(14));
+        assert!(!upper.is_member(11) && !upper.is_member(15));
+        assert_eq!(MpeZone::upper(15).member_channels(), 0..15);
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal OSC (Open Sound Control) implementation, for controlling the
+//! engine from other processes over UDP.
+//!
+//! Messages with int, float and string arguments are supported, as are
+//! bundles. Bundle time tags are ignored; messages take the time at which
+//! they arrive.
+//!
+//! See http://opensoundcontrol.org/spec-1_0 for the format.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use time;
+
+// Larger than any UDP datagram.
+const MAX_PACKET: usize = 65536;
+
+// How often the listener thread checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+/// An engine command, decoded from an OSC message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OscCommand {
+    /// `/node/<id>/param/<ix> value`
+    SetParam { node: usize, param_ix: usize, val: f32 },
+    /// `/note note velocity`, with velocity 0 for note off.
+    Note { note: u8, velocity: u8 },
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn pad4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn read_i32(data: &[u8]) -> io::Result<i32> {
+    if data.len() < 4 {
+        return Err(invalid("truncated OSC packet"));
+    }
+    Ok((data[0] as i32) << 24 | (data[1] as i32) << 16 | (data[2] as i32) << 8 | data[3] as i32)
+}
+
+// Read a NUL-terminated, padded string, returning it and the padded length.
+fn read_str(data: &[u8]) -> io::Result<(&str, usize)> {
+    let len = data.iter().position(|&b| b == 0).ok_or_else(|| invalid("unterminated OSC string"))?;
+    let s = ::std::str::from_utf8(&data[..len]).map_err(|_| invalid("OSC string not UTF-8"))?;
+    Ok((s, pad4(len + 1).min(data.len())))
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    let len = pad4(s.len() + 1);
+    out.resize(out.len() + len - s.len(), 0);
+}
+
+/// Decode an OSC packet, which is either a message or a bundle. The messages
+/// in a bundle, including nested bundles, are returned in order.
+pub fn parse_packet(data: &[u8]) -> io::Result<Vec<OscMessage>> {
+    let mut messages = Vec::new();
+    parse_into(data, &mut messages)?;
+    Ok(messages)
+}
+
+fn parse_into(data: &[u8], messages: &mut Vec<OscMessage>) -> io::Result<()> {
+    if data.starts_with(b"#bundle\0") {
+        // skip the time tag
+        let mut pos = 16;
+        while pos < data.len() {
+            let size = read_i32(&data[pos..])?;
+            let start = pos + 4;
+            if size < 0 || start + size as usize > data.len() {
+                return Err(invalid("bad OSC bundle element size"));
+            }
+            parse_into(&data[start..start + size as usize], messages)?;
+            pos = start + size as usize;
+        }
+        Ok(())
+    } else {
+        messages.push(OscMessage::parse(data)?);
+        Ok(())
+    }
+}
+
+impl OscMessage {
+    pub fn new(addr: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage { addr: addr.to_string(), args }
+    }
+
+    /// Decode a single message.
+    pub fn parse(data: &[u8]) -> io::Result<OscMessage> {
+        let (addr, mut pos) = read_str(data)?;
+        if !addr.starts_with('/') {
+            return Err(invalid("OSC address must start with '/'"));
+        }
+        let mut args = Vec::new();
+        // some old implementations omit the type tag string when there are
+        // no arguments
+        if pos < data.len() {
+            let (tags, len) = read_str(&data[pos..])?;
+            pos += len;
+            if !tags.starts_with(',') {
+                return Err(invalid("missing OSC type tags"));
+            }
+            for tag in tags[1..].chars() {
+                let rest = &data[pos..];
+                match tag {
+                    'i' => {
+                        args.push(OscArg::Int(read_i32(rest)?));
+                        pos += 4;
+                    }
+                    'f' => {
+                        args.push(OscArg::Float(f32::from_bits(read_i32(rest)? as u32)));
+                        pos += 4;
+                    }
+                    's' => {
+                        let (s, len) = read_str(rest)?;
+                        args.push(OscArg::Str(s.to_string()));
+                        pos += len;
+                    }
+                    _ => return Err(invalid("unsupported OSC argument type")),
+                }
+            }
+        }
+        Ok(OscMessage { addr: addr.to_string(), args })
+    }
+
+    /// Encode the message as an OSC packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_str(&mut out, &self.addr);
+        let mut tags = String::from(",");
+        for arg in &self.args {
+            tags.push(match *arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Str(_) => 's',
+            });
+        }
+        write_str(&mut out, &tags);
+        for arg in &self.args {
+            match *arg {
+                OscArg::Int(i) => out.extend_from_slice(&i.to_be_bytes()),
+                OscArg::Float(f) => out.extend_from_slice(&f.to_bits().to_be_bytes()),
+                OscArg::Str(ref s) => write_str(&mut out, s),
+            }
+        }
+        out
+    }
+}
+
+impl OscArg {
+    /// The argument as a number, if it is one.
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(i) => Some(i as f32),
+            OscArg::Float(f) => Some(f),
+            OscArg::Str(_) => None,
+        }
+    }
+}
+
+impl OscCommand {
+    /// Decode the command for a message, or `None` if the address or
+    /// arguments aren't recognized.
+    pub fn from_message(msg: &OscMessage) -> Option<OscCommand> {
+        let parts: Vec<_> = msg.addr.split('/').collect();
+        let arg = |i: usize| msg.args.get(i).and_then(OscArg::as_f32);
+        match parts[..] {
+            ["", "node", node, "param", param_ix] => Some(OscCommand::SetParam {
+                node: node.parse().ok()?,
+                param_ix: param_ix.parse().ok()?,
+                val: arg(0)?,
+            }),
+            ["", "note"] => Some(OscCommand::Note {
+                note: arg(0)?.clamp(0.0, 127.0) as u8,
+                velocity: arg(1)?.clamp(0.0, 127.0) as u8,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A thread listening for OSC packets on a UDP socket. The thread stops when
+/// the listener is dropped.
+pub struct OscListener {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OscListener {
+    /// Bind a UDP socket and start listening. `handler` is called on the
+    /// listener thread for each message received, with the arrival time
+    /// from `time::precise_time_ns`, the same clock the engine uses.
+    /// Malformed packets are dropped.
+    pub fn spawn<A, F>(addr: A, mut handler: F) -> io::Result<OscListener>
+        where A: ToSocketAddrs, F: FnMut(OscMessage, u64) + Send + 'static
+    {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let addr = socket.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET];
+            while !thread_stop.load(Ordering::Relaxed) {
+                let len = match socket.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(_) => continue,
+                };
+                let ts = time::precise_time_ns();
+                match parse_packet(&buf[..len]) {
+                    Ok(messages) => for msg in messages {
+                        handler(msg, ts);
+                    },
+                    Err(e) => println!("bad OSC packet: {}", e),
+                }
+            }
+        });
+        Ok(OscListener { addr, stop, thread: Some(thread) })
+    }
+
+    /// The address the listener is bound to; useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for OscListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn roundtrip() {
+        let msg = OscMessage::new("/node/12/param/3",
+            vec![OscArg::Float(0.25), OscArg::Int(-7), OscArg::Str("abc".into())]);
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(OscMessage::parse(&bytes).unwrap(), msg);
+        assert_eq!(OscCommand::from_message(&msg),
+            Some(OscCommand::SetParam { node: 12, param_ix: 3, val: 0.25 }));
+    }
+
+    #[test]
+    fn bundle() {
+        let a = OscMessage::new("/note", vec![OscArg::Int(60), OscArg::Int(100)]);
+        let b = OscMessage::new("/note", vec![OscArg::Int(60), OscArg::Int(0)]);
+        let mut packet = b"#bundle\0".to_vec();
+        packet.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        for msg in &[&a, &b] {
+            let bytes = msg.to_bytes();
+            packet.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+            packet.extend_from_slice(&bytes);
+        }
+        assert_eq!(parse_packet(&packet).unwrap(), vec![a, b]);
+        assert!(parse_packet(&packet[..packet.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn listen_local() {
+        let (tx, rx) = mpsc::channel();
+        let listener = OscListener::spawn("127.0.0.1:0", move |msg, ts| {
+            let _ = tx.send((msg, ts));
+        }).unwrap();
+        let start = time::precise_time_ns();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let msg = OscMessage::new("/note", vec![OscArg::Int(64), OscArg::Float(90.0)]);
+        socket.send_to(&msg.to_bytes(), listener.local_addr()).unwrap();
+        let (received, ts) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received, msg);
+        assert!(ts >= start);
+        assert_eq!(OscCommand::from_message(&received),
+            Some(OscCommand::Note { note: 64, velocity: 90 }));
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Polyphase up/downsampling, so nonlinear modules can run their inner loop
+//! at a higher rate to control aliasing.
+//!
+//! Each 2x stage is a halfband FIR filter with 32 nonzero taps (Blackman
+//! windowed sinc), evaluated in polyphase form. Stages are cascaded for 4x
+//! and 8x. All state is fixed-size, so `upsample` and `downsample` are
+//! lock-free and don't allocate.
+//!
+//! The filters are linear phase. A round trip (upsample then downsample)
+//! delays the signal by 31.5 base-rate samples at 2x, 47.25 at 4x and 55.125
+//! at 8x; see `Oversampler::latency`.
+
+use std::f64::consts;
+
+use module::{Buffer, N_SAMPLES_PER_CHUNK};
+
+// Number of nonzero taps (excluding the center) in each halfband filter.
+const HALFBAND_TAPS: usize = 32;
+
+/// The largest supported oversampling factor.
+pub const MAX_FACTOR: usize = 8;
+
+const N: usize = N_SAMPLES_PER_CHUNK;
+
+// A 2x up/downsampler using a polyphase halfband FIR filter.
+struct Halfband {
+    // the nonzero taps (at odd offsets from the center)
+    coefs: [f32; HALFBAND_TAPS],
+    up_hist: [f32; HALFBAND_TAPS],
+    down_even: [f32; HALFBAND_TAPS],
+    down_odd: [f32; HALFBAND_TAPS / 2 + 1],
+}
+
+impl Halfband {
+    fn new() -> Halfband {
+        // Blackman-windowed sinc; the window is zero at both ends.
+        let n = 2 * HALFBAND_TAPS + 1;
+        let c = (n - 1) as f64 / 2.0;
+        let mut coefs = [0.0; HALFBAND_TAPS];
+        for (j, coef) in coefs.iter_mut().enumerate() {
+            let k = 2 * j + 1;
+            let t = k as f64 - c;
+            let sinc = (consts::PI * t / 2.0).sin() / (consts::PI * t);
+            let w = 2.0 * consts::PI * k as f64 / (n - 1) as f64;
+            let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+            *coef = (sinc * window) as f32;
+        }
+        Halfband {
+            coefs,
+            up_hist: [0.0; HALFBAND_TAPS],
+            down_even: [0.0; HALFBAND_TAPS],
+            down_odd: [0.0; HALFBAND_TAPS / 2 + 1],
+        }
+    }
+
+    fn upsample(&mut self, inp: &[f32], out: &mut [f32]) {
+        for (x, y) in inp.iter().zip(out.chunks_mut(2)) {
+            shift_in(&mut self.up_hist, *x);
+            y[0] = self.up_hist[HALFBAND_TAPS / 2];
+            y[1] = 2.0 * dot(&self.coefs, &self.up_hist);
+        }
+    }
+
+    fn downsample(&mut self, inp: &[f32], out: &mut [f32]) {
+        for (x, y) in inp.chunks(2).zip(out.iter_mut()) {
+            shift_in(&mut self.down_even, x[0]);
+            shift_in(&mut self.down_odd, x[1]);
+            *y = dot(&self.coefs, &self.down_even) + 0.5 * self.down_odd[HALFBAND_TAPS / 2];
+        }
+    }
+}
+
+fn shift_in(hist: &mut [f32], x: f32) {
+    for i in (1..hist.len()).rev() {
+        hist[i] = hist[i - 1];
+    }
+    hist[0] = x;
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+/// Up/downsampling of one chunk at a time by a factor of 1, 2, 4 or 8.
+///
+/// A typical module calls `upsample`, applies its nonlinearity to the
+/// returned high-rate samples, then calls `downsample`; `process` wraps
+/// that sequence.
+pub struct Oversampler {
+    n_stages: usize,
+    stages: [Halfband; 3],
+    // high-rate signal at each stage: 2x, 4x and 8x
+    buf2: [f32; 2 * N],
+    buf4: [f32; 4 * N],
+    buf8: [f32; 8 * N],
+}
+
+impl Oversampler {
+    /// Create an oversampler. The factor must be 1, 2, 4 or 8.
+    pub fn new(factor: usize) -> Oversampler {
+        let n_stages = match factor {
+            1 => 0,
+            2 => 1,
+            4 => 2,
+            8 => 3,
+            _ => panic!("oversampling factor must be 1, 2, 4 or 8"),
+        };
+        Oversampler {
+            n_stages,
+            stages: [Halfband::new(), Halfband::new(), Halfband::new()],
+            buf2: [0.0; 2 * N],
+            buf4: [0.0; 4 * N],
+            buf8: [0.0; 8 * N],
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        1 << self.n_stages
+    }
+
+    /// The delay of a round trip through `upsample` and `downsample`, in
+    /// base-rate samples.
+    pub fn latency(&self) -> f32 {
+        // Each stage delays by (HALFBAND_TAPS - 0.5) samples at its input rate.
+        let stage = HALFBAND_TAPS as f32 - 0.5;
+        (0..self.n_stages).fold(0.0, |acc, i| acc + stage / (1 << i) as f32)
+    }
+
+    /// Upsample one chunk, returning the high-rate samples. These may be
+    /// modified in place before calling `downsample`.
+    pub fn upsample(&mut self, inp: &[f32; N]) -> &mut [f32] {
+        match self.n_stages {
+            0 => {
+                self.buf2[..N].copy_from_slice(inp);
+                &mut self.buf2[..N]
+            }
+            1 => {
+                self.stages[0].upsample(inp, &mut self.buf2);
+                &mut self.buf2
+            }
+            2 => {
+                self.stages[0].upsample(inp, &mut self.buf2);
+                self.stages[1].upsample(&self.buf2, &mut self.buf4);
+                &mut self.buf4
+            }
+            _ => {
+                self.stages[0].upsample(inp, &mut self.buf2);
+                self.stages[1].upsample(&self.buf2, &mut self.buf4);
+                self.stages[2].upsample(&self.buf4, &mut self.buf8);
+                &mut self.buf8
+            }
+        }
+    }
+
+    /// Downsample the high-rate samples (as returned by `upsample`) into one
+    /// chunk at the base rate.
+    pub fn downsample(&mut self, out: &mut [f32; N]) {
+        match self.n_stages {
+            0 => out.copy_from_slice(&self.buf2[..N]),
+            1 => self.stages[0].downsample(&self.buf2, out),
+            2 => {
+                self.stages[1].downsample(&self.buf4, &mut self.buf2);
+                self.stages[0].downsample(&self.buf2, out);
+            }
+            _ => {
+                self.stages[2].downsample(&self.buf8, &mut self.buf4);
+                self.stages[1].downsample(&self.buf4, &mut self.buf2);
+                self.stages[0].downsample(&self.buf2, out);
+            }
+        }
+    }
+
+    /// Run `f` on the oversampled signal, from `inp` to `out`.
+    pub fn process<F: FnMut(&mut [f32])>(&mut self, inp: &Buffer, out: &mut Buffer, mut f: F) {
+        f(self.upsample(inp.get()));
+        self.downsample(out.get_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts;
+    use module::{Buffer, N_SAMPLES_PER_CHUNK};
+    use super::*;
+
+    const FACTORS: [usize; 4] = [1, 2, 4, 8];
+
+    // Amplitude of the component of `x` at `freq` (in cycles per sample),
+    // which should have a whole number of cycles over `x`.
+    fn amplitude(x: &[f32], freq: f64) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &y) in x.iter().enumerate() {
+            let th = 2.0 * consts::PI * freq * i as f64;
+            re += y as f64 * th.cos();
+            im += y as f64 * th.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / x.len() as f64
+    }
+
+    // Feed a sine through a round trip, a chunk at a time; returns input
+    // and output.
+    fn round_trip<F: FnMut(usize, &mut [f32])>(factor: usize, freq: f64, n_chunks: usize,
+        mut f: F) -> (Vec<f32>, Vec<f32>)
+    {
+        let mut os = Oversampler::new(factor);
+        let mut input = Vec::new();
+        let mut output = Vec::new();
+        for c in 0..n_chunks {
+            let mut inb = Buffer::default();
+            for (i, x) in inb.get_mut().iter_mut().enumerate() {
+                let n = c * N_SAMPLES_PER_CHUNK + i;
+                *x = (2.0 * consts::PI * freq * n as f64).sin() as f32;
+            }
+            let mut out = Buffer::default();
+            os.process(&inb, &mut out, |high| f(c, high));
+            input.extend_from_slice(inb.get());
+            output.extend_from_slice(out.get());
+        }
+        (input, output)
+    }
+
+    #[test]
+    fn latency_matches() {
+        let freq = 1.0 / 64.0;
+        for &factor in &FACTORS {
+            let latency = Oversampler::new(factor).latency() as f64;
+            let (_, output) = round_trip(factor, freq, 16, |_, _| ());
+            for (n, &y) in output.iter().enumerate().skip(128) {
+                let expected = (2.0 * consts::PI * freq * (n as f64 - latency)).sin();
+                assert!((y as f64 - expected).abs() < 1e-3, "{}x at {}: {} vs {}",
+                    factor, n, y, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn passband_gain() {
+        for &factor in &FACTORS {
+            for &freq in &[1.0 / 32.0, 1.0 / 8.0, 3.0 / 16.0] {
+                let (_, output) = round_trip(factor, freq, 16, |_, _| ());
+                let gain = amplitude(&output[256..], freq);
+                assert!((gain - 1.0).abs() < 0.01, "{}x at {}: gain {}", factor, freq, gain);
+            }
+        }
+    }
+
+    #[test]
+    fn images_attenuated() {
+        // Upsampling a tone at 1/8 of the base rate shouldn't leave an image
+        // above the base Nyquist frequency.
+        for &factor in &FACTORS[1..] {
+            let mut high = Vec::new();
+            round_trip(factor, 1.0 / 8.0, 16, |c, x| if c >= 8 {
+                high.extend_from_slice(x);
+            });
+            let tone = 1.0 / 8.0 / factor as f64;
+            assert!((amplitude(&high, tone) - 1.0).abs() < 0.01);
+            let image = 1.0 / factor as f64 - tone;
+            assert!(amplitude(&high, image) < 1e-3, "{}x: image {}", factor,
+                amplitude(&high, image));
+        }
+    }
+
+    #[test]
+    fn downsampling_removes_high_band() {
+        // A tone added above the base Nyquist frequency is filtered out.
+        for &factor in &FACTORS[1..] {
+            let tone = 3.0 / 4.0 / factor as f64;
+            let mut n = 0;
+            let (_, output) = round_trip(factor, 0.0, 16, |_, x| for y in x.iter_mut() {
+                *y = (2.0 * consts::PI * tone * n as f64).sin() as f32;
+                n += 1;
+            });
+            let peak = output[256..].iter().fold(0.0f32, |m, y| m.max(y.abs()));
+            assert!(peak < 1e-3, "{}x: {}", factor, peak);
+        }
+    }
+}
@@ -0,0 +1,365 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Patches: text descriptions of an instrument voice as a graph of modules,
+//! and banks of them selected by MIDI program change.
+//!
+//! Each line of a patch defines a node, with the module type and its
+//! arguments, followed by its buffer inputs after `<` and its control inputs
+//! after `:`. Parameters can be set with `set <node> <param index> <value>`.
+//!
+//! ```text
+//! # a filtered saw
+//! cutoff control 9.8
+//! reso control 0.5
+//! osc saw : pitch
+//! attack control 5
+//! decay control 5
+//! sustain control 4
+//! release control 5
+//! env adsr : attack decay sustain release
+//! filter biquad < osc : cutoff reso
+//! out gain < filter : env
+//! ```
+//!
+//! Inputs name earlier nodes, with an output index after a period if it
+//! isn't the first (`pitch.1`). The node `pitch` is provided by the engine;
+//! its outputs are those of `NotePitch`. The voice's sound is the first
+//! buffer output of the node named `out`. Nodes named `cutoff`, `reso`,
+//! `attack`, `decay`, `sustain` and `release` are driven by the same MIDI
+//! controllers as the mono synth.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use graph::{MAX_BUF, MAX_CTRL};
+use module::Module;
+use modules;
+
+/// The name of the note pitch node provided by the engine.
+pub const PITCH: &str = "pitch";
+
+/// The name of the node whose output is the voice.
+pub const OUT: &str = "out";
+
+// Module types, with their number of arguments and the fewest buffer and
+// control inputs they need.
+const MODULE_TYPES: &[(&str, usize, usize, usize)] = &[
+    ("const", 1, 0, 0),
+    ("control", 1, 0, 0),
+    ("sine", 0, 0, 1),
+    ("saw", 0, 0, 1),
+    ("pluck", 0, 0, 1),
+    ("lfo", 1, 0, 1),
+    ("biquad", 0, 1, 2),
+    ("adsr", 0, 0, 4),
+    ("gain", 0, 1, 1),
+    ("sum", 0, 0, 0),
+    ("multiply", 0, 0, 0),
+    ("crossfade", 0, 2, 1),
+    ("scale_offset", 2, 0, 1),
+    ("clamp", 2, 0, 1),
+    ("dc_blocker", 0, 1, 0),
+    ("mod_matrix", 2, 0, 0),
+    ("delay", 1, 1, 1),
+    ("chorus", 0, 1, 0),
+    ("flanger", 0, 1, 0),
+    ("phaser", 0, 1, 0),
+    ("reverb", 0, 1, 0),
+];
+
+// The longest delay time a patch can ask for, in seconds.
+const MAX_DELAY_TIME: f32 = 10.0;
+
+#[derive(Clone, Debug, Default)]
+pub struct Patch {
+    /// Nodes in order of definition; inputs always refer to earlier nodes.
+    pub nodes: Vec<PatchNode>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PatchNode {
+    pub name: String,
+    pub module: String,
+    pub args: Vec<f32>,
+    pub buf_in: Vec<PatchInput>,
+    pub ctrl_in: Vec<PatchInput>,
+    /// Parameter settings, as (param index, value).
+    pub params: Vec<(usize, f32)>,
+}
+
+/// A reference to an output of another node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchInput {
+    pub node: String,
+    pub output: usize,
+}
+
+/// Patches indexed by bank and program number.
+#[derive(Default)]
+pub struct PresetBank {
+    patches: HashMap<(u16, u8), Patch>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_num<T: ::std::str::FromStr>(s: &str, what: &str) -> io::Result<T> {
+    s.parse().map_err(|_| invalid(&format!("bad {}: {}", what, s)))
+}
+
+fn parse_input(s: &str) -> io::Result<PatchInput> {
+    let mut parts = s.splitn(2, '.');
+    let node = parts.next().unwrap().to_string();
+    let output = match parts.next() {
+        Some(output) => parse_num(output, "output index")?,
+        None => 0,
+    };
+    Ok(PatchInput { node, output })
+}
+
+impl Patch {
+    /// Parse the text of a patch.
+    pub fn parse(text: &str) -> io::Result<Patch> {
+        let mut nodes: Vec<PatchNode> = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap();
+            let mut tokens = line.split_whitespace();
+            let name = match tokens.next() {
+                Some(name) => name,
+                None => continue,
+            };
+            if name == "set" {
+                let node = tokens.next().ok_or_else(|| invalid("missing node to set"))?;
+                let param_ix = parse_num(tokens.next().unwrap_or(""), "param index")?;
+                let val = parse_num(tokens.next().unwrap_or(""), "param value")?;
+                let node = nodes.iter_mut().find(|n| n.name == node)
+                    .ok_or_else(|| invalid(&format!("set of undefined node {}", node)))?;
+                node.params.push((param_ix, val));
+                continue;
+            }
+            if name == PITCH || nodes.iter().any(|n| n.name == name) {
+                return Err(invalid(&format!("node {} already defined", name)));
+            }
+            let module = tokens.next().ok_or_else(|| invalid("missing module type"))?;
+            let mut node = PatchNode {
+                name: name.to_string(),
+                module: module.to_string(),
+                args: Vec::new(),
+                buf_in: Vec::new(),
+                ctrl_in: Vec::new(),
+                params: Vec::new(),
+            };
+            // 0 for arguments, 1 for buffer inputs, 2 for control inputs
+            let mut section = 0;
+            for token in tokens {
+                match token {
+                    "<" if section == 0 => section = 1,
+                    ":" if section < 2 => section = 2,
+                    _ if section == 0 => node.args.push(parse_num(token, "argument")?),
+                    _ => {
+                        let input = parse_input(token)?;
+                        if input.node != PITCH && !nodes.iter().any(|n| n.name == input.node) {
+                            return Err(invalid(&format!("input from undefined node {}",
+                                input.node)));
+                        }
+                        if section == 1 {
+                            node.buf_in.push(input);
+                        } else {
+                            node.ctrl_in.push(input);
+                        }
+                    }
+                }
+            }
+            node.check()?;
+            nodes.push(node);
+        }
+        if !nodes.iter().any(|n| n.name == OUT) {
+            return Err(invalid("no out node"));
+        }
+        Ok(Patch { nodes })
+    }
+
+    /// Load a patch file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Patch> {
+        Patch::parse(&fs::read_to_string(path)?)
+    }
+}
+
+impl PatchNode {
+    /// Check the module type, its arguments and the number of inputs.
+    pub fn check(&self) -> io::Result<()> {
+        let module = self.module.as_str();
+        let &(_, n_args, n_bufs, n_ctrl) = MODULE_TYPES.iter().find(|ty| ty.0 == module)
+            .ok_or_else(|| invalid(&format!("unknown module type {}", module)))?;
+        if self.args.len() != n_args {
+            return Err(invalid(&format!("{} takes {} arguments", module, n_args)));
+        }
+        if self.buf_in.len() > MAX_BUF || self.ctrl_in.len() > MAX_CTRL {
+            return Err(invalid("too many inputs"));
+        }
+        if self.buf_in.len() < n_bufs || self.ctrl_in.len() < n_ctrl {
+            return Err(invalid(&format!("{} needs {} buffer and {} control inputs", module,
+                n_bufs, n_ctrl)));
+        }
+        match module {
+            "delay" if !(0.0..=MAX_DELAY_TIME).contains(&self.args[0]) => {
+                Err(invalid(&format!("delay time must be from 0 to {}", MAX_DELAY_TIME)))
+            }
+            "mod_matrix" if self.args.iter().any(|&n| n < 0.0 || n.fract() != 0.0) => {
+                Err(invalid("matrix sizes must be whole numbers"))
+            }
+            "mod_matrix" if self.args[0] + self.args[1] > MAX_CTRL as f32 => {
+                Err(invalid("too many matrix inputs"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create the module, with its parameters set. Fails if `check` does.
+    pub fn build(&self, sample_rate: f32) -> io::Result<Box<dyn Module>> {
+        self.check()?;
+        let arg = |i: usize| self.args[i];
+        let mut module: Box<dyn Module> = match self.module.as_str() {
+            "const" => Box::new(modules::ConstCtrl::new(arg(0))),
+            "control" => Box::new(modules::SmoothCtrl::new(arg(0))),
+            "sine" => Box::new(modules::Sin::new(sample_rate)),
+            "saw" => Box::new(modules::Saw::new(sample_rate)),
+            "pluck" => Box::new(modules::Pluck::new(sample_rate)),
+            "lfo" => Box::new(modules::Lfo::new(sample_rate, modules::LfoShape::from_param(arg(0)))),
+            "biquad" => Box::new(modules::Biquad::new(sample_rate)),
+            "adsr" => Box::new(modules::Adsr::new()),
+            "gain" => Box::new(modules::Gain::new()),
+            "sum" => Box::new(modules::Sum::new()),
+            "multiply" => Box::new(modules::Multiply::new()),
+            "crossfade" => Box::new(modules::Crossfade::new()),
+            "scale_offset" => Box::new(modules::ScaleOffset::new(arg(0), arg(1))),
+            "clamp" => Box::new(modules::Clamp::new(arg(0), arg(1))),
+            "dc_blocker" => Box::new(modules::DcBlocker::new(sample_rate)),
+            "mod_matrix" => Box::new(modules::ModMatrix::new(arg(0) as usize, arg(1) as usize)),
+            "delay" => Box::new(modules::Delay::new(sample_rate, arg(0))),
+            "chorus" => Box::new(modules::Chorus::new(sample_rate)),
+            "flanger" => Box::new(modules::Flanger::new(sample_rate)),
+            "phaser" => Box::new(modules::Phaser::new(sample_rate)),
+            "reverb" => Box::new(modules::Reverb::new(sample_rate)),
+            _ => unreachable!(),
+        };
+        for &(param_ix, val) in &self.params {
+            module.set_param(param_ix, val, 0);
+        }
+        Ok(module)
+    }
+}
+
+impl PresetBank {
+    pub fn new() -> PresetBank {
+        PresetBank::default()
+    }
+
+    pub fn insert(&mut self, bank: u16, program: u8, patch: Patch) {
+        self.patches.insert((bank, program), patch);
+    }
+
+    pub fn get(&self, bank: u16, program: u8) -> Option<&Patch> {
+        self.patches.get(&(bank, program))
+    }
+
+    /// Load the patches in a directory. Files are named by program number,
+    /// counting from 0 as in MIDI messages: `<program>.patch` for bank 0, or
+    /// `<bank>-<program>.patch`. Other files are ignored.
+    ///
+    /// A patch that fails to load doesn't stop the others; the bank is
+    /// returned along with the path and error for each such file. Only a
+    /// failure to read the directory is an error.
+    pub fn load_dir<P: AsRef<Path>>(dir: P)
+        -> io::Result<(PresetBank, Vec<(PathBuf, io::Error)>)>
+    {
+        let mut result = PresetBank::new();
+        let mut errors = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "patch") {
+                continue;
+            }
+            let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => stem,
+                None => continue,
+            };
+            let (bank, program) = match stem.find('-') {
+                Some(pos) => (stem[..pos].parse().ok(), stem[pos + 1..].parse().ok()),
+                None => (Some(0), stem.parse().ok()),
+            };
+            if let (Some(bank), Some(program)) = (bank, program) {
+                if program < 128 {
+                    match Patch::load(&path) {
+                        Ok(patch) => result.insert(bank, program, patch),
+                        Err(e) => errors.push((path, e)),
+                    }
+                }
+            }
+        }
+        Ok((result, errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+
+    #[test]
+    fn load_dir_skips_bad_files() {
+        let dir = env::temp_dir().join(format!("synth-presets-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0.patch"), "out saw : pitch\n").unwrap();
+        fs::write(dir.join("1-5.patch"), "out saw : pitch\n").unwrap();
+        fs::write(dir.join("2.patch"), "out nonsense\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a patch").unwrap();
+        let result = PresetBank::load_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let (bank, errors) = result.unwrap();
+        assert!(bank.get(0, 0).is_some());
+        assert!(bank.get(1, 5).is_some());
+        assert!(bank.get(0, 2).is_none());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].0.ends_with("2.patch"));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let parse = |text: &str| Patch::parse(&format!("{}\nout saw : pitch\n", text));
+        assert!(parse("d const 1\ns saw : d\nx delay 2 < s : d").is_ok());
+        assert!(parse("d const 1\ns saw : d\nx delay 100000 < s : d").is_err());
+        assert!(parse("d const 1\ns saw : d\nx delay -1 < s : d").is_err());
+        assert!(parse("m mod_matrix 2 3").is_ok());
+        assert!(parse("m mod_matrix -1 3").is_err());
+        assert!(parse("m mod_matrix 1.5 3").is_err());
+        assert!(parse("m mod_matrix 10 10").is_err());
+        // too few control inputs for the envelope's stages
+        assert!(parse("e adsr : pitch pitch").is_err());
+    }
+
+    #[test]
+    fn build_checks_node() {
+        let mut node = Patch::parse("out sine : pitch\n").unwrap().nodes.remove(0);
+        assert!(node.build(48_000.0).is_ok());
+        node.module = "theremin".to_string();
+        assert!(node.build(48_000.0).is_err());
+        node.module = "lfo".to_string();
+        assert!(node.build(48_000.0).is_err());
+    }
+}
@@ -151,6 +151,13 @@ impl<T: Send + 'static> Sender<T> {
     pub fn send_item(&self, item: Item<T>) {
         self.queue.send_item(item);
     }
+
+    /// Enqueue several values atomically: the receiver gets either none of
+    /// them or all of them, in order, from a single `recv` or `recv_items`
+    /// call. Note: this method allocates.
+    pub fn send_all<I: IntoIterator<Item = T>>(&self, payloads: I) {
+        self.queue.send_all(payloads);
+    }
 }
 
 impl<T: Send + 'static> Receiver<T> {
@@ -197,15 +204,36 @@ impl<T: Send + 'static> Queue<T> {
         self.push_raw(item.ptr);
     }
 
+    fn send_all<I: IntoIterator<Item = T>>(&self, payloads: I) {
+        // link the items into a chain, most recent first, as on the stack
+        let mut first = None;
+        let mut last = None;
+        for payload in payloads {
+            let mut ptr = Item::make_item(payload).ptr;
+            unsafe { ptr.as_mut().child = last; }
+            first = first.or(Some(ptr));
+            last = Some(ptr);
+        }
+        if let (Some(first), Some(last)) = (first, last) {
+            self.push_chain(first, last);
+        }
+    }
+
     fn recv_items(&self) -> QueueItemIter<T> {
         unsafe { QueueItemIter(Node::reverse(self.pop_all())) }
     }
 
-    fn push_raw(&self, mut n: NonNull<Node<T>>) {
+    fn push_raw(&self, n: NonNull<Node<T>>) {
+        self.push_chain(n, n);
+    }
+
+    // Push a chain of nodes linked from `last` (the most recent) down to
+    // `first`, in one atomic operation.
+    fn push_chain(&self, mut first: NonNull<Node<T>>, last: NonNull<Node<T>>) {
         let mut old_ptr = self.head.load(Relaxed);
         loop {
-            unsafe { n.as_mut().child = NonNull::new(old_ptr); }
-            match self.head.compare_exchange_weak(old_ptr, n.as_ptr(), Release, Relaxed) {
+            unsafe { first.as_mut().child = NonNull::new(old_ptr); }
+            match self.head.compare_exchange_weak(old_ptr, last.as_ptr(), Release, Relaxed) {
                 Ok(_) => break,
                 Err(old) => old_ptr = old,
             }
@@ -0,0 +1,359 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A reader for a practical subset of the SFZ instrument format.
+//!
+//! Supported headers are `<control>`, `<global>`, `<group>` and `<region>`,
+//! with opcodes inherited from global to group to region. Supported opcodes:
+//!
+//! * `default_path` (in `<control>`), `sample`
+//! * `lokey`, `hikey`, `key`, `pitch_keycenter` (numbers or note names; c4 is 60)
+//! * `lovel`, `hivel`, `amp_veltrack`
+//! * `tune` (cents), `transpose` (semitones), `volume` (dB)
+//! * `offset`, `end`, `loop_mode`, `loop_start`, `loop_end`
+//!   (and the `loopstart`, `loopend` and `loopmode` spellings)
+//! * `ampeg_release` (seconds)
+//!
+//! Other opcodes are ignored, as are `//` comments. Sample paths may contain
+//! spaces, and backslashes are treated as path separators.
+//!
+//! See https://sfzformat.com/ for the format.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use modules::SamplerMode;
+
+/// One region: a sample and the notes and velocities that play it.
+#[derive(Clone, Debug)]
+pub struct SfzRegion {
+    pub sample: PathBuf,
+    pub lokey: u8,
+    pub hikey: u8,
+    pub lovel: u8,
+    pub hivel: u8,
+    /// The note at which the sample plays at its original speed.
+    pub pitch_keycenter: f32,
+    /// Fine tuning, in cents.
+    pub tune: f32,
+    /// Coarse tuning, in semitones.
+    pub transpose: f32,
+    /// Gain in dB.
+    pub volume: f32,
+    /// How much velocity affects level, in percent.
+    pub amp_veltrack: f32,
+    /// Start point, in frames.
+    pub offset: usize,
+    /// End point, in frames (inclusive, as in SFZ); `None` for the end of the sample.
+    pub end: Option<usize>,
+    /// `None` means the SFZ default, which is not to loop.
+    pub loop_mode: Option<SamplerMode>,
+    pub loop_start: Option<usize>,
+    /// Loop end, in frames (inclusive, as in SFZ).
+    pub loop_end: Option<usize>,
+    /// Release time of the amplitude envelope, in seconds.
+    pub ampeg_release: f32,
+}
+
+/// An instrument: a list of regions.
+#[derive(Clone, Debug)]
+pub struct Sfz {
+    pub regions: Vec<SfzRegion>,
+}
+
+impl Default for SfzRegion {
+    fn default() -> SfzRegion {
+        SfzRegion {
+            sample: PathBuf::new(),
+            lokey: 0,
+            hikey: 127,
+            lovel: 1,
+            hivel: 127,
+            pitch_keycenter: 60.0,
+            tune: 0.0,
+            transpose: 0.0,
+            volume: 0.0,
+            amp_veltrack: 100.0,
+            offset: 0,
+            end: None,
+            loop_mode: None,
+            loop_start: None,
+            loop_end: None,
+            ampeg_release: 0.001,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parse a key, either a MIDI note number or a note name such as "c#4" or
+/// "eb3", where c4 is note 60.
+pub fn parse_key(s: &str) -> io::Result<u8> {
+    let bad = || invalid(&format!("bad key: {}", s));
+    if let Ok(n) = s.parse::<u8>() {
+        return if n < 128 { Ok(n) } else { Err(bad()) };
+    }
+    let lower = s.to_lowercase();
+    let mut chars = lower.chars();
+    let mut semitone: i32 = match chars.next() {
+        Some('c') => 0,
+        Some('d') => 2,
+        Some('e') => 4,
+        Some('f') => 5,
+        Some('g') => 7,
+        Some('a') => 9,
+        Some('b') => 11,
+        _ => return Err(bad()),
+    };
+    let mut rest = chars.as_str();
+    if rest.starts_with('#') {
+        semitone += 1;
+        rest = &rest[1..];
+    } else if rest.starts_with('b') {
+        semitone -= 1;
+        rest = &rest[1..];
+    }
+    let octave: i32 = rest.parse().map_err(|_| bad())?;
+    let key = (octave + 1) * 12 + semitone;
+    if (0..128).contains(&key) { Ok(key as u8) } else { Err(bad()) }
+}
+
+fn parse_num<T: ::std::str::FromStr>(opcode: &str, val: &str) -> io::Result<T> {
+    val.parse().map_err(|_| invalid(&format!("bad value for {}: {}", opcode, val)))
+}
+
+fn parse_loop_mode(val: &str) -> io::Result<SamplerMode> {
+    match val {
+        "no_loop" => Ok(SamplerMode::NoLoop),
+        "one_shot" => Ok(SamplerMode::OneShot),
+        "loop_continuous" => Ok(SamplerMode::LoopContinuous),
+        "loop_sustain" => Ok(SamplerMode::LoopSustain),
+        _ => Err(invalid(&format!("bad loop_mode: {}", val))),
+    }
+}
+
+// Split the opcode text between headers into (opcode, value) pairs. A word
+// without an '=' continues the previous value, so paths can contain spaces.
+fn parse_opcodes(text: &str, out: &mut Vec<(String, String)>) {
+    for word in text.split_whitespace() {
+        match word.find('=') {
+            Some(eq) if eq > 0 => out.push((word[..eq].to_string(), word[eq + 1..].to_string())),
+            _ => if let Some((_, val)) = out.last_mut() {
+                val.push(' ');
+                val.push_str(word);
+            }
+        }
+    }
+}
+
+impl SfzRegion {
+    fn apply(&mut self, opcode: &str, val: &str, default_path: &str) -> io::Result<()> {
+        match opcode {
+            "sample" => self.sample = PathBuf::from(format!("{}{}", default_path, val).replace('\\', "/")),
+            "lokey" => self.lokey = parse_key(val)?,
+            "hikey" => self.hikey = parse_key(val)?,
+            "key" => {
+                let key = parse_key(val)?;
+                self.lokey = key;
+                self.hikey = key;
+                self.pitch_keycenter = key as f32;
+            }
+            "pitch_keycenter" => self.pitch_keycenter = parse_key(val)? as f32,
+            "lovel" => self.lovel = parse_num(opcode, val)?,
+            "hivel" => self.hivel = parse_num(opcode, val)?,
+            "amp_veltrack" => self.amp_veltrack = parse_num(opcode, val)?,
+            "tune" => self.tune = parse_num(opcode, val)?,
+            "transpose" => self.transpose = parse_num(opcode, val)?,
+            "volume" => self.volume = parse_num(opcode, val)?,
+            "offset" => self.offset = parse_num(opcode, val)?,
+            "end" => self.end = Some(parse_num(opcode, val)?),
+            "loop_mode" | "loopmode" => self.loop_mode = Some(parse_loop_mode(val)?),
+            "loop_start" | "loopstart" => self.loop_start = Some(parse_num(opcode, val)?),
+            "loop_end" | "loopend" => self.loop_end = Some(parse_num(opcode, val)?),
+            "ampeg_release" => self.ampeg_release = parse_num(opcode, val)?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// The original pitch of the sample, in (fractional) MIDI note numbers,
+    /// taking tuning into account.
+    pub fn root_key(&self) -> f32 {
+        self.pitch_keycenter - self.transpose - self.tune * 0.01
+    }
+
+    /// Whether this region plays for a note.
+    pub fn matches(&self, key: u8, velocity: u8) -> bool {
+        key >= self.lokey && key <= self.hikey && velocity >= self.lovel && velocity <= self.hivel
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Header {
+    None,
+    Control,
+    Global,
+    Group,
+    Region,
+}
+
+impl Sfz {
+    /// Parse the contents of an .sfz file. Sample paths are resolved relative
+    /// to `base_dir`.
+    pub fn parse(text: &str, base_dir: &Path) -> io::Result<Sfz> {
+        let mut control = Vec::new();
+        let mut global = Vec::new();
+        let mut group = Vec::new();
+        let mut region = Vec::new();
+        let mut regions = Vec::new();
+        let mut header = Header::None;
+        let mut default_path = String::new();
+
+        // Build the region from the opcodes seen so far, if one is open.
+        let mut finish_region = |header: Header, control: &[(String, String)],
+            global: &[(String, String)], group: &[(String, String)],
+            region: &[(String, String)], default_path: &mut String| -> io::Result<()>
+        {
+            if let Some((_, path)) = control.iter().rev().find(|(op, _)| op == "default_path") {
+                *default_path = path.clone();
+            }
+            if header != Header::Region {
+                return Ok(());
+            }
+            let mut r = SfzRegion::default();
+            for (op, val) in global.iter().chain(group).chain(region) {
+                r.apply(op, val, default_path)?;
+            }
+            if r.sample.as_os_str().is_empty() {
+                return Err(invalid("region without sample"));
+            }
+            r.sample = base_dir.join(&r.sample);
+            regions.push(r);
+            Ok(())
+        };
+
+        for line in text.lines() {
+            let mut line = match line.find("//") {
+                Some(ix) => &line[..ix],
+                None => line,
+            };
+            while !line.is_empty() {
+                let (opcodes, rest) = match line.find('<') {
+                    Some(ix) => line.split_at(ix),
+                    None => (line, ""),
+                };
+                let target = match header {
+                    Header::Control => &mut control,
+                    Header::Global => &mut global,
+                    Header::Group => &mut group,
+                    Header::Region => &mut region,
+                    Header::None => &mut region,
+                };
+                parse_opcodes(opcodes, target);
+                if rest.is_empty() {
+                    break;
+                }
+                let end = rest.find('>').ok_or_else(|| invalid("unterminated header"))?;
+                finish_region(header, &control, &global, &group, &region, &mut default_path)?;
+                region.clear();
+                header = match &rest[1..end] {
+                    "control" => {
+                        control.clear();
+                        Header::Control
+                    }
+                    "global" => {
+                        global.clear();
+                        group.clear();
+                        Header::Global
+                    }
+                    "group" => {
+                        group.clear();
+                        Header::Group
+                    }
+                    "region" => Header::Region,
+                    // unsupported headers; their opcodes are ignored
+                    _ => Header::None,
+                };
+                line = &rest[end + 1..];
+            }
+        }
+        finish_region(header, &control, &global, &group, &region, &mut default_path)?;
+        Ok(Sfz { regions })
+    }
+
+    /// Load an .sfz file. Sample paths are resolved relative to its directory.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Sfz> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Sfz::parse(&text, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_inherit() {
+        let text = "<global> volume=-6 lovel=10\n\
+            <group> key=c4 tune=10 // a comment\n\
+            <region> sample=a.wav\n\
+            <region> sample=b.wav key=62\n\
+            <group> <region> sample=c.wav\n";
+        let sfz = Sfz::parse(text, Path::new("base")).unwrap();
+        let r = &sfz.regions;
+        assert_eq!(r.len(), 3);
+        assert_eq!(r[0].sample, Path::new("base/a.wav"));
+        assert_eq!((r[0].lokey, r[0].hikey, r[0].lovel, r[0].volume), (60, 60, 10, -6.0));
+        assert_eq!(r[0].root_key(), 59.9);
+        assert_eq!((r[1].lokey, r[1].hikey, r[1].tune), (62, 62, 10.0));
+        // a new group drops the old group's opcodes, but not the global ones
+        assert_eq!((r[2].lokey, r[2].hikey, r[2].tune, r[2].volume), (0, 127, 0.0, -6.0));
+    }
+
+    #[test]
+    fn sample_paths() {
+        let text = "<control> default_path=samples\\piano\\\n\
+            <region> sample=Grand C4.wav lokey=c4\n";
+        let sfz = Sfz::parse(text, Path::new("base")).unwrap();
+        assert_eq!(sfz.regions[0].sample, Path::new("base/samples/piano/Grand C4.wav"));
+        assert_eq!(sfz.regions[0].lokey, 60);
+        assert!(Sfz::parse("<region> lokey=60", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn note_names() {
+        assert_eq!(parse_key("c4").unwrap(), 60);
+        assert_eq!(parse_key("C#4").unwrap(), 61);
+        assert_eq!(parse_key("eb5").unwrap(), 75);
+        assert_eq!(parse_key("a-1").unwrap(), 9);
+        assert_eq!(parse_key("127").unwrap(), 127);
+        for bad in &["128", "h4", "c", "g#9"] {
+            assert!(parse_key(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn loop_points() {
+        let text = "<region> sample=a.wav offset=10 end=99 loopmode=loop_sustain \
+            loopstart=20 loop_end=49";
+        let r = &Sfz::parse(text, Path::new("")).unwrap().regions[0];
+        assert_eq!((r.offset, r.end), (10, Some(99)));
+        assert_eq!(r.loop_mode, Some(SamplerMode::LoopSustain));
+        assert_eq!((r.loop_start, r.loop_end), (Some(20), Some(49)));
+    }
+}
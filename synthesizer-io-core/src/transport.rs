@@ -0,0 +1,776 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A tempo clock, and a step sequencer and arpeggiator driven by it.
+//!
+//! These run on the control side. They are polled periodically, and produce
+//! note events for a window of time ahead; the events carry precise
+//! timestamps, and the worker holds each one back until its time comes.
+//! Times are in nanoseconds, on the same clock as `time::precise_time_ns`.
+
+/// A note event produced by a sequencer or arpeggiator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScheduledNote {
+    pub note: u8,
+    pub velocity: u8,
+    pub on: bool,
+    pub timestamp: u64,
+}
+
+/// A tempo clock with start/stop and swing.
+pub struct Transport {
+    bpm: f64,
+    swing: f64,
+    running: bool,
+    // beat position at `anchor_time`
+    anchor_beat: f64,
+    anchor_time: u64,
+}
+
+impl Transport {
+    pub fn new(bpm: f64) -> Transport {
+        Transport {
+            bpm,
+            swing: 0.5,
+            running: false,
+            anchor_beat: 0.0,
+            anchor_time: 0,
+        }
+    }
+
+    pub fn bpm(&self) -> f64 {
+        self.bpm
+    }
+
+    /// Change the tempo, keeping the beat position continuous at `now`.
+    pub fn set_bpm(&mut self, bpm: f64, now: u64) {
+        self.anchor_beat = self.beat_at(now);
+        self.anchor_time = now;
+        self.bpm = bpm;
+    }
+
+    pub fn swing(&self) -> f64 {
+        self.swing
+    }
+
+    /// Set the swing, as the fraction of each pair of steps taken by the
+    /// first: 0.5 is straight, 0.67 is a triplet feel. Clamped to 0.5..0.9.
+    pub fn set_swing(&mut self, swing: f64) {
+        self.swing = swing.clamp(0.5, 0.9);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Start from beat 0 at `now`.
+    pub fn start(&mut self, now: u64) {
+        self.start_from(0.0, now);
+    }
+
+    /// Start from a beat position at `now`.
+    pub fn start_from(&mut self, beat: f64, now: u64) {
+        self.running = true;
+        self.anchor_beat = beat;
+        self.anchor_time = now;
+    }
+
+    /// Continue from the position where the transport was stopped.
+    pub fn resume(&mut self, now: u64) {
+        if !self.running {
+            self.running = true;
+            self.anchor_time = now;
+        }
+    }
+
+    pub fn stop(&mut self, now: u64) {
+        if self.running {
+            self.anchor_beat = self.beat_at(now);
+            self.anchor_time = now;
+            self.running = false;
+        }
+    }
+
+    /// Move to a beat position. Only has an effect while stopped.
+    pub fn set_position(&mut self, beat: f64) {
+        if !self.running {
+            self.anchor_beat = beat;
+        }
+    }
+
+    /// Pull the transport toward an external clock that is at `beat` at
+    /// `time`, at tempo `bpm`. A `gain` of 1 jumps straight to the external
+    /// position; smaller values correct phase error gradually.
+    pub fn sync_to(&mut self, beat: f64, time: u64, bpm: f64, gain: f64) {
+        let predicted = self.beat_at(time);
+        self.anchor_beat = predicted + gain * (beat - predicted);
+        self.anchor_time = time;
+        self.bpm = bpm;
+    }
+
+    /// The beat position at a time. While stopped, this is the position at
+    /// which the transport stopped.
+    pub fn beat_at(&self, time: u64) -> f64 {
+        if !self.running {
+            return self.anchor_beat;
+        }
+        let dt = time as f64 - self.anchor_time as f64;
+        self.anchor_beat + dt * 1e-9 * self.bpm * (1.0 / 60.0)
+    }
+
+    /// The time of a beat position, assuming the transport keeps running at
+    /// the current tempo.
+    pub fn time_at(&self, beat: f64) -> u64 {
+        let dt = (beat - self.anchor_beat) * 60.0 / self.bpm * 1e9;
+        (self.anchor_time as f64 + dt).max(0.0) as u64
+    }
+
+    /// The time of step `step` on a grid of `steps_per_beat`, with swing
+    /// delaying the second step of each pair.
+    pub fn step_time(&self, step: u64, steps_per_beat: u32) -> u64 {
+        let pair = (step / 2) as f64;
+        let offset = if step % 2 == 1 { 2.0 * self.swing } else { 0.0 };
+        self.time_at((2.0 * pair + offset) / steps_per_beat as f64)
+    }
+
+    /// The index of the first step on a grid of `steps_per_beat` that starts
+    /// at or after the current position.
+    fn step_after(&self, beat: f64, steps_per_beat: u32) -> u64 {
+        (beat * steps_per_beat as f64).max(0.0).ceil() as u64
+    }
+}
+
+// Emit the on and off events for a step, if the step starts within the
+// window `(now, until)`. Returns false when the step is at or beyond `until`.
+fn schedule_step<F>(transport: &Transport, step: u64, steps_per_beat: u32, gate: f32,
+    (now, until): (u64, u64), out: &mut Vec<ScheduledNote>, note: F) -> bool
+    where F: FnOnce() -> Option<(u8, u8)>
+{
+    let start = transport.step_time(step, steps_per_beat);
+    if start >= until {
+        return false;
+    }
+    if start >= now {
+        if let Some((note, velocity)) = note() {
+            let len = transport.step_time(step + 1, steps_per_beat) - start;
+            let end = start + (len as f64 * gate.clamp(0.0, 1.0) as f64) as u64;
+            out.push(ScheduledNote { note, velocity, on: true, timestamp: start });
+            out.push(ScheduledNote { note, velocity: 0, on: false, timestamp: end });
+        }
+    }
+    true
+}
+
+/// One step of a sequence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Step {
+    pub note: u8,
+    pub velocity: u8,
+    /// Note length as a fraction of the step, up to 1.
+    pub gate: f32,
+}
+
+/// A looping sequence of steps, each of which may be a rest.
+pub struct StepSequencer {
+    steps: Vec<Option<Step>>,
+    steps_per_beat: u32,
+    next_step: Option<u64>,
+}
+
+impl StepSequencer {
+    /// Create a sequence of `n_steps` rests, with `steps_per_beat` steps per
+    /// beat (4 for sixteenth notes).
+    pub fn new(n_steps: usize, steps_per_beat: u32) -> StepSequencer {
+        StepSequencer {
+            steps: vec![None; n_steps],
+            steps_per_beat,
+            next_step: None,
+        }
+    }
+
+    pub fn steps(&self) -> &[Option<Step>] {
+        &self.steps
+    }
+
+    /// Set a step; `None` makes it a rest.
+    pub fn set_step(&mut self, ix: usize, step: Option<Step>) {
+        self.steps[ix] = step;
+    }
+
+    /// Start again from the step at the transport's current position.
+    pub fn reset(&mut self) {
+        self.next_step = None;
+    }
+
+    /// Produce events for the steps that start between `now` and `until`.
+    /// Steps that were missed (because polling fell behind) are skipped.
+    pub fn schedule(&mut self, transport: &Transport, now: u64, until: u64,
+        out: &mut Vec<ScheduledNote>)
+    {
+        if !transport.is_running() || self.steps.is_empty() {
+            return;
+        }
+        let spb = self.steps_per_beat;
+        let mut step = match self.next_step {
+            Some(step) => step,
+            None => transport.step_after(transport.beat_at(now), spb),
+        };
+        loop {
+            let entry = self.steps[(step % self.steps.len() as u64) as usize];
+            let gate = entry.map_or(0.0, |s| s.gate);
+            let note = || entry.map(|s| (s.note, s.velocity));
+            if !schedule_step(transport, step, spb, gate, (now, until), out, note) {
+                break;
+            }
+            step += 1;
+        }
+        self.next_step = Some(step);
+    }
+}
+
+/// The order in which an arpeggiator plays held notes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// Up then down, without repeating the top and bottom notes.
+    UpDown,
+    /// A random held note each step.
+    Random,
+    /// In the order the notes were pressed.
+    AsPlayed,
+}
+
+/// Turns held notes into a repeating pattern.
+pub struct Arpeggiator {
+    mode: ArpMode,
+    steps_per_beat: u32,
+    gate: f32,
+    octaves: u8,
+    // (note, velocity), in the order pressed
+    held: Vec<(u8, u8)>,
+    pattern: Vec<(u8, u8)>,
+    pos: usize,
+    next_step: Option<u64>,
+    rand_state: u32,
+}
+
+impl Arpeggiator {
+    pub fn new(mode: ArpMode, steps_per_beat: u32) -> Arpeggiator {
+        Arpeggiator {
+            mode,
+            steps_per_beat,
+            gate: 0.5,
+            octaves: 1,
+            held: Vec::new(),
+            pattern: Vec::new(),
+            pos: 0,
+            next_step: None,
+            rand_state: 0x2f6b_4c1d,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: ArpMode) {
+        self.mode = mode;
+        self.update_pattern();
+    }
+
+    /// Set the note length as a fraction of the step, up to 1.
+    pub fn set_gate(&mut self, gate: f32) {
+        self.gate = gate;
+    }
+
+    /// Set the number of octaves the pattern spans (at least 1).
+    pub fn set_octaves(&mut self, octaves: u8) {
+        self.octaves = octaves.max(1);
+        self.update_pattern();
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        self.held.retain(|&(n, _)| n != note);
+        self.held.push((note, velocity));
+        self.update_pattern();
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.held.retain(|&(n, _)| n != note);
+        self.update_pattern();
+    }
+
+    /// Start again from the step at the transport's current position.
+    pub fn reset(&mut self) {
+        self.next_step = None;
+        self.pos = 0;
+    }
+
+    fn update_pattern(&mut self) {
+        let mut base = self.held.clone();
+        if self.mode != ArpMode::AsPlayed {
+            base.sort();
+        }
+        self.pattern.clear();
+        for octave in 0..self.octaves {
+            for &(note, velocity) in &base {
+                let note = note as u32 + 12 * octave as u32;
+                if note < 128 {
+                    self.pattern.push((note as u8, velocity));
+                }
+            }
+        }
+        match self.mode {
+            ArpMode::Down => self.pattern.reverse(),
+            ArpMode::UpDown if self.pattern.len() > 2 => {
+                let n = self.pattern.len();
+                for i in (1..n - 1).rev() {
+                    let entry = self.pattern[i];
+                    self.pattern.push(entry);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // xorshift32
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rand_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rand_state = x;
+        x
+    }
+
+    fn next_note(&mut self) -> Option<(u8, u8)> {
+        if self.pattern.is_empty() {
+            return None;
+        }
+        let ix = if self.mode == ArpMode::Random {
+            self.next_random() as usize % self.pattern.len()
+        } else {
+            let ix = self.pos % self.pattern.len();
+            self.pos = ix + 1;
+            ix
+        };
+        Some(self.pattern[ix])
+    }
+
+    /// Produce events for the steps that start between `now` and `until`.
+    /// Steps that were missed (because polling fell behind) are skipped.
+    pub fn schedule(&mut self, transport: &Transport, now: u64, until: u64,
+        out: &mut Vec<ScheduledNote>)
+    {
+        if !transport.is_running() {
+            return;
+        }
+        let spb = self.steps_per_beat;
+        let mut step = match self.next_step {
+            Some(step) => step,
+            None => transport.step_after(transport.beat_at(now), spb),
+        };
+        let gate = self.gate;
+        while schedule_step(transport, step, spb, gate, (now, until), out, || self.next_note()) {
+            step += 1;
+        }
+        self.next_step = Some(step);
+    }
+}
+
+/// MIDI clock resolution, in ticks per quarter note.
+pub const CLOCK_PPQN: u64 = 24;
+
+/// A MIDI realtime or song position message relevant to the transport.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockEvent {
+    /// Timing clock (0xF8).
+    Tick,
+    /// Start (0xFA).
+    Start,
+    /// Continue (0xFB).
+    Continue,
+    /// Stop (0xFC).
+    Stop,
+    /// Song position pointer (0xF2), in sixteenth notes.
+    SongPosition(u16),
+}
+
+// Smoothing of the tick period, and the fraction of phase error corrected on
+// each tick.
+const PERIOD_GAIN: f64 = 0.1;
+const PHASE_GAIN: f64 = 0.2;
+
+// Number of consecutive out-of-range tick intervals that are taken as a real
+// tempo change rather than jitter or dropouts.
+const MAX_OUTLIERS: u32 = 4;
+
+/// Follows an external MIDI clock, driving a `Transport`.
+///
+/// Tick intervals are smoothed with a one-pole filter, rejecting isolated
+/// outliers, and the transport's phase is pulled gradually toward the tick
+/// count, so timing jitter on the incoming clock doesn't reach the sequencer.
+#[derive(Default)]
+pub struct ClockIn {
+    period: Option<f64>,  // smoothed ns per tick
+    last_tick: Option<u64>,
+    outliers: u32,
+    ticks: u64,  // position, in ticks from the start of the song
+    running: bool,
+    // the next tick is the one at which playback starts
+    awaiting_first: bool,
+}
+
+impl ClockIn {
+    pub fn new() -> ClockIn {
+        ClockIn::default()
+    }
+
+    /// The tempo of the incoming clock, once it has been measured.
+    pub fn bpm(&self) -> Option<f64> {
+        self.period.map(|period| 60e9 / (CLOCK_PPQN as f64 * period))
+    }
+
+    fn measure(&mut self, ts: u64) {
+        if let Some(last) = self.last_tick {
+            let interval = ts.saturating_sub(last) as f64;
+            match self.period {
+                Some(period) if interval > 0.5 * period && interval < 2.0 * period => {
+                    self.period = Some(period + PERIOD_GAIN * (interval - period));
+                    self.outliers = 0;
+                }
+                Some(_) if self.outliers < MAX_OUTLIERS => self.outliers += 1,
+                _ => {
+                    self.period = Some(interval);
+                    self.outliers = 0;
+                }
+            }
+        }
+        self.last_tick = Some(ts);
+    }
+
+    /// Apply a clock event received at `ts`. Returns true when playback
+    /// starts, so sequencers can be reset.
+    pub fn handle(&mut self, event: ClockEvent, ts: u64, transport: &mut Transport) -> bool {
+        match event {
+            ClockEvent::Tick => {
+                self.measure(ts);
+                if !self.running {
+                    return false;
+                }
+                let beat = self.ticks as f64 / CLOCK_PPQN as f64;
+                let bpm = self.bpm().unwrap_or_else(|| transport.bpm());
+                if self.awaiting_first {
+                    // the first tick after start or continue marks the position
+                    self.awaiting_first = false;
+                    transport.set_bpm(bpm, ts);
+                    transport.start_from(beat, ts);
+                    self.ticks += 1;
+                    return true;
+                }
+                self.ticks += 1;
+                transport.sync_to(beat, ts, bpm, PHASE_GAIN);
+                false
+            }
+            ClockEvent::Start => {
+                self.ticks = 0;
+                self.running = true;
+                self.awaiting_first = true;
+                false
+            }
+            ClockEvent::Continue => {
+                self.running = true;
+                self.awaiting_first = true;
+                false
+            }
+            ClockEvent::Stop => {
+                self.running = false;
+                transport.stop(ts);
+                false
+            }
+            ClockEvent::SongPosition(sixteenths) => {
+                self.ticks = sixteenths as u64 * (CLOCK_PPQN / 4);
+                transport.set_position(self.ticks as f64 / CLOCK_PPQN as f64);
+                false
+            }
+        }
+    }
+}
+
+/// Generates MIDI clock ticks from a `Transport`.
+#[derive(Default)]
+pub struct ClockOut {
+    next_tick: Option<u64>,
+}
+
+impl ClockOut {
+    pub fn new() -> ClockOut {
+        ClockOut::default()
+    }
+
+    /// Start again from the tick at the transport's current position.
+    pub fn reset(&mut self) {
+        self.next_tick = None;
+    }
+
+    /// Produce timestamps for the clock ticks between `now` and `until`.
+    pub fn schedule(&mut self, transport: &Transport, now: u64, until: u64, out: &mut Vec<u64>) {
+        if !transport.is_running() {
+            return;
+        }
+        let ppqn = CLOCK_PPQN as f64;
+        let mut tick = match self.next_tick {
+            Some(tick) => tick,
+            None => (transport.beat_at(now) * ppqn).max(0.0).ceil() as u64,
+        };
+        loop {
+            let t = transport.time_at(tick as f64 / ppqn);
+            if t >= until {
+                break;
+            }
+            if t >= now {
+                out.push(t);
+            }
+            tick += 1;
+        }
+        self.next_tick = Some(tick);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    fn notes_on(events: &[ScheduledNote]) -> Vec<u8> {
+        events.iter().filter(|e| e.on).map(|e| e.note).collect()
+    }
+
+    fn step(note: u8, gate: f32) -> Option<Step> {
+        Some(Step { note, velocity: 100, gate })
+    }
+
+    #[test]
+    fn beat_time_conversion() {
+        let mut transport = Transport::new(120.0);
+        transport.start(1000 * MS);
+        assert_near(transport.beat_at(1500 * MS), 1.0);
+        assert_eq!(transport.time_at(2.0), 2000 * MS);
+        // the position carries through a tempo change
+        transport.set_bpm(60.0, 2000 * MS);
+        assert_near(transport.beat_at(3000 * MS), 3.0);
+        assert_eq!(transport.time_at(4.0), 4000 * MS);
+        transport.stop(3000 * MS);
+        assert_near(transport.beat_at(9000 * MS), 3.0);
+        transport.resume(10_000 * MS);
+        assert_near(transport.beat_at(11_000 * MS), 4.0);
+    }
+
+    #[test]
+    fn swing_delays_odd_steps() {
+        let mut transport = Transport::new(120.0);
+        transport.start(0);
+        assert_eq!(transport.step_time(1, 2), 250 * MS);
+        transport.set_swing(0.75);
+        assert_eq!(transport.step_time(0, 2), 0);
+        assert_eq!(transport.step_time(1, 2), 375 * MS);
+        assert_eq!(transport.step_time(2, 2), 500 * MS);
+        transport.set_swing(1.0);
+        assert_near(transport.swing(), 0.9);
+    }
+
+    #[test]
+    fn sequencer_window() {
+        let mut transport = Transport::new(120.0);
+        transport.start(0);
+        // 125ms steps
+        let mut seq = StepSequencer::new(4, 4);
+        seq.set_step(0, step(60, 0.5));
+        seq.set_step(2, step(62, 0.5));
+        seq.set_step(3, step(64, 0.5));
+        let mut out = Vec::new();
+        // a step at the start of the window is included, one at the end isn't
+        seq.schedule(&transport, 0, 250 * MS, &mut out);
+        assert_eq!(out, vec![
+            ScheduledNote { note: 60, velocity: 100, on: true, timestamp: 0 },
+            ScheduledNote { note: 60, velocity: 0, on: false, timestamp: 62_500_000 },
+        ]);
+        out.clear();
+        seq.schedule(&transport, 250 * MS, 500 * MS, &mut out);
+        assert_eq!(notes_on(&out), vec![62, 64]);
+        assert_eq!(out[0].timestamp, 250 * MS);
+    }
+
+    #[test]
+    fn sequencer_skips_missed_steps() {
+        let mut transport = Transport::new(120.0);
+        transport.start(0);
+        let mut seq = StepSequencer::new(4, 4);
+        for i in 0..4 {
+            seq.set_step(i, step(60 + i as u8, 0.5));
+        }
+        let mut out = Vec::new();
+        seq.schedule(&transport, 0, 100 * MS, &mut out);
+        assert_eq!(notes_on(&out), vec![60]);
+        out.clear();
+        // polling fell behind: steps 1 to 4 have passed
+        seq.schedule(&transport, 600 * MS, 700 * MS, &mut out);
+        assert_eq!(notes_on(&out), vec![61]);
+        assert_eq!(out[0].timestamp, 625 * MS);
+    }
+
+    #[test]
+    fn off_comes_before_next_on() {
+        let mut transport = Transport::new(120.0);
+        transport.start(0);
+        let mut seq = StepSequencer::new(1, 4);
+        seq.set_step(0, step(60, 1.0));
+        let mut out = Vec::new();
+        seq.schedule(&transport, 0, 300 * MS, &mut out);
+        assert_eq!(out.len(), 6);
+        for pair in out[1..5].chunks(2) {
+            assert!(!pair[0].on && pair[1].on);
+            assert_eq!(pair[0].timestamp, pair[1].timestamp);
+        }
+    }
+
+    fn arpeggiate(mode: ArpMode, held: &[u8], n_steps: u64) -> Vec<u8> {
+        let mut transport = Transport::new(120.0);
+        transport.start(0);
+        let mut arp = Arpeggiator::new(mode, 4);
+        for &note in held {
+            arp.note_on(note, 100);
+        }
+        let mut out = Vec::new();
+        arp.schedule(&transport, 0, n_steps * 125 * MS, &mut out);
+        notes_on(&out)
+    }
+
+    #[test]
+    fn arpeggiator_patterns() {
+        assert_eq!(arpeggiate(ArpMode::Up, &[64, 60, 67], 4), vec![60, 64, 67, 60]);
+        assert_eq!(arpeggiate(ArpMode::Down, &[64, 60, 67], 4), vec![67, 64, 60, 67]);
+        assert_eq!(arpeggiate(ArpMode::UpDown, &[64, 60, 67], 6),
+            vec![60, 64, 67, 64, 60, 64]);
+        assert_eq!(arpeggiate(ArpMode::UpDown, &[64, 60], 3), vec![60, 64, 60]);
+        assert_eq!(arpeggiate(ArpMode::AsPlayed, &[64, 60, 67], 4), vec![64, 60, 67, 64]);
+        assert!(arpeggiate(ArpMode::Up, &[], 4).is_empty());
+    }
+
+    #[test]
+    fn arpeggiator_octaves() {
+        let mut transport = Transport::new(120.0);
+        transport.start(0);
+        let mut arp = Arpeggiator::new(ArpMode::Up, 4);
+        arp.set_octaves(2);
+        arp.note_on(60, 100);
+        arp.note_on(64, 100);
+        let mut out = Vec::new();
+        arp.schedule(&transport, 0, 500 * MS, &mut out);
+        assert_eq!(notes_on(&out), vec![60, 64, 72, 76]);
+    }
+
+    // ns per clock tick at 120 bpm
+    const TICK: f64 = 0.5e9 / CLOCK_PPQN as f64;
+
+    // Send ticks `range` of a clock at 120 bpm that started at 1s, each off
+    // by up to 1ms.
+    fn send_ticks(clock: &mut ClockIn, transport: &mut Transport, range: ::std::ops::Range<u64>) {
+        for i in range {
+            let jitter = ((i * 7919) % 21) as f64 * 0.1e6 - 1e6;
+            let ts = (1e9 + i as f64 * TICK + jitter) as u64;
+            clock.handle(ClockEvent::Tick, ts, transport);
+        }
+    }
+
+    #[test]
+    fn clock_in_follows_jittered_ticks() {
+        let mut clock = ClockIn::new();
+        let mut transport = Transport::new(90.0);
+        clock.handle(ClockEvent::Start, 0, &mut transport);
+        send_ticks(&mut clock, &mut transport, 0..240);
+        assert!((clock.bpm().unwrap() - 120.0).abs() < 1.0);
+        assert!((transport.bpm() - 120.0).abs() < 1.0);
+        // tick 240 is beat 10
+        let beat = transport.beat_at((1e9 + 240.0 * TICK) as u64);
+        assert!((beat - 10.0).abs() < 0.02, "beat {}", beat);
+    }
+
+    #[test]
+    fn clock_in_rejects_outliers() {
+        let mut clock = ClockIn::new();
+        let mut transport = Transport::new(120.0);
+        clock.handle(ClockEvent::Start, 0, &mut transport);
+        send_ticks(&mut clock, &mut transport, 0..100);
+        let bpm = clock.bpm().unwrap();
+        // a dropped tick doubles one interval, and is ignored
+        send_ticks(&mut clock, &mut transport, 101..103);
+        assert!((clock.bpm().unwrap() - bpm).abs() < 0.5);
+
+        // a lasting change of tempo is taken up
+        let start = 1e9 + 103.0 * TICK;
+        for i in 1..50 {
+            let ts = (start + i as f64 * 2.0 * TICK) as u64;
+            clock.handle(ClockEvent::Tick, ts, &mut transport);
+        }
+        assert!((clock.bpm().unwrap() - 60.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn clock_in_positioning() {
+        let mut clock = ClockIn::new();
+        let mut transport = Transport::new(120.0);
+        clock.handle(ClockEvent::Start, 0, &mut transport);
+        assert!(!transport.is_running());
+        // playback starts on the first tick after Start
+        assert!(clock.handle(ClockEvent::Tick, 1000 * MS, &mut transport));
+        assert!(transport.is_running());
+        assert_near(transport.beat_at(1000 * MS), 0.0);
+        assert!(!clock.handle(ClockEvent::Tick, 1000 * MS + TICK as u64, &mut transport));
+
+        clock.handle(ClockEvent::Stop, 1100 * MS, &mut transport);
+        assert!(!transport.is_running());
+        // 8 sixteenths is beat 2
+        clock.handle(ClockEvent::SongPosition(8), 1200 * MS, &mut transport);
+        assert_near(transport.beat_at(1200 * MS), 2.0);
+        clock.handle(ClockEvent::Continue, 1300 * MS, &mut transport);
+        assert!(clock.handle(ClockEvent::Tick, 1400 * MS, &mut transport));
+        assert_near(transport.beat_at(1400 * MS), 2.0);
+
+        // Start goes back to the beginning
+        clock.handle(ClockEvent::Start, 1500 * MS, &mut transport);
+        assert!(clock.handle(ClockEvent::Tick, 1600 * MS, &mut transport));
+        assert_near(transport.beat_at(1600 * MS), 0.0);
+    }
+
+    #[test]
+    fn clock_out_tick_spacing() {
+        let mut transport = Transport::new(120.0);
+        let mut clock = ClockOut::new();
+        let mut out = Vec::new();
+        clock.schedule(&transport, 0, 100 * MS, &mut out);
+        assert!(out.is_empty());
+        transport.start(0);
+        clock.schedule(&transport, 0, 100 * MS, &mut out);
+        clock.schedule(&transport, 100 * MS, 500 * MS, &mut out);
+        // a quarter note's worth, with no tick repeated across windows
+        assert_eq!(out.len(), CLOCK_PPQN as usize);
+        assert_eq!(out[0], 0);
+        for pair in out.windows(2) {
+            assert!(((pair[1] - pair[0]) as f64 - TICK).abs() <= 1.0);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tuning tables, including support for Scala scale (.scl) and keyboard
+//! mapping (.kbm) files.
+//!
+//! See http://www.huygens-fokker.org/scala/scl_format.html for the file
+//! formats.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Number of entries in a tuning table, one per MIDI note number.
+pub const N_KEYS: usize = 128;
+
+/// A tuning table, mapping MIDI note numbers to pitch.
+///
+/// The table is a fixed-size array so that it can be copied into modules on
+/// the audio thread without allocating.
+#[derive(Clone, Copy)]
+pub struct Tuning {
+    // log2 of frequency in Hz, for each MIDI note number
+    pitches: [f32; N_KEYS],
+}
+
+/// A scale, as read from a Scala .scl file.
+#[derive(Clone, Debug)]
+pub struct Scale {
+    pub description: String,
+    /// Scale degrees, in octaves above the base note. The last degree is the
+    /// period of the scale (usually 1.0, an octave).
+    pub degrees: Vec<f64>,
+}
+
+/// A keyboard mapping, as read from a Scala .kbm file.
+#[derive(Clone, Debug)]
+pub struct KeyboardMapping {
+    pub first_note: usize,
+    pub last_note: usize,
+    /// The note to which the first entry of the mapping (scale degree 0) is mapped.
+    pub middle_note: usize,
+    pub reference_note: usize,
+    pub reference_freq: f64,
+    /// The scale degree of the formal octave; 0 means the period of the scale.
+    pub octave_degree: usize,
+    /// Scale degree for each key in one repetition of the pattern; `None` for
+    /// unmapped keys. An empty mapping means a linear mapping of scale degrees.
+    pub mapping: Vec<Option<usize>>,
+}
+
+impl Tuning {
+    /// Standard 12-tone equal temperament, with A4 (MIDI note 69) at 440Hz.
+    pub fn equal_temperament() -> Tuning {
+        let mut pitches = [0.0; N_KEYS];
+        for (i, pitch) in pitches.iter_mut().enumerate() {
+            *pitch = i as f32 * (1.0 / 12.0) + (440f32.log2() - 69.0 / 12.0);
+        }
+        Tuning { pitches }
+    }
+
+    /// Build a tuning from a scale and keyboard mapping. Unmapped keys, and keys
+    /// outside the mapped range, take the pitch of the nearest mapped key below
+    /// (or above, at the bottom of the range).
+    pub fn from_scale(scale: &Scale, kbm: &KeyboardMapping) -> Tuning {
+        let mut pitches = [None; N_KEYS];
+        let ref_offset = kbm.key_offset(scale, kbm.reference_note).unwrap_or(0.0);
+        let base = kbm.reference_freq.log2() - ref_offset;
+        let last_note = kbm.last_note.min(N_KEYS - 1);
+        for (key, pitch) in pitches.iter_mut().enumerate().take(last_note + 1).skip(kbm.first_note) {
+            *pitch = kbm.key_offset(scale, key).map(|offset| (base + offset) as f32);
+        }
+        let fallback = pitches.iter().filter_map(|&p| p).next()
+            .unwrap_or(kbm.reference_freq.log2() as f32);
+        let mut result = [0.0; N_KEYS];
+        let mut last = fallback;
+        for (pitch, result) in pitches.iter().zip(result.iter_mut()) {
+            if let Some(pitch) = *pitch {
+                last = pitch;
+            }
+            *result = last;
+        }
+        Tuning { pitches: result }
+    }
+
+    /// Load a tuning from a .scl file and an optional .kbm file. Without a
+    /// keyboard mapping, the scale is mapped linearly with degree 0 on middle
+    /// C and A4 at 440Hz.
+    pub fn load<P: AsRef<Path>>(scl_path: P, kbm_path: Option<P>) -> io::Result<Tuning> {
+        let scale = Scale::load(scl_path)?;
+        let kbm = match kbm_path {
+            Some(path) => KeyboardMapping::load(path)?,
+            None => KeyboardMapping::default(),
+        };
+        Ok(Tuning::from_scale(&scale, &kbm))
+    }
+
+    /// The pitch for a (possibly fractional) MIDI note number, as log2 of
+    /// frequency in Hz. Fractional notes are interpolated between table entries.
+    pub fn pitch(&self, midi_num: f32) -> f32 {
+        let x = midi_num.max(0.0).min((N_KEYS - 1) as f32);
+        let i = (x as usize).min(N_KEYS - 2);
+        let frac = x - i as f32;
+        let p0 = self.pitches[i];
+        p0 + (self.pitches[i + 1] - p0) * frac
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Tuning {
+        Tuning::equal_temperament()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Iterate the lines of a Scala file that aren't comments.
+fn content_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+// Parse a pitch value, either in cents (contains a period) or as a ratio.
+// Returns the value in octaves.
+fn parse_pitch(s: &str) -> io::Result<f64> {
+    let s = s.split_whitespace().next().ok_or_else(|| invalid("missing pitch value"))?;
+    if s.contains('.') {
+        let cents: f64 = s.parse().map_err(|_| invalid("bad cents value"))?;
+        Ok(cents / 1200.0)
+    } else {
+        let mut parts = s.splitn(2, '/');
+        let num: f64 = parts.next().unwrap().parse().map_err(|_| invalid("bad ratio"))?;
+        let den: f64 = match parts.next() {
+            Some(den) => den.parse().map_err(|_| invalid("bad ratio"))?,
+            None => 1.0,
+        };
+        if num <= 0.0 || den <= 0.0 {
+            return Err(invalid("ratio must be positive"));
+        }
+        Ok((num / den).log2())
+    }
+}
+
+impl Scale {
+    /// Parse the contents of a .scl file.
+    pub fn parse(text: &str) -> io::Result<Scale> {
+        let mut lines = content_lines(text);
+        let description = lines.next().ok_or_else(|| invalid("missing description"))?;
+        let count: usize = lines.next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid("missing note count"))?;
+        let degrees = lines.filter(|line| !line.trim().is_empty())
+            .take(count)
+            .map(parse_pitch)
+            .collect::<io::Result<Vec<_>>>()?;
+        if degrees.len() != count {
+            return Err(invalid("fewer pitches than note count"));
+        }
+        Ok(Scale { description: description.trim().to_string(), degrees })
+    }
+
+    /// Load a .scl file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Scale> {
+        Scale::parse(&fs::read_to_string(path)?)
+    }
+
+    /// The pitch of a scale degree in octaves, counting upward through repeated
+    /// periods. Degree 0 is the base note.
+    fn degree_pitch(&self, degree: i64) -> f64 {
+        let n = self.degrees.len() as i64;
+        if n == 0 {
+            return 0.0;
+        }
+        let period = self.degrees[n as usize - 1];
+        let octave = degree.div_euclid(n);
+        let ix = degree.rem_euclid(n);
+        let within = if ix == 0 { 0.0 } else { self.degrees[ix as usize - 1] };
+        octave as f64 * period + within
+    }
+}
+
+impl KeyboardMapping {
+    /// Parse the contents of a .kbm file.
+    pub fn parse(text: &str) -> io::Result<KeyboardMapping> {
+        let mut lines = content_lines(text).map(|line| line.trim()).filter(|line| !line.is_empty());
+        let mut next_num = |what: &str| -> io::Result<f64> {
+            lines.next()
+                .and_then(|line| line.split_whitespace().next())
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid(what))
+        };
+        let size = next_num("missing map size")? as usize;
+        let first_note = next_num("missing first note")? as usize;
+        let last_note = next_num("missing last note")? as usize;
+        let middle_note = next_num("missing middle note")? as usize;
+        let reference_note = next_num("missing reference note")? as usize;
+        let reference_freq = next_num("missing reference frequency")?;
+        let octave_degree = next_num("missing octave degree")? as usize;
+        let mut mapping = Vec::with_capacity(size);
+        for line in lines.take(size) {
+            let entry = line.split_whitespace().next().unwrap_or("x");
+            if entry == "x" {
+                mapping.push(None);
+            } else {
+                mapping.push(Some(entry.parse().map_err(|_| invalid("bad mapping entry"))?));
+            }
+        }
+        // Trailing unmapped entries may be omitted from the file.
+        mapping.resize(size, None);
+        Ok(KeyboardMapping {
+            first_note, last_note, middle_note, reference_note, reference_freq, octave_degree,
+            mapping,
+        })
+    }
+
+    /// Load a .kbm file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<KeyboardMapping> {
+        KeyboardMapping::parse(&fs::read_to_string(path)?)
+    }
+
+    // Pitch of a key in octaves relative to the middle note, or None if unmapped.
+    fn key_offset(&self, scale: &Scale, key: usize) -> Option<f64> {
+        let offset = key as i64 - self.middle_note as i64;
+        if self.mapping.is_empty() {
+            return Some(scale.degree_pitch(offset));
+        }
+        let size = self.mapping.len() as i64;
+        let repeat = offset.div_euclid(size);
+        let degree = self.mapping[offset.rem_euclid(size) as usize]?;
+        let formal_octave = if self.octave_degree == 0 {
+            scale.degree_pitch(scale.degrees.len() as i64)
+        } else {
+            scale.degree_pitch(self.octave_degree as i64)
+        };
+        Some(repeat as f64 * formal_octave + scale.degree_pitch(degree as i64))
+    }
+}
+
+impl Default for KeyboardMapping {
+    /// A linear mapping with scale degree 0 on middle C and A4 at 440Hz.
+    fn default() -> KeyboardMapping {
+        KeyboardMapping {
+            first_note: 0,
+            last_note: N_KEYS - 1,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq: 440.0,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::{Message, Node, Note, SetTuning};
+    use modules;
+    use worker::Worker;
+    use super::*;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    fn twelve_tet() -> Scale {
+        let mut text = "! 12tet.scl\n!\n12-tone equal temperament\n 12\n!\n".to_string();
+        for i in 1..13 {
+            text.push_str(&format!(" {}.0\n", i * 100));
+        }
+        Scale::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn parse_scale() {
+        let text = "! test.scl\n!\n A test scale\n 4\n!\n 100.0 cents\n 5/4\n 3\n 2/1\n";
+        let scale = Scale::parse(text).unwrap();
+        assert_eq!(scale.description, "A test scale");
+        let expected = [1.0 / 12.0, 1.25f64.log2(), 3f64.log2(), 1.0];
+        assert_eq!(scale.degrees.len(), expected.len());
+        for (&degree, &expected) in scale.degrees.iter().zip(&expected) {
+            assert!((degree - expected).abs() < 1e-9);
+        }
+        assert!(Scale::parse("short\n 3\n 100.0\n 2/1\n").is_err());
+        assert!(Scale::parse("bad\n 1\n 0/1\n").is_err());
+    }
+
+    #[test]
+    fn twelve_tet_matches_equal_temperament() {
+        let tuning = Tuning::from_scale(&twelve_tet(), &KeyboardMapping::default());
+        let et = Tuning::equal_temperament();
+        for key in 0..N_KEYS {
+            assert_near(tuning.pitch(key as f32), et.pitch(key as f32));
+        }
+    }
+
+    #[test]
+    fn keyboard_mapping() {
+        // five keys to the pattern, repeating at a fifth, with A4 at 440Hz
+        let text = "! test.kbm\n5\n0\n127\n60\n69\n440.0\n7\n! mapping\n0\nx\n2\n4\n5\n";
+        let kbm = KeyboardMapping::parse(text).unwrap();
+        assert_eq!(kbm.mapping, vec![Some(0), None, Some(2), Some(4), Some(5)]);
+        let tuning = Tuning::from_scale(&twelve_tet(), &kbm);
+        let a3 = 220f32.log2();
+        assert_near(tuning.pitch(69.0), 440f32.log2());
+        assert_near(tuning.pitch(60.0), a3);
+        // the unmapped key takes the pitch of the key below
+        assert_near(tuning.pitch(61.0), a3);
+        assert_near(tuning.pitch(62.0), a3 + 2.0 / 12.0);
+        assert_near(tuning.pitch(64.0), a3 + 5.0 / 12.0);
+        assert_near(tuning.pitch(65.0), a3 + 7.0 / 12.0);
+        assert_near(tuning.pitch(55.0), a3 - 7.0 / 12.0);
+    }
+
+    #[test]
+    fn note_pitch_follows_tuning() {
+        let (mut worker, _tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(modules::NotePitch::new()), 1, [], []));
+        worker.handle_node(Node::create(Box::new(modules::Sin::new(48_000.0)), 0, [],
+            [(1, 0)]));
+        let mut kbm = KeyboardMapping::default();
+        kbm.reference_freq = 880.0;
+        let tuning = Tuning::from_scale(&twelve_tet(), &kbm);
+        worker.handle_message(Message::SetTuning(SetTuning {
+            ixs: vec![1].into_boxed_slice(),
+            tuning: Box::new(tuning),
+        }));
+        let note = Note { ixs: vec![1].into_boxed_slice(), midi_num: 69.0, velocity: 100.0,
+            on: true, timestamp: 0 };
+        worker.handle_message(Message::Note(note));
+        // 0.1s at 880Hz
+        let out = worker.render(0, 150, 48_000.0);
+        let cycles = out.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!(cycles >= 87 && cycles <= 89, "{} cycles", cycles);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal reader for WAV files.
+//!
+//! Supports integer PCM (8, 16, 24 and 32 bits) and 32-bit float formats.
+//! Loading allocates and does I/O, so it belongs on the non-realtime side.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Decoded audio from a WAV file.
+pub struct Wav {
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// Interleaved samples, scaled to the range -1 to 1.
+    pub samples: Vec<f32>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    read_u16(b) as u32 | (read_u16(&b[2..]) as u32) << 16
+}
+
+impl Wav {
+    /// Read and decode a WAV file from a stream.
+    pub fn read<R: Read>(mut r: R) -> io::Result<Wav> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(invalid("not a WAV file"));
+        }
+        let mut fmt = None;
+        let mut pos = 12;
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let len = read_u32(&data[pos + 4..]) as usize;
+            let body = &data[pos + 8..(pos + 8 + len).min(data.len())];
+            if id == b"fmt " {
+                if body.len() < 16 {
+                    return Err(invalid("fmt chunk too short"));
+                }
+                let mut format = read_u16(body);
+                if format == FORMAT_EXTENSIBLE && body.len() >= 26 {
+                    // first two bytes of the subformat GUID
+                    format = read_u16(&body[24..]);
+                }
+                let channels = read_u16(&body[2..]) as usize;
+                let sample_rate = read_u32(&body[4..]);
+                let bits = read_u16(&body[14..]);
+                fmt = Some((format, channels, sample_rate, bits));
+            } else if id == b"data" {
+                let (format, channels, sample_rate, bits) =
+                    fmt.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                if channels == 0 {
+                    return Err(invalid("no channels"));
+                }
+                if sample_rate == 0 {
+                    return Err(invalid("zero sample rate"));
+                }
+                let samples = decode(body, format, bits)?;
+                return Ok(Wav { sample_rate, channels, samples });
+            }
+            // chunks are padded to an even length
+            pos += 8 + len + (len & 1);
+        }
+        Err(invalid("no data chunk"))
+    }
+
+    /// Load a WAV file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Wav> {
+        Wav::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Number of sample frames.
+    pub fn n_frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    /// Extract one channel.
+    pub fn channel(&self, ch: usize) -> Vec<f32> {
+        self.samples.iter().skip(ch).step_by(self.channels).cloned().collect()
+    }
+
+    /// Mix all channels down to mono.
+    pub fn to_mono(&self) -> Vec<f32> {
+        let scale = 1.0 / self.channels as f32;
+        self.samples.chunks(self.channels).map(|frame| frame.iter().sum::<f32>() * scale)
+            .collect()
+    }
+}
+
+fn decode(body: &[u8], format: u16, bits: u16) -> io::Result<Vec<f32>> {
+    match (format, bits) {
+        (FORMAT_PCM, 8) => Ok(body.iter().map(|&b| (b as f32 - 128.0) * (1.0 / 128.0)).collect()),
+        (FORMAT_PCM, 16) => Ok(body.chunks_exact(2)
+            .map(|b| read_u16(b) as i16 as f32 * (1.0 / 32_768.0)).collect()),
+        (FORMAT_PCM, 24) => Ok(body.chunks_exact(3)
+            .map(|b| {
+                let x = (b[0] as i32) << 8 | (b[1] as i32) << 16 | (b[2] as i32) << 24;
+                (x >> 8) as f32 * (1.0 / 8_388_608.0)
+            }).collect()),
+        (FORMAT_PCM, 32) => Ok(body.chunks_exact(4)
+            .map(|b| read_u32(b) as i32 as f32 * (1.0 / 2_147_483_648.0)).collect()),
+        (FORMAT_FLOAT, 32) => Ok(body.chunks_exact(4)
+            .map(|b| f32::from_bits(read_u32(b))).collect()),
+        _ => Err(invalid("unsupported sample format")),
+    }
+}
+
+/// Resample audio by linear interpolation. This is intended for material
+/// like impulse responses and samples recorded at a different rate than the
+/// engine, not for high quality conversion.
+pub fn resample(input: &[f32], from_rate: f32, to_rate: f32) -> Vec<f32> {
+    if from_rate == to_rate || input.is_empty() {
+        return input.to_vec();
+    }
+    let step = from_rate / to_rate;
+    let n_out = ((input.len() as f32) / step) as usize;
+    (0..n_out).map(|i| {
+        let x = i as f32 * step;
+        let ix = x as usize;
+        let frac = x - ix as f32;
+        let y0 = input[ix];
+        let y1 = input.get(ix + 1).cloned().unwrap_or(y0);
+        y0 + (y1 - y0) * frac
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 16-bit PCM file with the given header fields and two frames of silence.
+    fn wav_bytes(channels: u16, sample_rate: u32) -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(b"RIFF");
+        b.extend_from_slice(&44u32.to_le_bytes());
+        b.extend_from_slice(b"WAVEfmt ");
+        b.extend_from_slice(&16u32.to_le_bytes());
+        b.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        b.extend_from_slice(&channels.to_le_bytes());
+        b.extend_from_slice(&sample_rate.to_le_bytes());
+        b.extend_from_slice(&(sample_rate * 2 * channels as u32).to_le_bytes());
+        b.extend_from_slice(&(2 * channels).to_le_bytes());
+        b.extend_from_slice(&16u16.to_le_bytes());
+        b.extend_from_slice(b"data");
+        b.extend_from_slice(&8u32.to_le_bytes());
+        b.extend_from_slice(&[0; 8]);
+        b
+    }
+
+    #[test]
+    fn read_header() {
+        let wav = Wav::read(&wav_bytes(2, 44_100)[..]).unwrap();
+        assert_eq!((wav.sample_rate, wav.channels, wav.n_frames()), (44_100, 2, 2));
+    }
+
+    #[test]
+    fn reject_bad_header() {
+        for &(channels, sample_rate) in &[(0, 44_100), (1, 0)] {
+            let err = Wav::read(&wav_bytes(channels, sample_rate)[..]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -14,10 +14,10 @@
 
 //! A worker, designed to produce audio in a lock-free manner.
 
-use std::ops::Deref;
+use std::ops::DerefMut;
 
 use queue::{Queue, Sender, Receiver, Item};
-use module::Buffer;
+use module::{Buffer, N_SAMPLES_PER_CHUNK};
 use graph::{Graph, Node, Message};
 
 pub struct Worker {
@@ -25,8 +25,14 @@ pub struct Worker {
     from_worker: Sender<Message>,
     graph: Graph,
     root: usize,
+    // Items with a timestamp in the future, in the order received.
+    pending: Vec<Item<Message>>,
 }
 
+// Capacity of the pending list. If it fills, further items are handled
+// immediately rather than allocating.
+const MAX_PENDING: usize = 1024;
+
 impl Worker {
     /// Create a new worker, with the specified maximum number of graph nodes,
     /// and set up communication channels.
@@ -39,6 +45,7 @@ impl Worker {
             from_worker: from_worker,
             graph: graph,
             root: 0,
+            pending: Vec::with_capacity(MAX_PENDING),
         };
         (worker, tx, rx)
     }
@@ -55,18 +62,44 @@ impl Worker {
         self.handle_message(Message::Node(node));
     }
 
-    fn handle_item(&mut self, item: Item<Message>) {
-        let ix = match *item.deref() {
+    fn handle_item(&mut self, mut item: Item<Message>) {
+        let ix = match *item.deref_mut() {
             Message::Node(ref node) => Some(node.ix),
             Message::SetParam(ref param) => {
-                let module = self.graph.get_module_mut(param.ix);
-                module.set_param(param.param_ix, param.val, param.timestamp);
+                // ids can come from outside (such as OSC), so a missing node
+                // is ignored rather than taking down the audio thread
+                if let Some(module) = self.graph.module_mut(param.ix) {
+                    module.set_param(param.param_ix, param.val, param.timestamp);
+                }
                 None
             }
+            // Notes and tunings may have been sent before their receivers
+            // were removed, so missing nodes are skipped.
             Message::Note(ref note) => {
                 for &ix in note.ixs.iter() {
-                    let module = self.graph.get_module_mut(ix);
-                    module.handle_note(note.midi_num, note.velocity, note.on);
+                    if let Some(module) = self.graph.module_mut(ix) {
+                        module.handle_note(note.midi_num, note.velocity, note.on);
+                    }
+                }
+                None
+            }
+            Message::SetTuning(ref tuning) => {
+                for &ix in tuning.ixs.iter() {
+                    if let Some(module) = self.graph.module_mut(ix) {
+                        module.set_tuning(&tuning.tuning);
+                    }
+                }
+                None
+            }
+            Message::SetLane(ref mut set_lane) => {
+                if let Some(module) = self.graph.module_mut(set_lane.ix) {
+                    module.swap_lane(&mut set_lane.lane);
+                }
+                None
+            }
+            Message::Remove(ix) => {
+                if let Some(old_item) = self.graph.replace(ix, None) {
+                    self.from_worker.send_item(old_item);
                 }
                 None
             }
@@ -84,13 +117,96 @@ impl Worker {
 
     /// Process the incoming items, run the graph, and return the rendered audio
     /// buffers. Lock-free.
-    // TODO: leave incoming items in the queue if they have a timestamp in the
-    // future.
+    ///
+    /// Notes and param changes with a timestamp after `timestamp` are held
+    /// back until the chunk that starts at or after their time.
     pub fn work(&mut self, timestamp: u64) -> &[Buffer] {
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].timestamp().unwrap_or(0) <= timestamp {
+                let item = self.pending.remove(i);
+                self.handle_item(item);
+            } else {
+                i += 1;
+            }
+        }
         for item in self.to_worker.recv_items() {
-            self.handle_item(item);
+            let is_future = item.timestamp().map_or(false, |t| t > timestamp);
+            if is_future && self.pending.len() < self.pending.capacity() {
+                self.pending.push(item);
+            } else {
+                self.handle_item(item);
+            }
         }
         self.graph.run_graph(self.root, timestamp);
         self.graph.get_out_bufs(self.root)
     }
+
+    /// Render audio offline, for `n_chunks` chunks starting at `timestamp`,
+    /// returning the first output of the root node. Each chunk's timestamp
+    /// is exact for `sample_rate`, so notes, parameter changes and
+    /// automation lanes land where they would in real time. Allocates, so
+    /// this is not for the audio thread.
+    pub fn render(&mut self, timestamp: u64, n_chunks: usize, sample_rate: f32) -> Vec<f32> {
+        let ns_per_chunk = N_SAMPLES_PER_CHUNK as f64 * 1e9 / sample_rate as f64;
+        let mut result = Vec::with_capacity(n_chunks * N_SAMPLES_PER_CHUNK);
+        for i in 0..n_chunks {
+            let t = timestamp + (i as f64 * ns_per_chunk) as u64;
+            result.extend_from_slice(self.work(t)[0].get());
+        }
+        result
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::{Message, Node, Note, SetParam};
+    use modules;
+    use super::*;
+
+    #[test]
+    fn ignores_params_for_missing_nodes() {
+        let (mut worker, _tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [], []));
+        for &ix in &[5, 9999] {
+            let param = SetParam { ix, param_ix: 0, val: 1.0, timestamp: 0 };
+            worker.handle_message(Message::SetParam(param));
+        }
+        worker.work(0);
+    }
+
+    #[test]
+    fn removes_nodes() {
+        let (mut worker, _tx, rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 1, [], []));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [(1, 0)], []));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [], []));
+        worker.handle_message(Message::Remove(1));
+        let note = Note { ixs: vec![1].into_boxed_slice(), midi_num: 60.0, velocity: 100.0,
+            on: true, timestamp: 0 };
+        worker.handle_message(Message::Note(note));
+        worker.work(0);
+        // the removed node comes back to be freed
+        let removed = rx.recv().filter(|msg| match *msg {
+            Message::Node(ref node) => node.ix == 1,
+            _ => false,
+        }).count();
+        assert_eq!(removed, 1);
+    }
+
+    #[test]
+    fn holds_future_notes() {
+        let (mut worker, tx, rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [], []));
+        let note = Note { ixs: vec![0].into_boxed_slice(), midi_num: 60.0, velocity: 100.0,
+            on: true, timestamp: 2000 };
+        tx.send(Message::Note(note));
+        worker.work(0);
+        worker.work(1000);
+        // handled notes are sent back
+        assert_eq!(rx.recv().count(), 0);
+        worker.work(2000);
+        assert_eq!(rx.recv().count(), 1);
+    }
 }
@@ -32,18 +32,25 @@ mod grid;
 mod synth;
 mod ui;
 
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::ops::DerefMut;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use cpal::{EventLoop, StreamData, UnknownTypeOutputBuffer};
-use midir::{MidiInput, MidiInputConnection};
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
 
 use synthesizer_io_core::modules;
 
 use synthesizer_io_core::engine::{Engine, NoteEvent};
 use synthesizer_io_core::worker::Worker;
 use synthesizer_io_core::graph::Node;
+use synthesizer_io_core::midi_out::MidiOut;
+use synthesizer_io_core::osc::OscListener;
+use synthesizer_io_core::patch::PresetBank;
 use synthesizer_io_core::module::N_SAMPLES_PER_CHUNK;
 
 use druid_win_shell::win_main;
@@ -124,6 +131,16 @@ fn main() {
     // TODO: get sample rate from cpal
     let mut engine = Engine::new(48_000.0, rx, tx);
     engine.init_monosynth();
+    // patches for program change, if there's a directory of them
+    match PresetBank::load_dir("presets") {
+        Ok((presets, errors)) => {
+            for (path, e) in errors {
+                println!("error loading {}: {}", path.display(), e);
+            }
+            engine.set_preset_bank(presets);
+        }
+        Err(e) => println!("no presets loaded: {}", e),
+    }
 
     let engine = Arc::new(Mutex::new(engine));
 
@@ -142,6 +159,8 @@ fn main() {
     builder.set_handler(Box::new(UiMain::new(state)));
     builder.set_title("Synthesizer IO");
     let window = builder.build().unwrap();
+    setup_midi_out(&engine);
+    let _osc_listener = setup_osc(engine.clone());  // keep from being dropped
     let _midi_connection = setup_midi(engine);  // keep from being dropped
     thread::spawn(move || run_cpal(worker));
     window.show();
@@ -162,6 +181,86 @@ fn setup_midi(engine: Arc<Mutex<Engine>>) -> Option<MidiInputConnection<()>> {
     result.ok()
 }
 
+// UDP port for OSC control from other processes. Only local processes can
+// reach it; bind to all interfaces to allow control from the network.
+const OSC_PORT: u16 = 9000;
+
+fn setup_osc(engine: Arc<Mutex<Engine>>) -> Option<OscListener> {
+    let result = OscListener::spawn(("127.0.0.1", OSC_PORT), move |msg, ts| {
+        let mut engine = engine.lock().unwrap();
+        engine.dispatch_osc(&msg, ts);
+    });
+    if let Err(ref e) = result {
+        println!("error listening for osc: {:?}", e);
+    }
+    result.ok()
+}
+
+/// MIDI output through midir. Messages are handed to a thread which sends
+/// each one when it is due.
+struct MidirOut {
+    tx: mpsc::Sender<(u64, Vec<u8>)>,
+}
+
+impl MidiOut for MidirOut {
+    fn send(&mut self, timestamp: u64, data: &[u8]) {
+        let _ = self.tx.send((timestamp, data.to_vec()));
+    }
+}
+
+// Connect the engine to the first MIDI output, if there is one, and send
+// clock from the transport.
+fn setup_midi_out(engine: &Arc<Mutex<Engine>>) {
+    let midi_out = MidiOutput::new("midir output").expect("can't create midi output");
+    if midi_out.port_count() == 0 {
+        println!("no midi output available");
+        return;
+    }
+    let connection = match midi_out.connect(0, "out") {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("error connecting to midi output: {:?}", e);
+            return;
+        }
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || run_midi_out(connection, rx));
+    let mut engine = engine.lock().unwrap();
+    engine.set_midi_out(Some(Box::new(MidirOut { tx })));
+    engine.set_clock_out(true);
+}
+
+// Send messages as they come due. Thru messages are due immediately while
+// notes and clock are scheduled ahead, so they're held in order of time (and
+// then arrival) until due.
+fn run_midi_out(mut connection: MidiOutputConnection, rx: mpsc::Receiver<(u64, Vec<u8>)>) {
+    let mut pending = BinaryHeap::new();
+    let mut seq = 0u64;
+    loop {
+        let now = time::precise_time_ns();
+        while let Some(&Reverse((ts, _, _))) = pending.peek() {
+            if ts > now {
+                break;
+            }
+            let Reverse((_, _, data)) = pending.pop().unwrap();
+            let _ = connection.send(&data);
+        }
+        let (ts, data) = match pending.peek() {
+            Some(&Reverse((ts, _, _))) => match rx.recv_timeout(Duration::from_nanos(ts - now)) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match rx.recv() {
+                Ok(msg) => msg,
+                Err(_) => return,
+            },
+        };
+        pending.push(Reverse((ts, seq, data)));
+        seq += 1;
+    }
+}
+
 fn run_cpal(mut worker: Worker) {
     let event_loop = EventLoop::new();
     let device = cpal::default_output_device().expect("no output device");
@@ -95,6 +95,7 @@ impl SynthState {
             Action::Patch(ref delta) => self.apply_patch_delta(delta),
             Action::Poll(ref mut samples) => {
                 let mut engine = self.engine.lock().unwrap();
+                engine.poll_transport();
                 let _n_msg = engine.poll_rx();
                 *samples = engine.poll_monitor();
             }
@@ -33,6 +33,8 @@ use druid::{Id, HandlerCtx, LayoutCtx, PaintCtx};
 use druid::{MouseEvent, Widget};
 use druid::widget::MouseButton;
 
+use synthesizer_io_core::modules;
+
 use grid::{Delta, JumperDelta, ModuleGrid, ModuleInstance, ModuleSpec, WireDelta, WireGrid};
 
 pub struct Patcher {
@@ -568,7 +570,11 @@ fn make_mod_spec(name: &str) -> ModuleSpec {
         "sine" | "saw" => (2, 1),
         "adsr" => (2, 3),
         "control" => (1, 1),
-        _ => (2, 2),
+        // one row per port, on the larger side
+        _ => match modules::ports(name) {
+            Some(ports) => (2, ports.n_inputs().max(ports.n_outputs()).max(1) as u16),
+            None => (2, 2),
+        },
     };
     ModuleSpec {
         size: size,